@@ -1,9 +1,15 @@
+use std::collections::HashSet;
 use std::env;
 use std::error::Error;
 use std::fs;
+use std::future::{self, Future};
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
 
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 use prost::Message;
 
 use yharnam::*;
@@ -11,85 +17,459 @@ use yharnam::*;
 const DEFAULT_START_NODE_NAME: &str = "Start";
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let mut args = env::args();
+    let mut args = env::args().skip(1).peekable();
 
-    // Read first argument as a path to a yarnc file.
-    args.next();
+    if args.peek().map(String::as_str) == Some("graph") {
+        args.next();
+        return graph(args);
+    }
+    if args.peek().map(String::as_str) == Some("lint") {
+        args.next();
+        return lint(args);
+    }
+    if args.peek().map(String::as_str) == Some("tag") {
+        args.next();
+        return tag(args);
+    }
+    if args.peek().map(String::as_str) == Some("extract") {
+        args.next();
+        return extract(args);
+    }
+    if args.peek().map(String::as_str) == Some("export") {
+        args.next();
+        return export(args);
+    }
+    if args.peek().map(String::as_str) == Some("import") {
+        args.next();
+        return import(args);
+    }
+    if args.peek().map(String::as_str) == Some("check") {
+        args.next();
+        return check(args);
+    }
+
+    // With --watch, the program is reloaded whenever its files change, and --start names the node
+    // to start from.
+    const USAGE: &str = "Usage: yarn-run [--watch] [--start <start node>] <yarnc path>...";
+    let mut watch = false;
+    let mut start_node = DEFAULT_START_NODE_NAME.to_string();
+    while let Some(flag) = args.next_if(|arg| arg == "--watch" || arg == "--start") {
+        if flag == "--watch" {
+            watch = true;
+        } else {
+            start_node = args.next().ok_or(USAGE)?;
+        }
+    }
+
+    // The rest of the arguments are paths to yarnc files, which are merged into one program.
+    let proto_paths: Vec<PathBuf> = args.map(PathBuf::from).collect();
+    if proto_paths.is_empty() {
+        return Err(USAGE.into());
+    }
+
+    let program_set = load_program_set(&proto_paths)?;
+    let reloaded_string_table = Arc::new(Mutex::new(None));
+
+    // Run the virtual machine!
+    let vm = VirtualMachine::new(SharedProgram::new(program_set.program())?);
+    if vm.program.nodes.contains_key(&start_node) {
+        let handler = ConsoleHandler {
+            string_table: program_set.string_table(),
+            reloaded_string_table: reloaded_string_table.clone(),
+        };
+        let mut runner = DialogueRunner::new(vm, handler);
+        // Keep the watcher alive while the dialogue runs.
+        let _watcher = if watch {
+            Some(watch_programs(proto_paths, reloaded_string_table, runner.reloader())?)
+        } else {
+            None
+        };
+        runner.run_blocking(&start_node);
+    } else {
+        eprintln!("Could not find start node: {}", start_node);
+    }
+
+    Ok(())
+}
+
+/// `yarn-run graph <yarnc path> [dot|mermaid|json]`
+///
+/// Prints a graph of how the nodes in a program connect to each other. Defaults to DOT.
+fn graph(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
     let proto_path = args.next()
-        .unwrap();
-    let proto_path = PathBuf::from(proto_path);
+        .ok_or("Usage: yarn-run graph <yarnc path> [dot|mermaid|json]")?;
+    let format = args.next()
+        .unwrap_or_else(|| "dot".to_string());
 
+    let (program, string_table) = load_program(PathBuf::from(proto_path))?;
+    let graph = DialogueGraph::new(&program, &string_table);
+
+    let output = match format.as_str() {
+        "dot" => graph.to_dot(),
+        "mermaid" => graph.to_mermaid(),
+        "json" => graph.to_json(),
+        _ => return Err(format!("Unknown graph format: {}", format).into()),
+    };
+    print!("{}", output);
+
+    Ok(())
+}
+
+/// `yarn-run lint <yarnc path> [start node]`
+///
+/// Prints the problems found in a program as a JSON array, and exits with an error if there are
+/// any. Functions are checked against the built-in library only.
+fn lint(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let proto_path = args.next()
+        .ok_or("Usage: yarn-run lint <yarnc path> [start node]")?;
     let start_node = args.next()
         .unwrap_or(DEFAULT_START_NODE_NAME.to_string());
 
+    let (program, string_table) = load_program(PathBuf::from(proto_path))?;
+    let vm = VirtualMachine::new(SharedProgram::new(program)?);
+    let lints = yharnam::lint(&vm.program, &string_table, &start_node, &vm.library, &vm.enums);
+
+    println!("{}", serde_json::to_string_pretty(&lints)?);
+    if !lints.is_empty() {
+        process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// `yarn-run tag <yarn path>...`
+///
+/// Adds a `#line:` ID to every line in the yarn files that doesn't have one, rewriting the files
+/// in place. IDs are unique across all of the files.
+fn tag(args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let yarn_paths: Vec<PathBuf> = args.map(PathBuf::from).collect();
+    if yarn_paths.is_empty() {
+        return Err("Usage: yarn-run tag <yarn path>...".into());
+    }
+
+    let sources = yarn_paths.iter()
+        .map(fs::read_to_string)
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut used_ids: HashSet<String> = sources.iter()
+        .flat_map(|source| scan_lines(source))
+        .filter_map(|line| line.id)
+        .collect();
+
+    for (yarn_path, source) in yarn_paths.iter().zip(&sources) {
+        let tagged = tag_lines(source, &file_name(yarn_path), &mut used_ids);
+        if tagged != *source {
+            fs::write(yarn_path, tagged)?;
+            println!("Tagged {}", yarn_path.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// `yarn-run extract <yarn path>...`
+///
+/// Prints the string table for the yarn files as CSV, in the same format as the csv files that
+/// are loaded next to yarnc files. Lines without a `#line:` ID are reported and left out.
+fn extract(args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let yarn_paths: Vec<PathBuf> = args.map(PathBuf::from).collect();
+    if yarn_paths.is_empty() {
+        return Err("Usage: yarn-run extract <yarn path>...".into());
+    }
+
+    let mut csv_writer = csv::Writer::from_writer(io::stdout());
+    for yarn_path in &yarn_paths {
+        let source = fs::read_to_string(yarn_path)?;
+        for line in scan_lines(&source).iter().filter(|line| line.id.is_none()) {
+            eprintln!("{}:{}: Line has no ID, run yarn-run tag to add one", yarn_path.display(), line.line_number);
+        }
+        for line_info in extract_string_table(&source, &file_name(yarn_path)) {
+            csv_writer.serialize(line_info)?;
+        }
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
+
+/// `yarn-run export <csv path> <xliff1.2|xliff2.0|po> <source locale> <target locale> [translated csv path]`
+///
+/// Prints a string table in a format for translators. Lines that are already translated in the
+/// translated csv file are exported with their translation.
+fn export(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    const USAGE: &str = "Usage: yarn-run export <csv path> <xliff1.2|xliff2.0|po> <source locale> <target locale> [translated csv path]";
+    let csv_path = args.next().ok_or(USAGE)?;
+    let format = args.next().ok_or(USAGE)?;
+    let source_locale = args.next().ok_or(USAGE)?;
+    let target_locale = args.next().ok_or(USAGE)?;
+
+    let string_table = load_string_table(Path::new(&csv_path))?;
+    let translation = match args.next() {
+        Some(translated_csv_path) => load_string_table(Path::new(&translated_csv_path))?,
+        None => Vec::new(),
+    };
+
+    let output = match format.as_str() {
+        "xliff1.2" => export_xliff(&string_table, &translation, &source_locale, &target_locale, XliffVersion::V1_2),
+        "xliff2.0" => export_xliff(&string_table, &translation, &source_locale, &target_locale, XliffVersion::V2_0),
+        "po" => export_po(&string_table, &translation, &target_locale),
+        _ => return Err(format!("Unknown translation format: {}", format).into()),
+    };
+    print!("{}", output);
+
+    Ok(())
+}
+
+/// `yarn-run import <csv path> <xliff or po path> <locale> [--icu]`
+///
+/// Prints the string table for a translation as CSV. The translations are checked against the
+/// original lines and the locale's plural categories, and nothing is printed if any of them are
+/// broken. With `--icu`, the translations are read as ICU MessageFormat.
+fn import(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    const USAGE: &str = "Usage: yarn-run import <csv path> <xliff or po path> <locale> [--icu]";
+    let csv_path = args.next().ok_or(USAGE)?;
+    let translation_path = PathBuf::from(args.next().ok_or(USAGE)?);
+    let locale = LocaleContext::new(&args.next().ok_or(USAGE)?)?;
+    let syntax = message_syntax(args)?;
+
+    let string_table = load_string_table(Path::new(&csv_path))?;
+    let input = fs::read_to_string(&translation_path)?;
+    let result = match translation_path.extension().and_then(|extension| extension.to_str()) {
+        Some("po") => import_po(&input, &string_table, &locale, syntax),
+        Some("xlf") | Some("xliff") => import_xliff(&input, &string_table, &locale, syntax),
+        _ => return Err(format!("Unknown translation file type: {}", translation_path.display()).into()),
+    };
+
+    match result {
+        Ok(translation) => {
+            let mut csv_writer = csv::Writer::from_writer(io::stdout());
+            for line_info in translation {
+                csv_writer.serialize(line_info)?;
+            }
+            csv_writer.flush()?;
+        }
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}", error);
+            }
+            process::exit(1);
+        }
+    }
+
+    Ok(())
+}
+
+/// `yarn-run check <csv path> <translated csv path> <locale> [--icu]`
+///
+/// Prints the problems found in a translated string table as a JSON array, and exits with an
+/// error if there are any. With `--icu`, the translations are read as ICU MessageFormat.
+fn check(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    const USAGE: &str = "Usage: yarn-run check <csv path> <translated csv path> <locale> [--icu]";
+    let csv_path = args.next().ok_or(USAGE)?;
+    let translated_csv_path = args.next().ok_or(USAGE)?;
+    let locale = LocaleContext::new(&args.next().ok_or(USAGE)?)?;
+    let syntax = message_syntax(args)?;
+
+    let string_table = load_string_table(Path::new(&csv_path))?;
+    let translation = load_string_table(Path::new(&translated_csv_path))?;
+    let issues = check_translations(&string_table, &translation, &locale, syntax);
+
+    println!("{}", serde_json::to_string_pretty(&issues)?);
+    if !issues.is_empty() {
+        process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// Reads the optional `--icu` flag at the end of the import and check commands.
+fn message_syntax(mut args: impl Iterator<Item = String>) -> Result<MessageSyntax, Box<dyn Error>> {
+    match args.next().as_deref() {
+        None => Ok(MessageSyntax::FormatFunctions),
+        Some("--icu") => Ok(MessageSyntax::Icu),
+        Some(arg) => Err(format!("Unknown argument: {}", arg).into()),
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap_or_default().to_string_lossy().into_owned()
+}
+
+/// Loads several yarnc files, and their string tables, into a ProgramSet.
+fn load_program_set(proto_paths: &[PathBuf]) -> Result<ProgramSet, Box<dyn Error>> {
+    let mut program_set = ProgramSet::new();
+    for proto_path in proto_paths {
+        let (mut program, string_table) = load_program(proto_path.clone())?;
+        // Programs are told apart by name, so give unnamed ones the name of their file.
+        if program.name.is_empty() {
+            program.name = proto_path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        }
+        program_set.add(program, string_table)?;
+    }
+    Ok(program_set)
+}
+
+/// Watches the yarnc files and the csv files next to them, and sends the program to the runner
+/// to reload whenever they change. The string table is left in `reloaded_string_table` for the
+/// handler to swap in once the runner has reloaded the program.
+fn watch_programs(
+    proto_paths: Vec<PathBuf>,
+    reloaded_string_table: Arc<Mutex<Option<Vec<LineInfo>>>>,
+    reloader: Sender<SharedProgram>,
+) -> notify::Result<RecommendedWatcher> {
+    let mut watched_paths = Vec::new();
+    for proto_path in &proto_paths {
+        let proto_path = proto_path.canonicalize()?;
+        watched_paths.push(proto_path.with_extension("csv"));
+        watched_paths.push(proto_path);
+    }
+
+    let event_paths = watched_paths.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let event = match event {
+            Ok(event) => event,
+            Err(error) => {
+                eprintln!("Could not watch for changes: {}", error);
+                return;
+            }
+        };
+        if event.kind.is_access() || !event.paths.iter().any(|path| event_paths.contains(path)) {
+            return;
+        }
+
+        let loaded = load_program_set(&proto_paths).and_then(|program_set| {
+            Ok((SharedProgram::new(program_set.program())?, program_set.string_table()))
+        });
+        match loaded {
+            Ok((program, string_table)) => {
+                *reloaded_string_table.lock().unwrap() = Some(string_table);
+                // The runner is gone once the dialogue has completed, so there's nothing to reload.
+                let _ = reloader.send(program);
+            }
+            // The files may be halfway through being written, so wait for the next change.
+            Err(error) => eprintln!("Could not reload: {}", error),
+        }
+    })?;
+
+    // Watch the directories rather than the files, since editors and compilers often replace
+    // files instead of writing to them.
+    for path in &watched_paths {
+        if let Some(dir) = path.parent() {
+            watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        }
+    }
+    Ok(watcher)
+}
+
+/// Loads a Program from a yarnc file, along with the string table from the csv file next to it.
+fn load_program(proto_path: PathBuf) -> Result<(Program, Vec<LineInfo>), Box<dyn Error>> {
     // Read the file's bytes and load a Program.
     let proto_data = fs::read(&proto_path)?;
     let program = Program::decode(&*proto_data)?;
     // println!("{:#?}", &program);
 
     // Load LineInfos from a csv file.
-    let mut csv_path = proto_path;
-    csv_path.set_extension("csv");
+    let string_table = load_string_table(&proto_path.with_extension("csv"))?;
+
+    Ok((program, string_table))
+}
+
+/// Loads LineInfos from a csv file.
+fn load_string_table(csv_path: &Path) -> Result<Vec<LineInfo>, Box<dyn Error>> {
     let mut csv_reader = csv::Reader::from_path(csv_path)?;
-    let string_table: Vec<LineInfo> = csv_reader.deserialize()
-        .map(|result| result.unwrap())
-        .collect();
+    let string_table = csv_reader.deserialize()
+        .collect::<Result<Vec<LineInfo>, _>>()?;
+    Ok(string_table)
+}
 
-    // Run the virtual machine!
-    let mut vm = VirtualMachine::new(program);
-    if vm.program.nodes.contains_key(&start_node) {
-        // Set the start node.
-        vm.set_node(&start_node);
-
-        // Start executing.
-        loop {
-            match vm.continue_dialogue() {
-                SuspendReason::Line(line) => {
-                    let text = string_table.iter()
-                        .find(|line_info| line_info.id == line.id)
-                        .map(|line_info| &line_info.text);
-                    if let Some(text) = text {
-                        println!("{}", text);
-                    } else {
-                        // TODO: Could not find line, handle error.
-                    }
-                }
-                SuspendReason::Options(options) => {
-                    println!("== Choose option ==");
-                    for (i, opt) in options.iter().enumerate() {
-                        let text = string_table.iter()
-                            .find(|line_info| line_info.id == opt.line.id)
-                            .map(|line_info| &line_info.text);
-                        if let Some(text) = text {
-                            println!("{}: {}", i, text);
-                        } else {
-                            // TODO: Could not find line, handle error.
-                        }
-                    }
-
-                    // Block to accept input from player.
-                    let mut selection = String::new();
-                    io::stdin().read_line(&mut selection)?;
-                    let selection: u32 = selection.trim().parse()?;
-                    vm.set_selected_option(selection);
-                }
-                SuspendReason::Command(command_text) => {
-                    println!("== Command: {} ==", command_text);
-                }
-                SuspendReason::NodeChange { start, end } => {
-                    println!("== Node end: {} ==", end);
-                    println!("== Node start: {} ==", start);
-                }
-                SuspendReason::DialogueComplete(last_node) => {
-                    println!("== Node end: {} ==", last_node);
-                    println!("== Dialogue complete ==");
-                    break;
+/// Prints dialogue to the console, and reads option selections from stdin.
+struct ConsoleHandler {
+    string_table: Vec<LineInfo>,
+    /// Set by the watcher to the string table of the program it sends to be reloaded.
+    reloaded_string_table: Arc<Mutex<Option<Vec<LineInfo>>>>,
+}
+
+impl ConsoleHandler {
+    fn get_text(&self, line: &Line) -> Option<String> {
+        self.string_table.iter()
+            .find(|line_info| line_info.id == line.id)
+            .map(|line_info| line_info.text.clone())
+    }
+}
+
+impl DialogueHandler for ConsoleHandler {
+    fn line(&mut self, line: Line) -> impl Future<Output = ()> {
+        if let Some(text) = self.get_text(&line) {
+            println!("{}", text);
+        } else {
+            // TODO: Could not find line, handle error.
+        }
+        future::ready(())
+    }
+
+    fn options(&mut self, options: Vec<YarnOption>) -> impl Future<Output = u32> {
+        println!("== Choose option ==");
+        for (i, opt) in options.iter().enumerate() {
+            if let Some(text) = self.get_text(&opt.line) {
+                if opt.is_available {
+                    println!("{}: {}", i, text);
+                } else {
+                    println!("{}: {} (unavailable)", i, text);
                 }
+            } else {
+                // TODO: Could not find line, handle error.
             }
         }
-    } else {
-        eprintln!("Could not find start node: {}", DEFAULT_START_NODE_NAME);
+
+        // Block to accept input from player, until they pick a valid option.
+        let selection = loop {
+            let mut selection = String::new();
+            match io::stdin().read_line(&mut selection) {
+                Ok(0) | Err(_) => {
+                    eprintln!("Could not read an option selection");
+                    process::exit(1);
+                }
+                Ok(_) => {}
+            }
+            match selection.trim().parse::<u32>() {
+                Ok(selection) if options.get(selection as usize).is_some_and(|opt| opt.is_available) => break selection,
+                _ => println!("Please enter the number of an available option"),
+            }
+        };
+        future::ready(selection)
     }
 
-    Ok(())
+    fn command(&mut self, command: String) -> impl Future<Output = ()> {
+        println!("== Command: {} ==", command);
+        future::ready(())
+    }
+
+    fn dialogue_start(&mut self) -> impl Future<Output = ()> {
+        println!("== Dialogue start ==");
+        future::ready(())
+    }
+
+    fn node_start(&mut self, node_name: String) -> impl Future<Output = ()> {
+        println!("== Node start: {} ==", node_name);
+        future::ready(())
+    }
+
+    fn node_complete(&mut self, node_name: String) -> impl Future<Output = ()> {
+        println!("== Node end: {} ==", node_name);
+        future::ready(())
+    }
+
+    fn dialogue_complete(&mut self) -> impl Future<Output = ()> {
+        println!("== Dialogue complete ==");
+        future::ready(())
+    }
+
+    fn program_reloaded(&mut self, _outcome: ReloadOutcome) -> impl Future<Output = ()> {
+        // When reloads queue up, the first one swaps in the latest string table, and the rest
+        // find nothing to swap in. The dialogue doesn't continue until they're all done.
+        if let Some(string_table) = self.reloaded_string_table.lock().unwrap().take() {
+            self.string_table = string_table;
+        }
+        println!("== Reloaded ==");
+        future::ready(())
+    }
 }
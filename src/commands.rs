@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::future::{self, Future};
+use std::pin::Pin;
+use std::time::Duration;
+
+use crate::{YarnType, YarnValue};
+
+type CommandFuture = Pin<Box<dyn Future<Output = ()>>>;
+type CommandHandler = dyn FnMut(&[YarnValue]) -> CommandFuture;
+
+/// What should happen when a command is run, returned from [`CommandRegistry::dispatch`].
+pub enum CommandAction {
+    /// Run a registered command. Dialogue should continue once the future completes.
+    Run(CommandFuture),
+    /// The built-in `wait <seconds>` command. Dialogue should continue after the duration has
+    /// passed.
+    Wait(Duration),
+    /// The built-in `stop` command. Dialogue should stop immediately.
+    Stop,
+}
+
+/// An error produced while parsing or dispatching a command.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandError {
+    /// The command text was empty.
+    Empty,
+    /// A quoted string was never closed.
+    UnterminatedString,
+    /// No command with this name has been registered.
+    Unknown(String),
+    /// The command was given the wrong number of arguments.
+    WrongArgumentCount {
+        command: String,
+        expected: usize,
+        received: usize,
+    },
+    /// An argument could not be converted to the type the command expects.
+    InvalidArgument {
+        command: String,
+        index: usize,
+        expected: YarnType,
+        argument: String,
+    },
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "Command is empty"),
+            Self::UnterminatedString => write!(f, "Command has an unterminated string"),
+            Self::Unknown(command) => write!(f, "Unknown command {}", command),
+            Self::WrongArgumentCount { command, expected, received } => write!(
+                f,
+                "Command {} expected {} arguments, but received {}",
+                command,
+                expected,
+                received,
+            ),
+            Self::InvalidArgument { command, index, expected, argument } => write!(
+                f,
+                "Argument {} of command {} should be of type {:?}, but was \"{}\"",
+                index,
+                command,
+                expected,
+                argument,
+            ),
+        }
+    }
+}
+
+impl Error for CommandError {}
+
+/// Splits command text into its name and arguments.
+///
+/// Arguments are separated by whitespace. An argument can contain whitespace if it's wrapped in
+/// double quotes, and `\"` and `\\` can be used to include quotes and backslashes inside it.
+pub fn split_command(text: &str) -> Result<Vec<String>, CommandError> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    loop {
+        // Skip the whitespace between tokens.
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+
+        let mut token = String::new();
+        match chars.peek() {
+            None => break,
+            Some('"') => {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(escaped_char @ ('"' | '\\')) => token.push(escaped_char),
+                            Some(other) => {
+                                token.push('\\');
+                                token.push(other);
+                            }
+                            None => return Err(CommandError::UnterminatedString),
+                        },
+                        Some(c) => token.push(c),
+                        None => return Err(CommandError::UnterminatedString),
+                    }
+                }
+            }
+            Some(_) => {
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    token.push(c);
+                    chars.next();
+                }
+            }
+        }
+        tokens.push(token);
+    }
+
+    if tokens.is_empty() {
+        return Err(CommandError::Empty);
+    }
+    Ok(tokens)
+}
+
+/// Converts a command argument into a value of the given type.
+fn parse_argument(argument: &str, expected: YarnType) -> Option<YarnValue> {
+    match expected {
+        YarnType::Number => {
+            argument.parse::<f32>().ok().map(YarnValue::Number)
+        }
+        YarnType::Bool => {
+            match argument.to_lowercase().as_str() {
+                "true" => Some(YarnValue::Bool(true)),
+                "false" => Some(YarnValue::Bool(false)),
+                _ => None,
+            }
+        }
+        YarnType::String => {
+            Some(YarnValue::Str(argument.to_string()))
+        }
+        YarnType::Any => {
+            parse_argument(argument, YarnType::Number)
+                .or_else(|| parse_argument(argument, YarnType::Bool))
+                .or_else(|| parse_argument(argument, YarnType::String))
+        }
+    }
+}
+
+struct CommandInfo {
+    params: Vec<YarnType>,
+    handler: Box<CommandHandler>,
+}
+
+/// A collection of commands that can be run by name, with arguments converted to the types each
+/// command expects.
+///
+/// The built-in `wait <seconds>` and `stop` commands are always available, unless they've been
+/// replaced by registering a command with the same name.
+#[derive(Default)]
+pub struct CommandRegistry {
+    commands: HashMap<String, CommandInfo>,
+}
+
+impl CommandRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a command that finishes as soon as its handler returns.
+    pub fn add_command<F>(&mut self, name: &str, params: &[YarnType], mut handler: F)
+    where
+        F: FnMut(&[YarnValue]) + 'static,
+    {
+        self.add_async_command(name, params, move |args| {
+            handler(args);
+            future::ready(())
+        });
+    }
+
+    /// Registers a command whose handler returns a future. Dialogue doesn't continue until the
+    /// future completes.
+    pub fn add_async_command<F, Fut>(&mut self, name: &str, params: &[YarnType], mut handler: F)
+    where
+        F: FnMut(&[YarnValue]) -> Fut + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        let info = CommandInfo {
+            params: params.to_vec(),
+            handler: Box::new(move |args| Box::pin(handler(args))),
+        };
+        self.commands.insert(name.to_string(), info);
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.commands.contains_key(name)
+    }
+
+    /// Parses command text, and returns what should be done to run it.
+    pub fn dispatch(&mut self, text: &str) -> Result<CommandAction, CommandError> {
+        let tokens = split_command(text)?;
+        let (name, arguments) = tokens.split_first().unwrap();
+
+        let (params, handler) = match self.commands.get_mut(name.as_str()) {
+            Some(info) => (info.params.as_slice(), Some(&mut info.handler)),
+            None => match name.as_str() {
+                "wait" => (&[YarnType::Number][..], None),
+                "stop" => (&[][..], None),
+                _ => return Err(CommandError::Unknown(name.clone())),
+            },
+        };
+
+        if params.len() != arguments.len() {
+            return Err(CommandError::WrongArgumentCount {
+                command: name.clone(),
+                expected: params.len(),
+                received: arguments.len(),
+            });
+        }
+
+        let mut values = Vec::with_capacity(arguments.len());
+        for (index, (argument, &expected)) in arguments.iter().zip(params).enumerate() {
+            match parse_argument(argument, expected) {
+                Some(value) => values.push(value),
+                None => return Err(CommandError::InvalidArgument {
+                    command: name.clone(),
+                    index,
+                    expected,
+                    argument: argument.clone(),
+                }),
+            }
+        }
+
+        let action = match handler {
+            Some(handler) => CommandAction::Run(handler(&values)),
+            None if name == "wait" => {
+                let seconds = values[0].as_number();
+                if !(seconds >= 0.0 && seconds.is_finite()) {
+                    return Err(CommandError::InvalidArgument {
+                        command: name.clone(),
+                        index: 0,
+                        expected: YarnType::Number,
+                        argument: arguments[0].clone(),
+                    });
+                }
+                CommandAction::Wait(Duration::from_secs_f32(seconds))
+            }
+            None => CommandAction::Stop,
+        };
+        Ok(action)
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use crate::{is_smart_variable, Program};
+use crate::yarn_proto::{
+    instruction::OpCode,
+    operand::Value,
+    Instruction as ProtoInstruction,
+};
+
+/// An index into the strings of a [`CompiledProgram`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct StringId(u32);
+
+/// A problem that stops a [`Program`] from being loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgramError {
+    /// The instruction at the given index in the node has an unknown opcode, or operands that
+    /// don't match its opcode.
+    InvalidInstruction {
+        node: String,
+        index: usize,
+        opcode: i32,
+    },
+    /// An instruction in the node jumps to a label that isn't in the node.
+    UnknownLabel {
+        node: String,
+        label: String,
+    },
+}
+
+impl fmt::Display for ProgramError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidInstruction { node, index, opcode } => write!(
+                f,
+                "Instruction {} in node {} has an invalid opcode ({}) or operands",
+                index,
+                node,
+                opcode,
+            ),
+            Self::UnknownLabel { node, label } => write!(f, "Node {} has no label {}", node, label),
+        }
+    }
+}
+
+impl Error for ProgramError {}
+
+/// An instruction with its operands decoded, so that running it doesn't need to look anything
+/// up in the original [`Program`]. Jumps to labels are resolved to instruction indices.
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum Instruction {
+    JumpTo(usize),
+    Jump,
+    RunLine {
+        line_id: StringId,
+        expression_count: usize,
+    },
+    RunCommand {
+        text: StringId,
+        expression_count: usize,
+    },
+    AddOption {
+        line_id: StringId,
+        destination: StringId,
+        expression_count: usize,
+        has_condition: bool,
+    },
+    ShowOptions,
+    PushString(StringId),
+    PushFloat(f32),
+    PushBool(bool),
+    PushNull,
+    JumpIfFalse(usize),
+    Pop,
+    CallFunc(StringId),
+    PushVariable {
+        name: StringId,
+        /// The node that calculates the variable, if it's a smart variable.
+        smart_variable_node: Option<usize>,
+    },
+    StoreVariable {
+        name: StringId,
+        /// Whether the variable is a smart variable, which can't be stored.
+        is_smart_variable: bool,
+    },
+    Stop,
+    RunNode,
+    AddSaliencyCandidate {
+        content_id: StringId,
+        complexity: i32,
+        destination: StringId,
+    },
+    SelectSaliencyCandidate,
+    DetourToNode(StringId),
+    PeekAndDetourToNode,
+    Return,
+}
+
+pub(crate) struct CompiledNode {
+    pub name: String,
+    pub instructions: Vec<Instruction>,
+    /// Labels are still needed for jumps to a label on the stack, like the selected option's.
+    pub labels: HashMap<String, usize>,
+}
+
+/// A [`Program`] lowered into a form that is faster to run. Nodes are referred to by index,
+/// labels are resolved to instruction indices, and strings are stored once and referred to by
+/// [`StringId`].
+pub(crate) struct CompiledProgram {
+    pub nodes: Vec<CompiledNode>,
+    node_indices: HashMap<String, usize>,
+    strings: Vec<String>,
+}
+
+impl CompiledProgram {
+    pub fn new(program: &Program) -> Result<Self, ProgramError> {
+        // Sort the nodes so that their indices don't depend on the map's order.
+        let mut node_names: Vec<&String> = program.nodes.keys().collect();
+        node_names.sort();
+        let node_indices: HashMap<String, usize> = node_names.iter()
+            .enumerate()
+            .map(|(i, &name)| (name.clone(), i))
+            .collect();
+
+        let mut lowering = Lowering {
+            program,
+            node_indices: &node_indices,
+            strings: Vec::new(),
+            string_ids: HashMap::new(),
+        };
+        let nodes = node_names.into_iter()
+            .map(|name| {
+                let node = &program.nodes[name];
+                let labels: HashMap<String, usize> = node.labels.iter()
+                    .map(|(label, &i)| (label.clone(), i as usize))
+                    .collect();
+                let instructions = node.instructions.iter()
+                    .enumerate()
+                    .map(|(i, instruction)| lowering.lower(name, i, instruction, &labels))
+                    .collect::<Result<_, _>>()?;
+                Ok(CompiledNode {
+                    name: name.clone(),
+                    instructions,
+                    labels,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        let strings = lowering.strings;
+        Ok(Self {
+            nodes,
+            node_indices,
+            strings,
+        })
+    }
+
+    pub fn node_index(&self, name: &str) -> Option<usize> {
+        self.node_indices.get(name).copied()
+    }
+
+    pub fn string(&self, id: StringId) -> &str {
+        &self.strings[id.0 as usize]
+    }
+}
+
+/// A [`Program`] that is ready to be run, which can be shared by many
+/// [`VirtualMachine`](crate::VirtualMachine)s.
+///
+/// The program is lowered into a faster form once, when this is created, and cloning it only
+/// clones a reference to the same program, so creating a VM from it is cheap. It derefs to the
+/// [`Program`], which can't be changed once it's shared.
+///
+/// The [`Program`] is kept alongside the lowered form, because linting, graphs, lookahead and
+/// reloading read its instructions, so a loaded program takes about twice the memory of the
+/// [`Program`] on its own.
+#[derive(Clone)]
+pub struct SharedProgram(Arc<LoadedProgram>);
+
+struct LoadedProgram {
+    program: Program,
+    compiled: CompiledProgram,
+}
+
+impl SharedProgram {
+    /// Loads a program, checking that every instruction in it can be run.
+    pub fn new(program: Program) -> Result<Self, ProgramError> {
+        let compiled = CompiledProgram::new(&program)?;
+        Ok(Self(Arc::new(LoadedProgram {
+            program,
+            compiled,
+        })))
+    }
+
+    pub(crate) fn compiled(&self) -> &CompiledProgram {
+        &self.0.compiled
+    }
+}
+
+impl TryFrom<Program> for SharedProgram {
+    type Error = ProgramError;
+
+    fn try_from(program: Program) -> Result<Self, ProgramError> {
+        Self::new(program)
+    }
+}
+
+impl Deref for SharedProgram {
+    type Target = Program;
+
+    fn deref(&self) -> &Program {
+        &self.0.program
+    }
+}
+
+struct Lowering<'a> {
+    program: &'a Program,
+    node_indices: &'a HashMap<String, usize>,
+    strings: Vec<String>,
+    string_ids: HashMap<String, StringId>,
+}
+
+impl Lowering<'_> {
+    fn intern(&mut self, s: &str) -> StringId {
+        if let Some(&id) = self.string_ids.get(s) {
+            return id;
+        }
+        let id = StringId(self.strings.len() as u32);
+        self.strings.push(s.to_string());
+        self.string_ids.insert(s.to_string(), id);
+        id
+    }
+
+    fn lower(
+        &mut self,
+        node_name: &str,
+        index: usize,
+        instruction: &ProtoInstruction,
+        labels: &HashMap<String, usize>,
+    ) -> Result<Instruction, ProgramError> {
+        let operand = |i: usize| instruction.operands.get(i).and_then(|o| o.value.as_ref());
+        let string = |i: usize| match operand(i) {
+            Some(Value::StringValue(val)) => Some(val.as_str()),
+            _ => None,
+        };
+        let float = |i: usize| match operand(i) {
+            Some(Value::FloatValue(val)) => Some(*val),
+            _ => None,
+        };
+        let expression_count = |i: usize| float(i).map_or(0, |count| count as usize);
+        let target = |label: &str| labels.get(label).copied().ok_or_else(|| ProgramError::UnknownLabel {
+            node: node_name.to_string(),
+            label: label.to_string(),
+        });
+
+        let lowered = match OpCode::from_i32(instruction.opcode) {
+            Some(OpCode::JumpTo) => string(0).map(target).transpose()?.map(Instruction::JumpTo),
+            Some(OpCode::Jump) => Some(Instruction::Jump),
+            Some(OpCode::RunLine) => string(0).map(|line_id| Instruction::RunLine {
+                line_id: self.intern(line_id),
+                expression_count: expression_count(1),
+            }),
+            Some(OpCode::RunCommand) => string(0).map(|text| Instruction::RunCommand {
+                text: self.intern(text),
+                expression_count: expression_count(1),
+            }),
+            Some(OpCode::AddOption) => match (string(0), string(1)) {
+                (Some(line_id), Some(destination)) => Some(Instruction::AddOption {
+                    line_id: self.intern(line_id),
+                    destination: self.intern(destination),
+                    expression_count: expression_count(2),
+                    has_condition: matches!(operand(3), Some(Value::BoolValue(true))),
+                }),
+                _ => None,
+            },
+            Some(OpCode::ShowOptions) => Some(Instruction::ShowOptions),
+            Some(OpCode::PushString) => string(0).map(|val| Instruction::PushString(self.intern(val))),
+            Some(OpCode::PushFloat) => float(0).map(Instruction::PushFloat),
+            Some(OpCode::PushBool) => match operand(0) {
+                Some(Value::BoolValue(val)) => Some(Instruction::PushBool(*val)),
+                _ => None,
+            },
+            Some(OpCode::PushNull) => Some(Instruction::PushNull),
+            Some(OpCode::JumpIfFalse) => string(0).map(target).transpose()?.map(Instruction::JumpIfFalse),
+            Some(OpCode::Pop) => Some(Instruction::Pop),
+            Some(OpCode::CallFunc) => string(0).map(|name| Instruction::CallFunc(self.intern(name))),
+            Some(OpCode::PushVariable) => string(0).map(|name| Instruction::PushVariable {
+                name: self.intern(name),
+                smart_variable_node: Some(name)
+                    .filter(|name| is_smart_variable(self.program, name))
+                    .and_then(|name| self.node_indices.get(name))
+                    .copied(),
+            }),
+            Some(OpCode::StoreVariable) => string(0).map(|name| Instruction::StoreVariable {
+                name: self.intern(name),
+                is_smart_variable: is_smart_variable(self.program, name),
+            }),
+            Some(OpCode::Stop) => Some(Instruction::Stop),
+            Some(OpCode::RunNode) => Some(Instruction::RunNode),
+            Some(OpCode::AddSaliencyCandidate) => match (string(0), float(1), string(2)) {
+                (Some(content_id), Some(complexity), Some(destination)) => Some(Instruction::AddSaliencyCandidate {
+                    content_id: self.intern(content_id),
+                    complexity: complexity as i32,
+                    destination: self.intern(destination),
+                }),
+                _ => None,
+            },
+            Some(OpCode::SelectSaliencyCandidate) => Some(Instruction::SelectSaliencyCandidate),
+            Some(OpCode::DetourToNode) => string(0).map(|node_name| Instruction::DetourToNode(self.intern(node_name))),
+            Some(OpCode::PeekAndDetourToNode) => Some(Instruction::PeekAndDetourToNode),
+            Some(OpCode::Return) => Some(Instruction::Return),
+            None => None,
+        };
+        lowered.ok_or_else(|| ProgramError::InvalidInstruction {
+            node: node_name.to_string(),
+            index,
+            opcode: instruction.opcode,
+        })
+    }
+}
@@ -0,0 +1,446 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+use crate::{EnumDeclarations, FunctionInfo, FunctionSignature, VariableError, YarnType, YarnValue};
+
+/// An error produced while parsing or evaluating an expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExpressionError {
+    /// A character that can't start any token was found at the given byte offset.
+    UnexpectedCharacter(usize, char),
+    /// A token that doesn't fit the expression grammar was found at the given byte offset.
+    UnexpectedToken(usize, String),
+    /// The expression ended before it was complete.
+    UnexpectedEnd,
+    /// A string literal was never closed.
+    UnterminatedString(usize),
+    /// The expression calls a function that isn't in the library.
+    UnknownFunction(String),
+    /// A function was called with the wrong number of arguments.
+    WrongArgumentCount {
+        function: String,
+        signature: FunctionSignature,
+        received: usize,
+    },
+    /// A function was called with an argument of a type it doesn't accept.
+    WrongArgumentType {
+        function: String,
+        index: usize,
+        expected: YarnType,
+        received: YarnType,
+    },
+    /// An operator was applied to values of types it doesn't support, like `"a" * 2`.
+    InvalidOperands {
+        function: String,
+        received: Vec<YarnType>,
+    },
+    /// A function that doesn't return a value was used in an expression.
+    NoReturnValue(String),
+    /// The expression uses an enum case that isn't declared.
+    UnknownEnumCase {
+        enum_name: String,
+        case: String,
+    },
+    /// The expression reads a smart variable that couldn't be calculated.
+    Variable(VariableError),
+}
+
+impl fmt::Display for ExpressionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnexpectedCharacter(pos, c) => write!(f, "Unexpected character '{}' at {}", c, pos),
+            Self::UnexpectedToken(pos, token) => write!(f, "Unexpected \"{}\" at {}", token, pos),
+            Self::UnexpectedEnd => write!(f, "Unexpected end of expression"),
+            Self::UnterminatedString(pos) => write!(f, "Unterminated string starting at {}", pos),
+            Self::UnknownFunction(name) => write!(f, "Unknown function {}", name),
+            Self::WrongArgumentCount { function, signature, received } => write!(
+                f,
+                "Function {}{} received {} arguments",
+                function,
+                signature,
+                received,
+            ),
+            Self::WrongArgumentType { function, index, expected, received } => write!(
+                f,
+                "Function {} received a {:?} as argument {}, but expects a {:?}",
+                function,
+                received,
+                index,
+                expected,
+            ),
+            Self::InvalidOperands { function, received } => {
+                write!(f, "{} can't be applied to values of types {:?}", function, received)
+            }
+            Self::NoReturnValue(name) => write!(f, "Function {} does not return a value", name),
+            Self::UnknownEnumCase { enum_name, case } => write!(f, "Unknown enum case {}.{}", enum_name, case),
+            Self::Variable(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for ExpressionError {}
+
+impl From<VariableError> for ExpressionError {
+    fn from(error: VariableError) -> Self {
+        Self::Variable(error)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f32),
+    Str(String),
+    Variable(String),
+    Identifier(String),
+    EnumCase(String, String),
+    LeftParen,
+    RightParen,
+    Comma,
+    Operator(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Number(val) => write!(f, "{}", val),
+            Self::Str(val) => write!(f, "\"{}\"", val),
+            Self::Variable(name) | Self::Identifier(name) => write!(f, "{}", name),
+            Self::EnumCase(enum_name, case) => write!(f, "{}.{}", enum_name, case),
+            Self::LeftParen => write!(f, "("),
+            Self::RightParen => write!(f, ")"),
+            Self::Comma => write!(f, ","),
+            Self::Operator(op) => write!(f, "{}", op),
+        }
+    }
+}
+
+/// A parsed expression. Operators are represented as calls to the library functions that
+/// implement them, the same way the compiler does.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Expression {
+    Value(YarnValue),
+    Variable(String),
+    EnumCase(String, String),
+    Call(String, Vec<Expression>),
+}
+
+impl Expression {
+    /// The names of the variables the expression reads, in the order they appear.
+    pub(crate) fn variables(&self) -> Vec<&str> {
+        match self {
+            Self::Variable(name) => vec![name],
+            Self::Value(_) | Self::EnumCase(..) => Vec::new(),
+            Self::Call(_, args) => args.iter().flat_map(Self::variables).collect(),
+        }
+    }
+}
+
+/// Maps the operators that can appear between two expressions to the functions implementing
+/// them, along with their precedence. Higher precedences bind more tightly.
+fn binary_operator(op: &str) -> Option<(&'static str, u8)> {
+    let operator = match op {
+        "and" | "&&" => ("And", 1),
+        "or" | "||" => ("Or", 1),
+        "xor" | "^" => ("Xor", 1),
+        "==" | "is" | "eq" => ("EqualTo", 2),
+        "!=" | "neq" => ("NotEqualTo", 2),
+        ">" | "gt" => ("GreaterThan", 3),
+        ">=" | "gte" => ("GreaterThanOrEqualTo", 3),
+        "<" | "lt" => ("LessThan", 3),
+        "<=" | "lte" => ("LessThanOrEqualTo", 3),
+        "+" => ("Add", 4),
+        "-" => ("Minus", 4),
+        "*" => ("Multiply", 5),
+        "/" => ("Divide", 5),
+        "%" => ("Modulo", 5),
+        _ => return None,
+    };
+    Some(operator)
+}
+
+const WORD_OPERATORS: &[&str] = &[
+    "and", "or", "xor", "not", "is", "eq", "neq", "gt", "gte", "lt", "lte",
+];
+
+const SYMBOL_OPERATORS: &[&str] = &[
+    "&&", "||", "==", "!=", ">=", "<=", "^", ">", "<", "!", "+", "-", "*", "/", "%",
+];
+
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, ExpressionError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some(&(pos, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        let token = match c {
+            '(' => {
+                chars.next();
+                Token::LeftParen
+            }
+            ')' => {
+                chars.next();
+                Token::RightParen
+            }
+            ',' => {
+                chars.next();
+                Token::Comma
+            }
+            '"' => {
+                chars.next();
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, escaped_char)) => string.push(escaped_char),
+                            None => return Err(ExpressionError::UnterminatedString(pos)),
+                        },
+                        Some((_, c)) => string.push(c),
+                        None => return Err(ExpressionError::UnterminatedString(pos)),
+                    }
+                }
+                Token::Str(string)
+            }
+            '$' => {
+                chars.next();
+                let name = read_word(&mut chars);
+                if name.is_empty() {
+                    return Err(ExpressionError::UnexpectedCharacter(pos, c));
+                }
+                Token::Variable(format!("${}", name))
+            }
+            c if c.is_ascii_digit() || c == '.' => {
+                let mut number = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if !c.is_ascii_digit() && c != '.' {
+                        break;
+                    }
+                    number.push(c);
+                    chars.next();
+                }
+                let number = number.parse()
+                    .map_err(|_| ExpressionError::UnexpectedToken(pos, number))?;
+                Token::Number(number)
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let word = read_word(&mut chars);
+                match WORD_OPERATORS.iter().find(|&&op| op == word) {
+                    Some(op) => Token::Operator(op),
+                    None => match word.split_once('.') {
+                        Some((enum_name, case)) => Token::EnumCase(enum_name.to_string(), case.to_string()),
+                        None => Token::Identifier(word),
+                    },
+                }
+            }
+            _ => {
+                let rest = &input[pos..];
+                let op = SYMBOL_OPERATORS.iter()
+                    .find(|&&op| rest.starts_with(op))
+                    .ok_or(ExpressionError::UnexpectedCharacter(pos, c))?;
+                for _ in 0..op.len() {
+                    chars.next();
+                }
+                Token::Operator(op)
+            }
+        };
+        tokens.push((pos, token));
+    }
+
+    Ok(tokens)
+}
+
+fn read_word(chars: &mut Peekable<CharIndices>) -> String {
+    let mut word = String::new();
+    while let Some(&(_, c)) = chars.peek() {
+        if !c.is_alphanumeric() && c != '_' && c != '.' {
+            break;
+        }
+        word.push(c);
+        chars.next();
+    }
+    word
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(_, token)| token)
+    }
+
+    fn advance(&mut self) -> Result<(usize, Token), ExpressionError> {
+        let token = self.tokens.get(self.next)
+            .cloned()
+            .ok_or(ExpressionError::UnexpectedEnd)?;
+        self.next += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), ExpressionError> {
+        match self.advance()? {
+            (_, token) if token == expected => Ok(()),
+            (pos, token) => Err(ExpressionError::UnexpectedToken(pos, token.to_string())),
+        }
+    }
+
+    // expression = unary (binary_operator unary)*
+    fn parse_expression(&mut self, min_precedence: u8) -> Result<Expression, ExpressionError> {
+        let mut lhs = self.parse_unary()?;
+
+        while let Some(Token::Operator(op)) = self.peek() {
+            let (function, precedence) = match binary_operator(op) {
+                Some(operator) if operator.1 >= min_precedence => operator,
+                _ => break,
+            };
+            self.advance()?;
+
+            // All binary operators are left associative.
+            let rhs = self.parse_expression(precedence + 1)?;
+            lhs = Expression::Call(function.to_string(), vec![lhs, rhs]);
+        }
+
+        Ok(lhs)
+    }
+
+    // unary = ("-" | "!" | "not") unary | primary
+    fn parse_unary(&mut self) -> Result<Expression, ExpressionError> {
+        match self.peek() {
+            Some(Token::Operator("-")) => {
+                self.advance()?;
+                Ok(Expression::Call("UnaryMinus".to_string(), vec![self.parse_unary()?]))
+            }
+            Some(Token::Operator("!")) | Some(Token::Operator("not")) => {
+                self.advance()?;
+                Ok(Expression::Call("Not".to_string(), vec![self.parse_unary()?]))
+            }
+            _ => self.parse_primary(),
+        }
+    }
+
+    // primary = number | string | variable | enum_case | "true" | "false" | "null"
+    //         | identifier "(" (expression ("," expression)*)? ")"
+    //         | "(" expression ")"
+    fn parse_primary(&mut self) -> Result<Expression, ExpressionError> {
+        let expression = match self.advance()? {
+            (_, Token::Number(val)) => Expression::Value(YarnValue::Number(val)),
+            (_, Token::Str(val)) => Expression::Value(YarnValue::Str(val)),
+            (_, Token::Variable(name)) => Expression::Variable(name),
+            (_, Token::EnumCase(enum_name, case)) => Expression::EnumCase(enum_name, case),
+            (_, Token::Identifier(name)) if name == "true" => Expression::Value(YarnValue::Bool(true)),
+            (_, Token::Identifier(name)) if name == "false" => Expression::Value(YarnValue::Bool(false)),
+            (_, Token::Identifier(name)) if name == "null" => Expression::Value(YarnValue::Null),
+            (_, Token::Identifier(name)) => {
+                self.expect(Token::LeftParen)?;
+                let mut args = Vec::new();
+                if self.peek() != Some(&Token::RightParen) {
+                    loop {
+                        args.push(self.parse_expression(0)?);
+                        if self.peek() == Some(&Token::Comma) {
+                            self.advance()?;
+                        } else {
+                            break;
+                        }
+                    }
+                }
+                self.expect(Token::RightParen)?;
+                Expression::Call(name, args)
+            }
+            (_, Token::LeftParen) => {
+                let expression = self.parse_expression(0)?;
+                self.expect(Token::RightParen)?;
+                expression
+            }
+            (pos, token) => return Err(ExpressionError::UnexpectedToken(pos, token.to_string())),
+        };
+        Ok(expression)
+    }
+}
+
+/// Parses an expression written in Yarn's expression syntax.
+pub(crate) fn parse(input: &str) -> Result<Expression, ExpressionError> {
+    let mut parser = Parser {
+        tokens: tokenize(input)?,
+        next: 0,
+    };
+    let expression = parser.parse_expression(0)?;
+
+    // Make sure the whole input was used.
+    if let Some((pos, token)) = parser.tokens.get(parser.next) {
+        return Err(ExpressionError::UnexpectedToken(*pos, token.to_string()));
+    }
+    Ok(expression)
+}
+
+/// Evaluates a parsed expression, reading variables with `variable`, looking up functions in the
+/// library, and enum cases in the given declarations.
+pub(crate) fn evaluate(
+    expression: &Expression,
+    variable: &dyn Fn(&str) -> Option<YarnValue>,
+    library: &HashMap<String, FunctionInfo>,
+    enums: &EnumDeclarations,
+) -> Result<YarnValue, ExpressionError> {
+    match expression {
+        Expression::Value(val) => Ok(val.clone()),
+        Expression::Variable(name) => {
+            // Undefined variables are null, like they are when running dialogue.
+            match variable(name) {
+                Some(val) => Ok(enums.to_typed(name, val)),
+                None => Ok(YarnValue::Null),
+            }
+        }
+        Expression::EnumCase(enum_name, case) => {
+            if !enums.is_case(enum_name, case) {
+                return Err(ExpressionError::UnknownEnumCase {
+                    enum_name: enum_name.clone(),
+                    case: case.clone(),
+                });
+            }
+            Ok(YarnValue::Enum {
+                enum_name: enum_name.clone(),
+                case: case.clone(),
+            })
+        }
+        Expression::Call(name, args) => {
+            let function = library.get(name)
+                .ok_or_else(|| ExpressionError::UnknownFunction(name.clone()))?;
+
+            if !function.signature.accepts_count(args.len()) {
+                return Err(ExpressionError::WrongArgumentCount {
+                    function: name.clone(),
+                    signature: function.signature.clone(),
+                    received: args.len(),
+                });
+            }
+
+            let parameters = args.iter()
+                .map(|arg| evaluate(arg, variable, library, enums))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            if let Some((index, expected, received)) = function.signature.mismatched_argument(&parameters) {
+                return Err(ExpressionError::WrongArgumentType {
+                    function: name.clone(),
+                    index,
+                    expected,
+                    received,
+                });
+            }
+
+            function.func.call(&parameters)
+                .map_err(|()| ExpressionError::InvalidOperands {
+                    function: name.clone(),
+                    received: parameters.iter().map(YarnValue::yarn_type).collect(),
+                })?
+                .ok_or_else(|| ExpressionError::NoReturnValue(name.clone()))
+        }
+    }
+}
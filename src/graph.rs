@@ -0,0 +1,178 @@
+use std::fmt::Write;
+
+use serde::Serialize;
+
+use crate::{LineInfo, Program};
+use crate::yarn_proto::{
+    instruction::OpCode,
+    operand::Value,
+};
+
+/// How one node leads to another.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EdgeKind {
+    /// The player can pick an option that leads to the node.
+    Option,
+    /// The node is run directly, e.g. with `[[NodeName]]`.
+    RunNode,
+    /// The node is run with `<<detour>>`, and returns to this one when it finishes.
+    Detour,
+}
+
+/// A connection from one node to another.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GraphEdge {
+    pub from: String,
+    pub to: String,
+    pub kind: EdgeKind,
+    /// The text of the option that leads to the node, if there is one.
+    pub label: Option<String>,
+}
+
+/// A graph of how the nodes in a [`Program`] connect to each other, which can be exported to
+/// Graphviz DOT, Mermaid, or JSON.
+#[derive(Debug, Clone, Serialize)]
+pub struct DialogueGraph {
+    /// The names of all nodes in the program, in alphabetical order.
+    pub nodes: Vec<String>,
+    pub edges: Vec<GraphEdge>,
+}
+
+impl DialogueGraph {
+    /// Builds a graph by walking the instructions of every node in the program.
+    ///
+    /// An edge is added for every option whose destination is a node, and for every node that
+    /// is detoured to by name, or run or detoured to with a name pushed just before it. Options
+    /// that jump to a label inside the same node (shortcut options) don't leave the node, so
+    /// they don't produce edges. Option edges are labelled with the option's text from the
+    /// string table, or its line ID if the text can't be found.
+    pub fn new(program: &Program, string_table: &[LineInfo]) -> Self {
+        let mut nodes: Vec<String> = program.nodes.keys().cloned().collect();
+        nodes.sort();
+
+        let mut edges = Vec::new();
+        for node_name in &nodes {
+            let node = &program.nodes[node_name];
+
+            let mut last_pushed_string = None;
+            for instruction in &node.instructions {
+                let string_operand = |i: usize| match instruction.operands.get(i).and_then(|o| o.value.as_ref()) {
+                    Some(Value::StringValue(val)) => Some(val),
+                    _ => None,
+                };
+
+                let edge = match OpCode::from_i32(instruction.opcode) {
+                    Some(OpCode::AddOption) => {
+                        match (string_operand(0), string_operand(1)) {
+                            (Some(line_id), Some(destination)) if !node.labels.contains_key(destination) => {
+                                let label = string_table.iter()
+                                    .find(|line_info| line_info.id == *line_id)
+                                    .map_or(line_id, |line_info| &line_info.text);
+                                Some((destination.clone(), EdgeKind::Option, Some(label.clone())))
+                            }
+                            _ => None,
+                        }
+                    }
+                    Some(OpCode::RunNode) => {
+                        last_pushed_string.map(|destination: &String| (destination.clone(), EdgeKind::RunNode, None))
+                    }
+                    Some(OpCode::DetourToNode) => {
+                        string_operand(0).map(|destination| (destination.clone(), EdgeKind::Detour, None))
+                    }
+                    Some(OpCode::PeekAndDetourToNode) => {
+                        last_pushed_string.map(|destination: &String| (destination.clone(), EdgeKind::Detour, None))
+                    }
+                    _ => None,
+                };
+
+                if let Some((to, kind, label)) = edge {
+                    let edge = GraphEdge {
+                        from: node_name.clone(),
+                        to,
+                        kind,
+                        label,
+                    };
+                    if !edges.contains(&edge) {
+                        edges.push(edge);
+                    }
+                }
+
+                last_pushed_string = match OpCode::from_i32(instruction.opcode) {
+                    Some(OpCode::PushString) => string_operand(0),
+                    _ => None,
+                };
+            }
+        }
+
+        Self {
+            nodes,
+            edges,
+        }
+    }
+
+    /// Exports the graph in the Graphviz DOT format. Edges that run a node directly are dashed,
+    /// and detours are dotted.
+    pub fn to_dot(&self) -> String {
+        fn escape(s: &str) -> String {
+            s.replace('\\', "\\\\").replace('"', "\\\"")
+        }
+
+        let mut dot = String::from("digraph dialogue {\n");
+        for node in &self.nodes {
+            writeln!(dot, "    \"{}\";", escape(node)).unwrap();
+        }
+        for edge in &self.edges {
+            write!(dot, "    \"{}\" -> \"{}\"", escape(&edge.from), escape(&edge.to)).unwrap();
+            match (&edge.label, edge.kind) {
+                (Some(label), _) => write!(dot, " [label=\"{}\"]", escape(label)).unwrap(),
+                (None, EdgeKind::RunNode) => dot.push_str(" [style=dashed]"),
+                (None, EdgeKind::Detour) => dot.push_str(" [style=dotted]"),
+                (None, EdgeKind::Option) => {}
+            }
+            dot.push_str(";\n");
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Exports the graph as a Mermaid flowchart. Edges that run a node directly are dotted, and
+    /// detours are thick.
+    pub fn to_mermaid(&self) -> String {
+        fn escape(s: &str) -> String {
+            s.replace('"', "#quot;")
+        }
+
+        // Mermaid node IDs can't contain arbitrary characters, so number every node, including
+        // destinations that don't exist in the program.
+        let mut ids: Vec<&str> = self.nodes.iter().map(String::as_str).collect();
+        for edge in &self.edges {
+            if !ids.contains(&edge.to.as_str()) {
+                ids.push(&edge.to);
+            }
+        }
+        let id = |name: &str| ids.iter().position(|&n| n == name).unwrap();
+
+        let mut mermaid = String::from("flowchart TD\n");
+        for (i, node) in ids.iter().enumerate() {
+            writeln!(mermaid, "    n{}[\"{}\"]", i, escape(node)).unwrap();
+        }
+        for edge in &self.edges {
+            let arrow = match edge.kind {
+                EdgeKind::Option => "-->",
+                EdgeKind::RunNode => "-.->",
+                EdgeKind::Detour => "==>",
+            };
+            match &edge.label {
+                Some(label) => writeln!(mermaid, "    n{} {}|\"{}\"| n{}", id(&edge.from), arrow, escape(label), id(&edge.to)),
+                None => writeln!(mermaid, "    n{} {} n{}", id(&edge.from), arrow, id(&edge.to)),
+            }.unwrap();
+        }
+        mermaid
+    }
+
+    /// Exports the graph as JSON, with a list of `nodes` and a list of `edges`.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
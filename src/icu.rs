@@ -0,0 +1,217 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::utils::FormatFunctionError;
+
+/// The syntax that translations are written in, when importing or checking them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MessageSyntax {
+    /// Yarn Spinner's [format functions](https://yarnspinner.dev/docs/syntax#format-functions),
+    /// which the translations are kept in.
+    FormatFunctions,
+    /// ICU MessageFormat, which is converted with [`icu_to_format_functions`].
+    Icu,
+}
+
+/// Converts a line written in [ICU MessageFormat](https://unicode-org.github.io/icu/userguide/format_parse/messages/)
+/// syntax into [format functions](https://yarnspinner.dev/docs/syntax#format-functions), so
+/// that translators can use the syntax their tools already know.
+///
+/// Arguments are the line's substitutions, referred to by number. `select`, `plural` and
+/// `selectordinal` arguments become `select`, `plural` and `ordinal` functions, and can be
+/// nested inside each other's branches:
+///
+/// ```text
+/// {0, plural, one {# coin} other {# coins}}
+/// [plural "{0}" one="% coin" other="% coins"]
+/// ```
+///
+/// Explicit values like `=0`, plural offsets, and other argument types like `number` aren't
+/// supported, since format functions have no equivalent for them.
+pub fn icu_to_format_functions(message: &str) -> Result<String, FormatFunctionError> {
+    let mut chars = message.chars().peekable();
+    let output = convert_text(&mut chars, &Context::default())?;
+    match chars.next() {
+        Some(c) => Err(FormatFunctionError::Expected(format!("the end of the message, not {}", c))),
+        None => Ok(output),
+    }
+}
+
+/// Where the text being converted is.
+#[derive(Default)]
+struct Context {
+    /// Whether the text is a branch of a function, which becomes a quoted string.
+    in_branch: bool,
+    /// The argument of the plural function that the text is in, if any, which `#` stands for.
+    plural_argument: Option<String>,
+    /// Whether the text is directly in a branch of that plural function, where `#` can be `%`.
+    in_plural_branch: bool,
+}
+
+/// Converts text up to the end of the message, or the `}` that ends a branch.
+fn convert_text(chars: &mut Peekable<Chars>, context: &Context) -> Result<String, FormatFunctionError> {
+    let mut output = String::new();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            '}' if context.in_branch => break,
+            '}' => return Err(FormatFunctionError::Expected("a { before }".to_string())),
+            '{' => {
+                chars.next();
+                output.push_str(&convert_argument(chars, context)?);
+            }
+            '#' => {
+                chars.next();
+                match &context.plural_argument {
+                    Some(_) if context.in_plural_branch => output.push('%'),
+                    Some(argument) => output.push_str(&format!("{{{}}}", argument)),
+                    None => push_literal(&mut output, "#", context),
+                }
+            }
+            '\'' => {
+                chars.next();
+                push_literal(&mut output, &quoted_literal(chars), context);
+            }
+            _ => {
+                chars.next();
+                push_literal(&mut output, &c.to_string(), context);
+            }
+        }
+    }
+
+    Ok(output)
+}
+
+/// Reads the text after an apostrophe. Two apostrophes are one apostrophe, and an apostrophe
+/// before syntax characters quotes them until the next apostrophe.
+fn quoted_literal(chars: &mut Peekable<Chars>) -> String {
+    match chars.peek() {
+        Some('\'') => {
+            chars.next();
+            "'".to_string()
+        }
+        Some('{') | Some('}') | Some('#') | Some('|') => {
+            let mut literal = String::new();
+            while let Some(c) = chars.next() {
+                if c != '\'' {
+                    literal.push(c);
+                } else if chars.peek() == Some(&'\'') {
+                    chars.next();
+                    literal.push('\'');
+                } else {
+                    break;
+                }
+            }
+            literal
+        }
+        _ => "'".to_string(),
+    }
+}
+
+/// Adds literal text to the output, escaping it if it's going inside a quoted string.
+fn push_literal(output: &mut String, literal: &str, context: &Context) {
+    for c in literal.chars() {
+        if context.in_branch && matches!(c, '"' | '\\' | '%') {
+            output.push('\\');
+        }
+        output.push(c);
+    }
+}
+
+/// Converts an argument, after its opening `{`, up to and including its closing `}`.
+fn convert_argument(chars: &mut Peekable<Chars>, context: &Context) -> Result<String, FormatFunctionError> {
+    let argument = read_word(chars);
+    if argument.is_empty() || !argument.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(FormatFunctionError::UnsupportedIcu(format!(
+            "argument \"{}\" isn't a substitution number",
+            argument,
+        )));
+    }
+
+    skip_whitespace(chars);
+    match chars.next() {
+        Some('}') => return Ok(format!("{{{}}}", argument)),
+        Some(',') => {}
+        Some(c) => return Err(FormatFunctionError::Expected(format!("a , or }}, not {}", c))),
+        None => return Err(FormatFunctionError::UnexpectedEnd),
+    }
+
+    let function = match read_word(chars).as_str() {
+        "select" => "select",
+        "plural" => "plural",
+        "selectordinal" => "ordinal",
+        "" => return Err(FormatFunctionError::Expected("an argument type".to_string())),
+        other => return Err(FormatFunctionError::UnsupportedIcu(format!("argument type {}", other))),
+    };
+    skip_whitespace(chars);
+    if chars.next() != Some(',') {
+        return Err(FormatFunctionError::Expected("a , after the argument type".to_string()));
+    }
+
+    let branch_context = if function == "select" {
+        Context {
+            in_branch: true,
+            plural_argument: context.plural_argument.clone(),
+            in_plural_branch: false,
+        }
+    } else {
+        Context {
+            in_branch: true,
+            plural_argument: Some(argument.clone()),
+            in_plural_branch: true,
+        }
+    };
+
+    let mut output = format!("[{} \"{{{}}}\"", function, argument);
+    loop {
+        skip_whitespace(chars);
+        match chars.peek() {
+            Some('}') => {
+                chars.next();
+                break;
+            }
+            Some('=') => return Err(FormatFunctionError::UnsupportedIcu("explicit values like =0".to_string())),
+            None => return Err(FormatFunctionError::UnexpectedEnd),
+            _ => {}
+        }
+
+        let key = read_word(chars);
+        if key.starts_with("offset:") {
+            return Err(FormatFunctionError::UnsupportedIcu("plural offsets".to_string()));
+        }
+        if key.is_empty() {
+            return Err(FormatFunctionError::Expected("a key".to_string()));
+        }
+        skip_whitespace(chars);
+        if chars.next() != Some('{') {
+            return Err(FormatFunctionError::Expected(format!("a {{ after key {}", key)));
+        }
+        let text = convert_text(chars, &branch_context)?;
+        if chars.next() != Some('}') {
+            return Err(FormatFunctionError::UnexpectedEnd);
+        }
+        output.push_str(&format!(" {}=\"{}\"", key, text));
+    }
+    output.push(']');
+
+    Ok(output)
+}
+
+fn read_word(chars: &mut Peekable<Chars>) -> String {
+    skip_whitespace(chars);
+    let mut word = String::new();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() || matches!(c, ',' | '{' | '}') {
+            break;
+        }
+        word.push(c);
+        chars.next();
+    }
+    word
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+        chars.next();
+    }
+}
@@ -1,24 +1,70 @@
 // #![warn(missing_docs)]
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
 
 use log::*;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+
+use crate::reload::map_position;
 
 pub use crate::{
     yarn_proto::Program,
+    commands::{split_command, CommandAction, CommandError, CommandRegistry},
+    compiled::{ProgramError, SharedProgram},
+    expression::ExpressionError,
+    graph::{DialogueGraph, EdgeKind, GraphEdge},
+    icu::{icu_to_format_functions, MessageSyntax},
+    lint::{lint, Lint},
+    locale::{LocaleContext, LocaleError, TextDirection},
+    program_set::{MergeError, ProgramSet},
+    reload::ReloadOutcome,
+    rng::YarnRng,
+    runner::{DialogueHandler, DialogueRunner},
+    saliency::{
+        BestMatchSaliencyStrategy,
+        FirstSaliencyStrategy,
+        LeastRecentlySeenSaliencyStrategy,
+        RandomSaliencyStrategy,
+        SaliencyCandidate,
+        SaliencyContext,
+        SaliencyStrategy,
+    },
+    signature::{FunctionCallError, FunctionSignature, SignatureMismatch},
+    source::{extract_string_table, scan_lines, tag_lines, SourceLine},
+    translation::{export_po, export_xliff, import_po, import_xliff, TranslationError, XliffVersion},
+    translation_check::{check_translations, TranslationIssue},
     utils::*,
-    value::YarnValue,
+    value::{EnumDeclarations, YarnType, YarnValue},
 };
 
 pub mod yarn_proto {
     include!(concat!(env!("OUT_DIR"), "/yarn.rs"));
 }
 
+mod commands;
+mod compiled;
+mod expression;
+mod graph;
+mod icu;
+mod lint;
+mod locale;
+mod program_set;
+mod reload;
+mod rng;
+mod runner;
+mod saliency;
+mod signature;
+mod source;
+mod stdlib;
+mod translation;
+mod translation_check;
 mod utils;
 mod value;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LineInfo {
     pub id: String,
     pub text: String,
@@ -34,18 +80,21 @@ pub struct LineInfo {
 /// presentation to the user.
 ///
 /// 1. Use the value in the `id` field to look up the appropriate user-facing text in the string
-/// table.
+///    table.
 ///
 /// 2. For each of the entries in the `substitutions` field, replace the corresponding placeholder
-/// with the entry. That is, the text "`{0}`" should be replaced with the value of
-/// `substitutions[0]`, "`{1}`" with `substitutions[1]`, and so on.
+///    with the entry. That is, the text "`{0}`" should be replaced with the value of
+///    `substitutions[0]`, "`{1}`" with `substitutions[1]`, and so on.
 ///
 /// 3. Use [`expand_format_functions`] to expand all [format functions](
 /// https://yarnspinner.dev/docs/syntax#format-functions) in the line.
 ///
+/// [`LocaleContext::compose`] does steps 2 and 3, and can keep substitutions from being
+/// reordered in right-to-left text.
+///
 /// You do not create instances of this struct yourself. They are created by the [`VirtualMachine`]
 /// during program execution.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Line {
     pub id: String,
     pub substitutions: Vec<String>,
@@ -60,22 +109,111 @@ impl Line {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct YarnOption {
     pub line: Line,
     pub id: u32,
     pub destination_node: String,
+    /// Whether the option's condition passed. Options that aren't available should still be
+    /// shown to the user, e.g. greyed out, but can't be selected. When none of the options are
+    /// available, they aren't shown, and the node ends instead.
+    pub is_available: bool,
 }
 
 impl YarnOption {
-    fn new(line: Line, id: u32, destination_node: String) -> Self {
+    fn new(line: Line, id: u32, destination_node: String, is_available: bool) -> Self {
         Self {
             line,
             id,
             destination_node,
+            is_available,
+        }
+    }
+}
+
+/// An error from [`VirtualMachine::set_selected_option`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelectOptionError {
+    /// The VM isn't waiting for an option to be selected.
+    NotWaitingForSelection,
+    /// There is no option with the given ID.
+    InvalidOption {
+        id: u32,
+        option_count: usize,
+    },
+    /// The option with the given ID isn't available, because its condition failed.
+    UnavailableOption(u32),
+}
+
+impl fmt::Display for SelectOptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NotWaitingForSelection => write!(f, "An option was selected, but dialogue wasn't waiting for a selection"),
+            Self::InvalidOption { id, option_count } => write!(
+                f,
+                "{} is not a valid option ID (expected a number between 0 and {})",
+                id,
+                option_count.saturating_sub(1),
+            ),
+            Self::UnavailableOption(id) => write!(f, "Option {} is not available", id),
+        }
+    }
+}
+
+impl Error for SelectOptionError {}
+
+/// The tag of nodes that calculate smart variables. The node is named after the variable, and
+/// leaves the variable's value on top of the stack.
+pub const SMART_VARIABLE_TAG: &str = "Yarn.SmartVariable";
+
+/// Whether the program calculates the variable with a smart variable node.
+pub(crate) fn is_smart_variable(program: &Program, name: &str) -> bool {
+    program.nodes.get(name)
+        .is_some_and(|node| node.tags.iter().any(|tag| tag == SMART_VARIABLE_TAG))
+}
+
+/// An error from setting a variable, or from calculating the value of a smart variable.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VariableError {
+    /// The variable is a smart variable, which is calculated when it's read, so it can't be set.
+    SmartVariable(String),
+    /// Calculating the smart variable needs its own value.
+    SmartVariableCycle(String),
+    /// The node that calculates the smart variable does something other than evaluate an
+    /// expression.
+    InvalidSmartVariable(String),
+    /// The node that calculates the smart variable calls a function that failed.
+    FunctionCall(FunctionCallError),
+    /// The variable was declared with an enum type, and the value isn't a case of that enum.
+    InvalidEnumCase {
+        variable: String,
+        enum_name: String,
+        value: YarnValue,
+    },
+}
+
+impl fmt::Display for VariableError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::SmartVariable(variable) => write!(f, "{} is a smart variable, so it can't be set", variable),
+            Self::SmartVariableCycle(variable) => write!(f, "Smart variable {} depends on its own value", variable),
+            Self::InvalidSmartVariable(variable) => {
+                write!(f, "The node for smart variable {} can only contain an expression", variable)
+            }
+            Self::FunctionCall(error) => write!(f, "{}", error),
+            Self::InvalidEnumCase { variable, enum_name, value } => write!(
+                f,
+                "{} can only be set to a case of enum {}, not {:?}",
+                variable,
+                enum_name,
+                value,
+            ),
         }
     }
 }
 
+impl Error for VariableError {}
+
 pub type ReturningFunction = dyn Fn(&[YarnValue]) -> YarnValue + Send + Sync;
 pub type Function = dyn Fn(&[YarnValue]) + Send + Sync;
 
@@ -99,43 +237,126 @@ impl YarnFunction {
     }
 }
 
-enum ParamCount {
-    N(u8),
-    Variadic,
+/// A function in a [`FunctionInfo`]. Unlike [`YarnFunction`], it can own its closure, so that
+/// functions can capture state.
+enum Callable {
+    Void(Box<Function>),
+    Returning(Box<ReturningFunction>),
+    /// A built-in operator, which returns `None` when it can't be applied to the types of its
+    /// parameters.
+    Operator(&'static OperatorFunction),
 }
 
-impl From<i8> for ParamCount {
-    fn from(val: i8) -> Self {
-        if val >= 0 {
-            Self::N(val as u8)
-        } else {
-            Self::Variadic
+type OperatorFunction = dyn Fn(&[YarnValue]) -> Option<YarnValue> + Send + Sync;
+
+impl Callable {
+    /// Calls the function, returning its value if it returns one. Fails when it's an operator
+    /// that can't be applied to the parameters.
+    fn call(&self, params: &[YarnValue]) -> Result<Option<YarnValue>, ()> {
+        match self {
+            Self::Void(func) => {
+                (func)(params);
+                Ok(None)
+            }
+            Self::Returning(func) => Ok(Some((func)(params))),
+            Self::Operator(func) => (func)(params).map(Some).ok_or(()),
+        }
+    }
+}
+
+impl From<YarnFunction> for Callable {
+    fn from(func: YarnFunction) -> Self {
+        match func {
+            YarnFunction::Void(func) => Self::Void(Box::new(func)),
+            YarnFunction::Returning(func) => Self::Returning(Box::new(func)),
         }
     }
 }
 
 pub struct FunctionInfo {
-    param_count: ParamCount,
-    func: YarnFunction,
+    signature: FunctionSignature,
+    func: Callable,
 }
 
 impl FunctionInfo {
+    /// Creates a function that doesn't return a value, and takes `param_count` parameters of any
+    /// type. If `param_count` is negative, the function takes any number of parameters.
     pub fn new(param_count: i8, func: &'static Function) -> Self {
+        Self::from_fn(param_count, func)
+    }
+
+    /// Creates a function that returns a value of any type, and takes `param_count` parameters
+    /// of any type. If `param_count` is negative, the function takes any number of parameters.
+    pub fn new_returning(param_count: i8, func: &'static ReturningFunction) -> Self {
+        Self::from_returning_fn(param_count, func)
+    }
+
+    /// Like [`new`](Self::new), but takes ownership of the closure, so that it can capture state.
+    pub fn from_fn<F>(param_count: i8, func: F) -> Self
+    where
+        F: Fn(&[YarnValue]) + Send + Sync + 'static,
+    {
         Self {
-            param_count: param_count.into(),
-            func: YarnFunction::Void(func),
+            signature: untyped_signature(param_count, None),
+            func: Callable::Void(Box::new(func)),
         }
     }
 
-    pub fn new_returning(param_count: i8, func: &'static ReturningFunction) -> Self {
+    /// Like [`new_returning`](Self::new_returning), but takes ownership of the closure, so that it
+    /// can capture state.
+    pub fn from_returning_fn<F>(param_count: i8, func: F) -> Self
+    where
+        F: Fn(&[YarnValue]) -> YarnValue + Send + Sync + 'static,
+    {
+        Self {
+            signature: untyped_signature(param_count, Some(YarnType::Any)),
+            func: Callable::Returning(Box::new(func)),
+        }
+    }
+
+    /// Creates a function with the given signature. Fails when the signature has a return type
+    /// but the function is `Void`, or the other way around.
+    pub fn with_signature(signature: FunctionSignature, func: YarnFunction) -> Result<Self, SignatureMismatch> {
+        match (&func, signature.returns) {
+            (YarnFunction::Void(_), None) | (YarnFunction::Returning(_), Some(_)) => Ok(Self {
+                signature,
+                func: func.into(),
+            }),
+            _ => Err(SignatureMismatch(signature)),
+        }
+    }
+
+    /// Creates one of the functions that every VM's library starts with.
+    fn builtin(signature: FunctionSignature, func: &'static ReturningFunction) -> Self {
+        Self {
+            signature,
+            func: Callable::Returning(Box::new(func)),
+        }
+    }
+
+    /// Creates one of the built-in operators, which fail when they can't be applied to the types
+    /// of their parameters.
+    fn operator(signature: FunctionSignature, func: &'static OperatorFunction) -> Self {
         Self {
-            param_count: param_count.into(),
-            func: YarnFunction::Returning(func),
+            signature,
+            func: Callable::Operator(func),
         }
     }
+
+    pub fn signature(&self) -> &FunctionSignature {
+        &self.signature
+    }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+fn untyped_signature(param_count: i8, returns: Option<YarnType>) -> FunctionSignature {
+    if param_count >= 0 {
+        FunctionSignature::new(&vec![YarnType::Any; param_count as usize], returns)
+    } else {
+        FunctionSignature::new_variadic(&[], YarnType::Any, returns)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ExecutionState {
     Stopped,
     WaitingOnOptionSelection,
@@ -143,23 +364,51 @@ pub enum ExecutionState {
     Running,
 }
 
+/// The reason the [`VirtualMachine`] stopped executing, returned from
+/// [`VirtualMachine::continue_dialogue`].
+///
+/// Every run of dialogue begins with a `DialogueStart` and ends with a `DialogueComplete`. Each
+/// node that is entered in between is bracketed by a `NodeStart` and a matching `NodeComplete`,
+/// including the first node selected with [`VirtualMachine::set_node`] and the node that was
+/// running when the dialogue ended.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum SuspendReason {
+    /// A line of dialogue should be shown to the user.
     Line(Line),
+    /// A set of options should be shown to the user, who must pick one with
+    /// [`VirtualMachine::set_selected_option`] before dialogue can continue.
     Options(Vec<YarnOption>),
+    /// A command should be handled by the game.
     Command(String),
-    NodeChange {
-        start: String,
-        end: String,
-    },
-    DialogueComplete(String),
+    /// A new run of dialogue has started.
+    DialogueStart,
+    /// The named node has started running.
+    NodeStart(String),
+    /// The named node has finished running.
+    NodeComplete(String),
+    /// The dialogue has finished running.
+    DialogueComplete,
 }
 
+/// Where to return to when a node that was detoured to finishes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReturnFrame {
+    pub node_name: String,
+    /// The instruction to continue from, which is the one after the detour.
+    pub program_counter: isize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VmState {
     pub current_node_name: String,
     // TODO: Switch back to usize soon.
     pub program_counter: isize,
-    pub current_options: Vec<(Line, String)>,
+    /// The options that have been added, with their destinations and whether they're available.
+    pub current_options: Vec<(Line, String, bool)>,
+    pub saliency_candidates: Vec<SaliencyCandidate>,
     pub stack: Vec<YarnValue>,
+    /// The nodes that detoured to the current node, with the innermost detour last.
+    pub return_stack: Vec<ReturnFrame>,
 }
 
 impl VmState {
@@ -168,11 +417,28 @@ impl VmState {
             current_node_name: String::new(),
             program_counter: 0,
             current_options: Vec::new(),
+            saliency_candidates: Vec::new(),
             stack: Vec::new(),
+            return_stack: Vec::new(),
         }
     }
 }
 
+/// A saved copy of everything that changes while a [`VirtualMachine`] runs dialogue, created by
+/// [`VirtualMachine::snapshot`].
+///
+/// Restoring a snapshot puts the VM back exactly where it was, including the state of its random
+/// number generator, so dialogue continues the same way it would have. The program and library
+/// aren't included, and must be set up the same way before restoring.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VmSnapshot {
+    pub state: VmState,
+    pub variable_storage: HashMap<String, YarnValue>,
+    pub execution_state: ExecutionState,
+    pub rng_state: u64,
+    pending_events: Vec<SuspendReason>,
+}
+
 pub struct VirtualMachine {
     pub state: VmState,
     pub variable_storage: HashMap<String, YarnValue>,
@@ -180,122 +446,192 @@ pub struct VirtualMachine {
 
     pub execution_state: ExecutionState,
 
-    pub program: Program,
+    /// The program being run, which can be shared with other VMs.
+    pub program: SharedProgram,
+
+    /// The VM's random number generator, which is saved in snapshots. Seed it with
+    /// [`set_seed`](Self::set_seed) to make random dialogue repeatable.
+    ///
+    /// The standard library's random functions use it, and functions added to the library can
+    /// too, by capturing a clone of it.
+    pub rng: Arc<Mutex<YarnRng>>,
+
+    /// Chooses which piece of content in a line group or node group gets run. Defaults to
+    /// [`FirstSaliencyStrategy`].
+    pub saliency_strategy: Box<dyn SaliencyStrategy>,
+
+    /// The enums and enum variables that were declared when the program was compiled. Values
+    /// stored in enum variables are checked against them.
+    pub enums: EnumDeclarations,
+
+    /// Events that have been produced, but not yet returned from `continue_dialogue`.
+    pending_events: VecDeque<SuspendReason>,
 }
 
 impl VirtualMachine {
-    pub fn new(program: Program) -> Self {
+    /// Creates a VM that runs the given program, which is loaded with [`SharedProgram::new`].
+    /// Clone the [`SharedProgram`] to share one program between many VMs, instead of giving each
+    /// one its own copy.
+    pub fn new(program: SharedProgram) -> Self {
         let mut library = HashMap::new();
         library.insert(
             "Add".to_string(),
-            FunctionInfo::new_returning(2, &|parameters: &[YarnValue]| {
-                parameters[0].add(&parameters[1]).unwrap()
-            }),
+            FunctionInfo::operator(
+                FunctionSignature::new(&[YarnType::Any, YarnType::Any], Some(YarnType::Any)),
+                &|parameters: &[YarnValue]| {
+                    parameters[0].add(&parameters[1])
+                },
+            ),
         );
 
         library.insert(
             "Minus".to_string(),
-            FunctionInfo::new_returning(2, &|parameters: &[YarnValue]| {
-                parameters[0].sub(&parameters[1]).unwrap()
-            }),
+            FunctionInfo::operator(
+                FunctionSignature::new(&[YarnType::Any, YarnType::Any], Some(YarnType::Number)),
+                &|parameters: &[YarnValue]| {
+                    parameters[0].sub(&parameters[1])
+                },
+            ),
         );
 
         library.insert(
             "UnaryMinus".to_string(),
-            FunctionInfo::new_returning(1, &|parameters: &[YarnValue]| {
-                parameters[0].neg()
-            }),
+            FunctionInfo::builtin(
+                FunctionSignature::new(&[YarnType::Any], Some(YarnType::Number)),
+                &|parameters: &[YarnValue]| {
+                    parameters[0].neg()
+                },
+            ),
         );
 
         library.insert(
             "Divide".to_string(),
-            FunctionInfo::new_returning(2, &|parameters: &[YarnValue]| {
-                parameters[0].div(&parameters[1]).unwrap()
-            }),
+            FunctionInfo::operator(
+                FunctionSignature::new(&[YarnType::Any, YarnType::Any], Some(YarnType::Number)),
+                &|parameters: &[YarnValue]| {
+                    parameters[0].div(&parameters[1])
+                },
+            ),
         );
 
         library.insert(
             "Multiply".to_string(),
-            FunctionInfo::new_returning(2, &|parameters: &[YarnValue]| {
-                parameters[0].mul(&parameters[1]).unwrap()
-            }),
+            FunctionInfo::operator(
+                FunctionSignature::new(&[YarnType::Any, YarnType::Any], Some(YarnType::Number)),
+                &|parameters: &[YarnValue]| {
+                    parameters[0].mul(&parameters[1])
+                },
+            ),
         );
 
         library.insert(
             "Modulo".to_string(),
-            FunctionInfo::new_returning(2, &|parameters: &[YarnValue]| {
-                parameters[0].rem(&parameters[1]).unwrap()
-            }),
+            FunctionInfo::operator(
+                FunctionSignature::new(&[YarnType::Any, YarnType::Any], Some(YarnType::Number)),
+                &|parameters: &[YarnValue]| {
+                    parameters[0].rem(&parameters[1])
+                },
+            ),
         );
 
         library.insert(
             "EqualTo".to_string(),
-            FunctionInfo::new_returning(2, &|parameters: &[YarnValue]| {
-                (parameters[0] == parameters[1]).into()
-            }),
+            FunctionInfo::builtin(
+                FunctionSignature::new(&[YarnType::Any, YarnType::Any], Some(YarnType::Bool)),
+                &|parameters: &[YarnValue]| {
+                    (parameters[0] == parameters[1]).into()
+                },
+            ),
         );
 
         library.insert(
             "NotEqualTo".to_string(),
-            FunctionInfo::new_returning(2, &|parameters: &[YarnValue]| {
-                (parameters[0] != parameters[1]).into()
-            }),
+            FunctionInfo::builtin(
+                FunctionSignature::new(&[YarnType::Any, YarnType::Any], Some(YarnType::Bool)),
+                &|parameters: &[YarnValue]| {
+                    (parameters[0] != parameters[1]).into()
+                },
+            ),
         );
 
         library.insert(
             "GreaterThan".to_string(),
-            FunctionInfo::new_returning(2, &|parameters: &[YarnValue]| {
-                (parameters[0] > parameters[1]).into()
-            }),
+            FunctionInfo::builtin(
+                FunctionSignature::new(&[YarnType::Any, YarnType::Any], Some(YarnType::Bool)),
+                &|parameters: &[YarnValue]| {
+                    (parameters[0] > parameters[1]).into()
+                },
+            ),
         );
 
         library.insert(
             "GreaterThanOrEqualTo".to_string(),
-            FunctionInfo::new_returning(2, &|parameters: &[YarnValue]| {
-                (parameters[0] >= parameters[1]).into()
-            }),
+            FunctionInfo::builtin(
+                FunctionSignature::new(&[YarnType::Any, YarnType::Any], Some(YarnType::Bool)),
+                &|parameters: &[YarnValue]| {
+                    (parameters[0] >= parameters[1]).into()
+                },
+            ),
         );
 
         library.insert(
             "LessThan".to_string(),
-            FunctionInfo::new_returning(2, &|parameters: &[YarnValue]| {
-                (parameters[0] < parameters[1]).into()
-            }),
+            FunctionInfo::builtin(
+                FunctionSignature::new(&[YarnType::Any, YarnType::Any], Some(YarnType::Bool)),
+                &|parameters: &[YarnValue]| {
+                    (parameters[0] < parameters[1]).into()
+                },
+            ),
         );
 
         library.insert(
             "LessThanOrEqualTo".to_string(),
-            FunctionInfo::new_returning(2, &|parameters: &[YarnValue]| {
-                (parameters[0] <= parameters[1]).into()
-            }),
+            FunctionInfo::builtin(
+                FunctionSignature::new(&[YarnType::Any, YarnType::Any], Some(YarnType::Bool)),
+                &|parameters: &[YarnValue]| {
+                    (parameters[0] <= parameters[1]).into()
+                },
+            ),
         );
 
         library.insert(
             "And".to_string(),
-            FunctionInfo::new_returning(2, &|parameters: &[YarnValue]| {
-                (parameters[0].as_bool() && parameters[1].as_bool()).into()
-            }),
+            FunctionInfo::builtin(
+                FunctionSignature::new(&[YarnType::Any, YarnType::Any], Some(YarnType::Bool)),
+                &|parameters: &[YarnValue]| {
+                    (parameters[0].as_bool() && parameters[1].as_bool()).into()
+                },
+            ),
         );
 
         library.insert(
             "Or".to_string(),
-            FunctionInfo::new_returning(2, &|parameters: &[YarnValue]| {
-                (parameters[0].as_bool() || parameters[1].as_bool()).into()
-            }),
+            FunctionInfo::builtin(
+                FunctionSignature::new(&[YarnType::Any, YarnType::Any], Some(YarnType::Bool)),
+                &|parameters: &[YarnValue]| {
+                    (parameters[0].as_bool() || parameters[1].as_bool()).into()
+                },
+            ),
         );
 
         library.insert(
             "Xor".to_string(),
-            FunctionInfo::new_returning(2, &|parameters: &[YarnValue]| {
-                (parameters[0].as_bool() ^ parameters[1].as_bool()).into()
-            }),
+            FunctionInfo::builtin(
+                FunctionSignature::new(&[YarnType::Any, YarnType::Any], Some(YarnType::Bool)),
+                &|parameters: &[YarnValue]| {
+                    (parameters[0].as_bool() ^ parameters[1].as_bool()).into()
+                },
+            ),
         );
 
         library.insert(
             "Not".to_string(),
-            FunctionInfo::new_returning(1, &|parameters: &[YarnValue]| {
-                (!parameters[0].as_bool()).into()
-            }),
+            FunctionInfo::builtin(
+                FunctionSignature::new(&[YarnType::Any], Some(YarnType::Bool)),
+                &|parameters: &[YarnValue]| {
+                    (!parameters[0].as_bool()).into()
+                },
+            ),
         );
 
         Self {
@@ -304,9 +640,147 @@ impl VirtualMachine {
             library,
             execution_state: ExecutionState::Stopped,
             program,
+            rng: Arc::new(Mutex::new(YarnRng::default())),
+            saliency_strategy: Box::new(FirstSaliencyStrategy),
+            enums: EnumDeclarations::default(),
+            pending_events: VecDeque::new(),
+        }
+    }
+
+    /// Adds the standard library of functions to the VM's library: `random`, `random_range`,
+    /// `dice`, `round`, `floor`, `ceil`, `inc`, `dec`, `decimal`, `int`, `string`, `number` and
+    /// `format_invariant`.
+    ///
+    /// The random functions use the VM's [`rng`](Self::rng).
+    pub fn add_standard_library(&mut self) {
+        stdlib::register(&mut self.library, &self.rng);
+    }
+
+    /// Reseeds the VM's random number generator.
+    pub fn set_seed(&mut self, seed: u64) {
+        *self.rng.lock().unwrap() = YarnRng::new(seed);
+    }
+
+    /// Saves the VM's current state, variables and random number generator.
+    pub fn snapshot(&self) -> VmSnapshot {
+        VmSnapshot {
+            state: self.state.clone(),
+            variable_storage: self.variable_storage.clone(),
+            execution_state: self.execution_state,
+            rng_state: self.rng.lock().unwrap().state(),
+            pending_events: self.pending_events.iter().cloned().collect(),
+        }
+    }
+
+    /// Restores a snapshot created by [`snapshot`](Self::snapshot).
+    pub fn restore(&mut self, snapshot: VmSnapshot) {
+        self.state = snapshot.state;
+        self.variable_storage = snapshot.variable_storage;
+        self.execution_state = snapshot.execution_state;
+        *self.rng.lock().unwrap() = YarnRng::new(snapshot.rng_state);
+        self.pending_events = snapshot.pending_events.into();
+    }
+
+    /// Replaces the program the VM runs, keeping its variables, e.g. after adding a program to or
+    /// removing one from a [`ProgramSet`].
+    ///
+    /// The dialogue that is running carries on, unless the new program doesn't have the current
+    /// node or a node that was detoured from, in which case the dialogue is stopped.
+    pub fn set_program(&mut self, program: SharedProgram) {
+        self.program = program;
+
+        let compiled = self.program.compiled();
+        let missing_node = std::iter::once(&self.state.current_node_name)
+            .chain(self.state.return_stack.iter().map(|frame| &frame.node_name))
+            .find(|node_name| !node_name.is_empty() && compiled.node_index(node_name).is_none());
+        if let Some(missing_node) = missing_node {
+            warn!("Node {} is no longer loaded, stopping the dialogue", missing_node);
+            self.stop();
+        }
+    }
+
+    /// Reloads the program while dialogue is running, e.g. after its `.yarnc` file was
+    /// recompiled, keeping the VM's variables.
+    ///
+    /// The dialogue carries on from the equivalent position in the new program. When that can't
+    /// be found, the node that was running starts again from the beginning, forgetting any
+    /// options that were shown. It completes and starts again, and if the nodes it would have
+    /// returned to from a detour can't be found either, they complete too. When the node is
+    /// gone, the dialogue is stopped.
+    pub fn reload(&mut self, program: SharedProgram) -> ReloadOutcome {
+        let old_program = std::mem::replace(&mut self.program, program);
+        if self.state.current_node_name.is_empty() {
+            return ReloadOutcome::NotRunning;
+        }
+
+        let node_name = self.state.current_node_name.clone();
+        let (old_node, new_node) = match (old_program.nodes.get(&node_name), self.program.nodes.get(&node_name)) {
+            (Some(old_node), Some(new_node)) => (old_node, new_node),
+            _ => {
+                warn!("Node {} is no longer loaded, stopping the dialogue", node_name);
+                self.stop();
+                return ReloadOutcome::Stopped;
+            }
+        };
+
+        // Options and the selected option refer to labels, which must still be in the node.
+        let has_label = |label: &String| new_node.labels.contains_key(label);
+        let labels_exist = self.state.current_options.iter().all(|(_, destination, _)| has_label(destination))
+            && self.state.stack.iter().all(|value| match value {
+                YarnValue::Str(s) if old_node.labels.contains_key(s) => has_label(s),
+                _ => true,
+            });
+        let program_counter = map_position(old_node, new_node, self.state.program_counter.max(0) as usize)
+            .filter(|_| labels_exist);
+
+        // The nodes that were detoured from only need to be found again once they're returned to,
+        // but check them now so that the dialogue doesn't stop unexpectedly later on.
+        let frame_positions: Option<Vec<usize>> = self.state.return_stack.iter()
+            .map(|frame| {
+                let old_node = old_program.nodes.get(&frame.node_name)?;
+                let new_node = self.program.nodes.get(&frame.node_name)?;
+                map_position(old_node, new_node, frame.program_counter.max(0) as usize)
+            })
+            .collect();
+
+        match (program_counter, frame_positions) {
+            (Some(program_counter), Some(frame_positions)) => {
+                self.state.program_counter = program_counter as isize;
+                for (frame, program_counter) in self.state.return_stack.iter_mut().zip(frame_positions) {
+                    frame.program_counter = program_counter as isize;
+                }
+                ReloadOutcome::Continued
+            }
+            (_, frame_positions) => {
+                warn!("Could not find where node {} was in the reloaded program, restarting it", node_name);
+                self.state.program_counter = 0;
+                self.state.stack.clear();
+                self.state.current_options.clear();
+                self.state.saliency_candidates.clear();
+
+                // If the node's start hasn't been delivered yet, it will be delivered when the
+                // node starts again.
+                let node_started = !self.pending_events.iter()
+                    .any(|event| matches!(event, SuspendReason::NodeStart(_)));
+                if node_started {
+                    self.pending_events.push_back(SuspendReason::NodeComplete(node_name.clone()));
+                }
+                if frame_positions.is_none() {
+                    self.unwind_return_stack();
+                }
+                if node_started {
+                    self.pending_events.push_back(SuspendReason::NodeStart(node_name.clone()));
+                }
+                if self.execution_state == ExecutionState::WaitingOnOptionSelection {
+                    self.execution_state = ExecutionState::Suspended;
+                }
+                ReloadOutcome::RestartedNode(node_name)
+            }
         }
     }
 
+    /// Starts running the given node. When dialogue is already running, the node that was
+    /// running is completed first, and the dialogue carries on in the new node.
     pub fn set_node(&mut self, node_name: &str) -> bool {
         // TODO: Handle error cases.
         // if (Program == null || Program.Nodes.Count == 0) {
@@ -320,6 +794,27 @@ impl VirtualMachine {
 
         // dialogue.LogDebugMessage ("Running node " + nodeName);
 
+        if self.execution_state == ExecutionState::Stopped {
+            self.pending_events.clear();
+            self.pending_events.push_back(SuspendReason::DialogueStart);
+        } else {
+            // The dialogue carries on in the new node, so it doesn't complete even if it was
+            // stopped. A node whose start hasn't been delivered yet is forgotten, while a node
+            // that already started completes. The nodes waiting for it to return from a detour
+            // have always started, so they complete either way.
+            let node_started = !self.pending_events.iter()
+                .any(|event| matches!(event, SuspendReason::NodeStart(_)));
+            self.pending_events.retain(|event| {
+                !matches!(event, SuspendReason::NodeStart(_) | SuspendReason::DialogueComplete)
+            });
+            if node_started && !self.state.current_node_name.is_empty() {
+                let last_node = std::mem::take(&mut self.state.current_node_name);
+                self.pending_events.push_back(SuspendReason::NodeComplete(last_node));
+            }
+            self.unwind_return_stack();
+        }
+        self.pending_events.push_back(SuspendReason::NodeStart(node_name.to_string()));
+
         self.state = VmState::new();
         self.state.current_node_name = node_name.to_string();
 
@@ -329,8 +824,6 @@ impl VirtualMachine {
         true
     }
 
-    // TODO: Return the reason why we stopped execution.
-    // Either Line, Options, Command, NodeStart?, NodeEnd?, DialogeEnd
     pub fn continue_dialogue(&mut self) -> SuspendReason {
         // TODO: Handle error cases.
         // if (currentNode == null)
@@ -342,32 +835,43 @@ impl VirtualMachine {
             panic!("Cannot continue running dialogue. Still waiting on option selection.");
         }
 
-        self.execution_state = ExecutionState::Running;
-
-        // Execute instructions until something forces us to stop
-        loop {
-            let instruction_count = if !self.state.current_node_name.is_empty() {
-                self.program.nodes[&self.state.current_node_name].instructions.len()
-            } else {
-                // No node is running, so return 0.
-                0
-            };
-
-            // If we've reached the end of a node, stop execution.
-            if self.state.program_counter as usize >= instruction_count {
-                let last_node = self.state.current_node_name.clone();
+        // Deliver any events that were queued up before running more instructions.
+        if let Some(event) = self.pending_events.pop_front() {
+            if let SuspendReason::DialogueComplete = event {
                 self.execution_state = ExecutionState::Stopped;
                 self.state = VmState::new();
                 // dialogue.LogDebugMessage ("Run complete.");
-                return SuspendReason::DialogueComplete(last_node);
             }
+            return event;
+        }
+
+        // No node is running, so there's nothing left to do.
+        if self.state.current_node_name.is_empty() {
+            self.execution_state = ExecutionState::Stopped;
+            self.state = VmState::new();
+            return SuspendReason::DialogueComplete;
+        }
 
-            let current_instruction = {
-                let current_node = &self.program.nodes[&self.state.current_node_name];
-                current_node.instructions[self.state.program_counter as usize].clone()
+        self.execution_state = ExecutionState::Running;
+
+        // Every instruction that changes the current node also suspends execution, so the node
+        // only needs to be looked up once.
+        let node_index = match self.program.compiled().node_index(&self.state.current_node_name) {
+            Some(node_index) => node_index,
+            None => panic!("No node named {} has been loaded", self.state.current_node_name),
+        };
+
+        // Execute instructions until something forces us to stop
+        loop {
+            let instructions = &self.program.compiled().nodes[node_index].instructions;
+            let current_instruction = match instructions.get(self.state.program_counter as usize) {
+                Some(&instruction) => instruction,
+                // If we've reached the end of a node, return from it, which stops execution if
+                // it wasn't detoured to.
+                None => return self.return_from_node(),
             };
 
-            let suspend = self.run_instruction(current_instruction);
+            let suspend = self.run_instruction(node_index, current_instruction);
 
             self.state.program_counter += 1;
 
@@ -377,23 +881,28 @@ impl VirtualMachine {
         }
     }
 
-    pub fn set_selected_option(&mut self, selected_option_id: u32) {
-        let selected_option_id = selected_option_id as usize;
-
+    /// Selects the option with the given ID, after `continue_dialogue` returned
+    /// [`SuspendReason::Options`]. Options that aren't available can't be selected.
+    pub fn set_selected_option(&mut self, selected_option_id: u32) -> Result<(), SelectOptionError> {
         if self.execution_state != ExecutionState::WaitingOnOptionSelection {
-            panic!();
-            // throw new DialogueException(@"SetSelectedOption was called, but Dialogue wasn't waiting for a selection.
-            // This method should only be called after the Dialogue is waiting for the user to select an option.");
+            return Err(SelectOptionError::NotWaitingForSelection);
         }
 
-        if selected_option_id >= self.state.current_options.len() {
-            panic!();
-            // throw new ArgumentOutOfRangeException($"{selectedOptionID} is not a valid option ID (expected a number between 0 and {state.currentOptions.Count-1}.");
+        let option = match self.state.current_options.get(selected_option_id as usize) {
+            Some(option) => option,
+            None => return Err(SelectOptionError::InvalidOption {
+                id: selected_option_id,
+                option_count: self.state.current_options.len(),
+            }),
+        };
+        let (_, destination_node, is_available) = option;
+        if !is_available {
+            return Err(SelectOptionError::UnavailableOption(selected_option_id));
         }
 
         // We now know what number option was selected; push the
         // corresponding node name to the stack
-        let destination_node = self.state.current_options[selected_option_id].1.clone();
+        let destination_node = destination_node.clone();
         self.state.stack.push(YarnValue::Str(destination_node));
 
         // We no longer need the accumulated list of options; clear it
@@ -405,137 +914,397 @@ impl VirtualMachine {
         self.execution_state = ExecutionState::Suspended;
 
         debug!("Selected option: {}", selected_option_id);
+        Ok(())
+    }
+
+    /// Stops the dialogue that is currently running.
+    ///
+    /// The next calls to `continue_dialogue` deliver the `NodeComplete` event for the current node,
+    /// followed by `DialogueComplete`.
+    pub fn stop(&mut self) {
+        let already_completing = self.pending_events.iter()
+            .any(|event| matches!(event, SuspendReason::DialogueComplete));
+        if self.execution_state == ExecutionState::Stopped || already_completing {
+            return;
+        }
+
+        let last_node = std::mem::take(&mut self.state.current_node_name);
+        self.state.current_options.clear();
+        self.execution_state = ExecutionState::Suspended;
+        self.pending_events.push_back(SuspendReason::NodeComplete(last_node));
+        self.unwind_return_stack();
+        self.pending_events.push_back(SuspendReason::DialogueComplete);
+    }
+
+    /// Checks every function call in the program against the signatures of the functions in
+    /// the library, so that mistakes can be found before the dialogue is run.
+    pub fn check_function_calls(&self) -> Result<(), Vec<FunctionCallError>> {
+        let errors = signature::check_function_calls(&self.program, &self.library);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Returns the name and signature of every function in the library, sorted by name.
+    pub fn function_signatures(&self) -> Vec<(&str, &FunctionSignature)> {
+        let mut signatures: Vec<_> = self.library.iter()
+            .map(|(name, function)| (name.as_str(), &function.signature))
+            .collect();
+        signatures.sort_by_key(|&(name, _)| name);
+        signatures
+    }
+
+    /// Returns the value of a variable, or `None` if it hasn't been set. Smart variables are
+    /// calculated by running their node, and enum variables are returned as
+    /// [`YarnValue::Enum`].
+    pub fn get_variable(&mut self, name: &str) -> Result<Option<YarnValue>, VariableError> {
+        let value = match self.program.compiled().node_index(name) {
+            Some(node_index) if is_smart_variable(&self.program, name) => {
+                Some(self.evaluate_smart_variable(node_index, &mut Vec::new())?)
+            }
+            _ => self.variable_storage.get(name).cloned(),
+        };
+        Ok(value.map(|value| self.enums.to_typed(name, value)))
+    }
+
+    /// Sets a variable, checking that it can be set to the value. Variables declared with an
+    /// enum type can only be set to cases of that enum, and smart variables can't be set at all.
+    pub fn set_variable(&mut self, name: &str, value: YarnValue) -> Result<(), VariableError> {
+        self.check_variable(name, &value)?;
+
+        // Enum cases are stored by their raw value, like compiled programs store them.
+        let value = match value {
+            YarnValue::Enum { case, .. } => YarnValue::Str(case),
+            value => value,
+        };
+        self.variable_storage.insert(name.to_string(), value);
+        Ok(())
     }
 
-    fn run_instruction(&mut self, instruction: yarn_proto::Instruction) -> Option<SuspendReason> {
+    fn check_variable(&self, name: &str, value: &YarnValue) -> Result<(), VariableError> {
+        if is_smart_variable(&self.program, name) {
+            return Err(VariableError::SmartVariable(name.to_string()));
+        }
+        if !self.enums.can_store(name, value) {
+            return Err(VariableError::InvalidEnumCase {
+                variable: name.to_string(),
+                enum_name: self.enums.variables[name].clone(),
+                value: value.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Evaluates an expression written in Yarn's expression syntax, such as
+    /// `$gold > 10 and visited("Shop")`, using the VM's current variables and library.
+    ///
+    /// Operators are evaluated with the same library functions that compiled programs use, so
+    /// the result matches what the expression would produce while running dialogue. Nothing is
+    /// stored, but functions with side effects will still run. Smart variables are calculated
+    /// the same way [`get_variable`](Self::get_variable) calculates them.
+    ///
+    /// Enum cases are written as `Enum.Case`, and enum variables are read as
+    /// [`YarnValue::Enum`], so they can be compared with each other.
+    pub fn evaluate_expression(&mut self, expression: &str) -> Result<YarnValue, ExpressionError> {
+        let expression = expression::parse(expression)?;
+
+        // Smart variables are calculated up front, since calculating them needs the VM.
+        let mut smart_variables = HashMap::new();
+        for name in expression.variables() {
+            if let Some(node_index) = self.program.compiled().node_index(name) {
+                if is_smart_variable(&self.program, name) {
+                    let value = self.evaluate_smart_variable(node_index, &mut Vec::new())?;
+                    smart_variables.insert(name, value);
+                }
+            }
+        }
+
+        let variable = |name: &str| smart_variables.get(name).or_else(|| self.variable_storage.get(name)).cloned();
+        expression::evaluate(&expression, &variable, &self.library, &self.enums)
+    }
+
+    /// The name of the variable that remembers whether `once` content has been seen. Programs
+    /// read it to decide whether to show the content, and set it to `true` once it has been
+    /// shown.
+    ///
+    /// The name is generated from the ID of the content's line, or the first line in a
+    /// `<<once>>` block, so it stays the same when the script around it changes. The variables
+    /// are kept in `variable_storage`, so they're saved along with the rest of the dialogue's
+    /// state.
+    pub fn once_variable_name(line_id: &str) -> String {
+        format!("$Yarn.Internal.Once.{}", line_id)
+    }
+
+    /// Returns the IDs of up to `count` lines that may be delivered soon, so that the game can
+    /// prepare for them ahead of time (e.g. by preloading voice-over clips).
+    ///
+    /// This follows the rest of the current node, including the labels that options and jumps
+    /// lead to, followed by the nodes that it can lead to by running another node or by detouring
+    /// to one, and the nodes it returns to. Conditions are not evaluated, so both sides of a
+    /// branch are followed, and some of the returned lines may never be delivered. Lines that
+    /// appear in options are included.
+    pub fn lookahead(&self, count: usize) -> Vec<String> {
         use yarn_proto::{
             instruction::OpCode,
             operand::Value,
         };
 
-        let opcode = OpCode::from_i32(instruction.opcode)
-            .unwrap();
-
-        debug!("Running {:?} {:?}", opcode, instruction.operands);
+        let mut line_ids: Vec<String> = Vec::new();
+        if count == 0 || self.state.current_node_name.is_empty() {
+            return line_ids;
+        }
 
-        match opcode {
-            OpCode::JumpTo => {
-                if let Some(Value::StringValue(label)) = &instruction.operands[0].value {
-                    self.state.program_counter = self.find_instruction_point_for_label(label) - 1;
-                } else {
-                    // TODO: Error.
-                }
+        // Positions to scan from, as a node and the instruction to start scanning from.
+        let mut to_scan = VecDeque::new();
+        let mut visited: Vec<(&str, usize)> = Vec::new();
+        let mut scan_from = |to_scan: &mut VecDeque<_>, position| {
+            if !visited.contains(&position) {
+                visited.push(position);
+                to_scan.push_back(position);
             }
-            OpCode::Jump => {
-                if let Some(YarnValue::Str(label)) = self.state.stack.last() {
-                    self.state.program_counter = self.find_instruction_point_for_label(label) - 1;
-                } else {
-                    // TODO: Error.
+        };
+        let current_node = self.state.current_node_name.as_str();
+        let current_position = (current_node, self.state.program_counter.max(0) as usize);
+        scan_from(&mut to_scan, current_position);
+
+        // If we're waiting on an option, any of the labels they jump to could be next.
+        if let Some(node) = self.program.nodes.get(current_node) {
+            for (_, label, _) in &self.state.current_options {
+                if let Some(&position) = node.labels.get(label) {
+                    scan_from(&mut to_scan, (current_node, position as usize));
                 }
             }
-            OpCode::RunLine => {
-                // Looks up a string from the string table and passes it to the client as a line.
-                if let Some(Value::StringValue(string_key)) = &instruction.operands[0].value {
-                    let mut substitutions = Vec::new();
-
-                    // The second operand, if provided (compilers prior
-                    // to v1.1 don't include it), indicates the number
-                    // of expressions in the command. We need to pop
-                    // these values off the stack and deliver them to
-                    // the line handler.
-                    if let Some(Value::FloatValue(expression_count)) = instruction.operands.get(1).and_then(|o| o.value.as_ref()) {
-                        let expression_count = *expression_count as u32;
-                        substitutions.resize(expression_count as usize, String::new());
-
-                        for expression_index in (0..expression_count as usize).rev() {
-                            let substitution = self.state.stack.pop().unwrap().as_string();
-                            // TODO: Avoid bounds check due to indexing.
-                            substitutions[expression_index] = substitution;
+        }
+
+        // When the current node was detoured to, the nodes that are waiting for it continue after
+        // it finishes.
+        for frame in self.state.return_stack.iter().rev() {
+            scan_from(&mut to_scan, (frame.node_name.as_str(), frame.program_counter.max(0) as usize));
+        }
+
+        while let Some((node_name, start)) = to_scan.pop_front() {
+            let node = match self.program.nodes.get(node_name) {
+                Some(node) => node,
+                None => continue,
+            };
+
+            let mut last_pushed_string = None;
+            for (position, instruction) in node.instructions.iter().enumerate().skip(start) {
+                let first_operand = instruction.operands.first().and_then(|o| o.value.as_ref());
+                let label_position = |label: Option<&Value>| match label {
+                    Some(Value::StringValue(label)) => node.labels.get(label).map(|&position| position as usize),
+                    _ => None,
+                };
+                let mut destination_node = None;
+                let mut ends_here = false;
+
+                match OpCode::from_i32(instruction.opcode) {
+                    Some(OpCode::RunLine) => {
+                        if let Some(Value::StringValue(line_id)) = first_operand {
+                            if !line_ids.contains(line_id) {
+                                line_ids.push(line_id.clone());
+                            }
+                        }
+                    }
+                    Some(OpCode::AddOption) => {
+                        if let Some(Value::StringValue(line_id)) = first_operand {
+                            if !line_ids.contains(line_id) {
+                                line_ids.push(line_id.clone());
+                            }
+                        }
+                        // The option's destination is a label in this node.
+                        if let Some(position) = label_position(instruction.operands.get(1).and_then(|o| o.value.as_ref())) {
+                            scan_from(&mut to_scan, (node_name, position));
+                        }
+                    }
+                    Some(OpCode::JumpIfFalse) => {
+                        if let Some(position) = label_position(first_operand) {
+                            scan_from(&mut to_scan, (node_name, position));
+                        }
+                    }
+                    Some(OpCode::JumpTo) => {
+                        if let Some(position) = label_position(first_operand) {
+                            scan_from(&mut to_scan, (node_name, position));
+                        }
+                        ends_here = true;
+                    }
+                    Some(OpCode::DetourToNode) => {
+                        if let Some(Value::StringValue(node_name)) = first_operand {
+                            destination_node = Some(node_name.as_str());
                         }
                     }
+                    Some(OpCode::PeekAndDetourToNode) => {
+                        destination_node = last_pushed_string;
+                    }
+                    Some(OpCode::RunNode) => {
+                        destination_node = last_pushed_string;
+                        ends_here = true;
+                    }
+                    // Where an option jumps to was found when it was added, unless the option
+                    // has just been selected and its label is waiting on the stack.
+                    Some(OpCode::Jump) => {
+                        if (node_name, position) == current_position {
+                            if let Some(YarnValue::Str(label)) = self.state.stack.last() {
+                                if let Some(&position) = node.labels.get(label) {
+                                    scan_from(&mut to_scan, (node_name, position as usize));
+                                }
+                            }
+                        }
+                        ends_here = true;
+                    }
+                    Some(OpCode::Stop) | Some(OpCode::Return) => {
+                        ends_here = true;
+                    }
+                    _ => {}
+                }
 
-                    self.execution_state = ExecutionState::Suspended;
-                    let line = Line::new(string_key.clone(), substitutions);
-                    return Some(SuspendReason::Line(line));
-                } else {
-                    // TODO: Handle this error!
+                if line_ids.len() >= count {
+                    line_ids.truncate(count);
+                    return line_ids;
+                }
+
+                if let Some(destination_node) = destination_node {
+                    scan_from(&mut to_scan, (destination_node, 0));
+                }
+                if ends_here {
+                    break;
                 }
+
+                last_pushed_string = match (OpCode::from_i32(instruction.opcode), first_operand) {
+                    (Some(OpCode::PushString), Some(Value::StringValue(val))) => Some(val.as_str()),
+                    _ => None,
+                };
             }
-            OpCode::RunCommand => {
-                // Passes a string to the client as a custom command
-                if let Some(Value::StringValue(command)) = &instruction.operands[0].value {
-                    let mut command_text = command.clone();
-
-                    // The second operand, if provided (compilers prior
-                    // to v1.1 don't include it), indicates the number
-                    // of expressions in the command. We need to pop
-                    // these values off the stack and deliver them to
-                    // the line handler.
-                    if let Some(Value::FloatValue(expression_count)) = instruction.operands.get(1).and_then(|o| o.value.as_ref()) {
-                        let expression_count = *expression_count as u32;
-
-                        // Get the values from the stack, and
-                        // substitute them into the command text
-                        for expression_index in (0..expression_count).rev() {
-                            let substitution = self.state.stack.pop().unwrap().as_string();
-
-                            // TODO: Try using String::replace_range.
-                            command_text = command_text.replacen(&format!("{{{}}}", expression_index), &substitution, 1);
-                        }
-                    }
+        }
 
-                    self.execution_state = ExecutionState::Suspended;
-                    return Some(SuspendReason::Command(command_text));
+        line_ids
+    }
+
+    /// Calls a function in the library, popping its parameters from the stack and pushing its
+    /// return value, if it returns one.
+    fn call_function(&mut self, func_name: compiled::StringId) -> Result<(), FunctionCallError> {
+        let func_name = self.program.compiled().string(func_name);
+        let function = self.library.get(func_name).ok_or_else(|| FunctionCallError::UnknownFunction {
+            node: self.state.current_node_name.clone(),
+            function: func_name.to_string(),
+        })?;
+
+        let actual_param_count = self.state.stack.pop().unwrap().as_number() as usize;
+        if !function.signature.accepts_count(actual_param_count) {
+            return Err(FunctionCallError::WrongArgumentCount {
+                node: self.state.current_node_name.clone(),
+                function: func_name.to_string(),
+                signature: function.signature.clone(),
+                received: actual_param_count,
+            });
+        }
+
+        // The parameters were pushed in order, so they're the last values on the stack.
+        let first_param = self.state.stack.len().saturating_sub(actual_param_count);
+        let parameters = &self.state.stack[first_param..];
+        if let Some((index, expected, received)) = function.signature.mismatched_argument(parameters) {
+            return Err(FunctionCallError::WrongArgumentType {
+                node: self.state.current_node_name.clone(),
+                function: func_name.to_string(),
+                index,
+                expected,
+                received,
+            });
+        }
+
+        let result = function.func.call(parameters).map_err(|()| FunctionCallError::InvalidOperands {
+            node: self.state.current_node_name.clone(),
+            function: func_name.to_string(),
+            received: parameters.iter().map(YarnValue::yarn_type).collect(),
+        })?;
+        self.state.stack.truncate(first_param);
+        if let Some(result) = result {
+            self.state.stack.push(result);
+        }
+        Ok(())
+    }
+
+    /// Runs one instruction of the node with the given index in the compiled program.
+    fn run_instruction(&mut self, node_index: usize, instruction: compiled::Instruction) -> Option<SuspendReason> {
+        use compiled::Instruction;
+
+        debug!("Running {:?}", instruction);
+
+        // The program counter is incremented when this function returns.
+        let jump_to = |vm: &mut Self, target: usize| vm.state.program_counter = target as isize - 1;
+
+        match instruction {
+            Instruction::JumpTo(target) => {
+                jump_to(self, target);
+            }
+            Instruction::Jump => {
+                if let Some(YarnValue::Str(label)) = self.state.stack.last() {
+                    match self.program.compiled().nodes[node_index].labels.get(label) {
+                        Some(&i) => self.state.program_counter = i as isize - 1,
+                        None => panic!("Unknown label {} in node {}", label, self.state.current_node_name),
+                    }
                 } else {
                     // TODO: Error.
                 }
             }
-            OpCode::AddOption => {
-                let line = if let Some(Value::StringValue(opt)) = instruction.operands.get(0).and_then(|o| o.value.as_ref()) {
-                    let mut substitutions = Vec::new();
-
-                    // get the number of expressions that we're
-                    // working with out of the third operand
-                    if let Some(Value::FloatValue(expression_count)) = instruction.operands.get(2).and_then(|o| o.value.as_ref()) {
-                        let expression_count = *expression_count as u32;
-                        substitutions.resize(expression_count as usize, String::new());
-
-                        // pop the expression values off the stack in
-                        // reverse order, and store the list of substitutions
-                        for expression_index in (0..expression_count as usize).rev() {
-                            let substitution = self.state.stack.pop().unwrap().as_string();
-                            // TODO: Avoid bounds check due to indexing.
-                            substitutions[expression_index] = substitution;
-                        }
-                    }
-                    Line::new(opt.clone(), substitutions)
-                } else {
-                    // TODO: Handle error.
-                    panic!();
-                };
-                let node_name = if let Some(Value::StringValue(opt)) = instruction.operands.get(1).and_then(|o| o.value.as_ref()) {
-                    opt.clone()
-                } else {
-                    // TODO: Handle error.
-                    panic!();
-                };
+            Instruction::RunLine { line_id, expression_count } => {
+                // Looks up a string from the string table and passes it to the client as a line.
+                // The number of expressions in the line are popped off the stack and delivered
+                // to the line handler. Compilers prior to v1.1 don't include the count, so it
+                // defaults to 0.
+                let substitutions = self.pop_substitutions(expression_count);
+
+                self.execution_state = ExecutionState::Suspended;
+                let line = Line::new(self.program.compiled().string(line_id).to_string(), substitutions);
+                return Some(SuspendReason::Line(line));
+            }
+            Instruction::RunCommand { text, expression_count } => {
+                // Passes a string to the client as a custom command, with the values from the
+                // stack substituted into the command text, starting from the last one.
+                let mut command_text = self.program.compiled().string(text).to_string();
+                for (expression_index, substitution) in self.pop_substitutions(expression_count).iter().enumerate().rev() {
+                    // TODO: Try using String::replace_range.
+                    command_text = command_text.replacen(&format!("{{{}}}", expression_index), substitution, 1);
+                }
+
+                self.execution_state = ExecutionState::Suspended;
+                return Some(SuspendReason::Command(command_text));
+            }
+            Instruction::AddOption { line_id, destination, expression_count, has_condition } => {
+                // The substitutions are on top of the stack. If the option has a condition, it was
+                // pushed before them, so it's popped last.
+                let substitutions = self.pop_substitutions(expression_count);
+                let is_available = !has_condition || self.state.stack.pop().is_some_and(|val| val.as_bool());
+
+                let line = Line::new(self.program.compiled().string(line_id).to_string(), substitutions);
+                let destination = self.program.compiled().string(destination).to_string();
 
-                self.state.current_options.push((line, node_name));
+                self.state.current_options.push((line, destination, is_available));
             }
-            OpCode::ShowOptions => {
+            Instruction::ShowOptions => {
                 // If we have no options to show, immediately stop.
                 if self.state.current_options.is_empty() {
-                    self.execution_state = ExecutionState::Stopped;
-                    let last_node = self.state.current_node_name.clone();
-                    self.state = VmState::new();
-                    return Some(SuspendReason::DialogueComplete(last_node));
+                    return Some(self.complete_dialogue());
+                }
+
+                // If none of the options can be selected, the dialogue can't continue from here,
+                // so the node ends instead.
+                if !self.state.current_options.iter().any(|(_, _, is_available)| *is_available) {
+                    warn!("None of the options in node {} are available, ending the node", self.state.current_node_name);
+                    self.state.current_options.clear();
+                    let suspend = self.return_from_node();
+                    // The program counter is incremented when this function returns.
+                    self.state.program_counter -= 1;
+                    return Some(suspend);
                 }
 
                 // Present the list of options to the user and let them pick
                 let mut options = Vec::new();
 
                 for (i, opt) in self.state.current_options.iter().enumerate() {
-                    options.push(YarnOption::new(opt.0.clone(), i as u32, opt.1.clone()));
+                    options.push(YarnOption::new(opt.0.clone(), i as u32, opt.1.clone(), opt.2));
                 }
 
                 // We can't continue until our client tell us which option to pick.
@@ -543,129 +1312,87 @@ impl VirtualMachine {
 
                 return Some(SuspendReason::Options(options));
             }
-            OpCode::PushString => {
-                if let Some(Value::StringValue(val)) = &instruction.operands[0].value {
-                    self.state.stack.push(YarnValue::Str(val.clone()));
-                } else {
-                    // TODO: Error: bad operand.
-                }
+            Instruction::PushString(val) => {
+                self.state.stack.push(YarnValue::Str(self.program.compiled().string(val).to_string()));
             }
-            OpCode::PushFloat => {
-                if let Some(Value::FloatValue(val)) = &instruction.operands[0].value {
-                    self.state.stack.push(YarnValue::Number(*val));
-                } else {
-                    // TODO: Error: bad operand.
-                }
+            Instruction::PushFloat(val) => {
+                self.state.stack.push(YarnValue::Number(val));
             }
-            OpCode::PushBool => {
-                if let Some(Value::BoolValue(val)) = &instruction.operands[0].value {
-                    self.state.stack.push(YarnValue::Bool(*val));
-                } else {
-                    // TODO: Error: bad operand.
-                }
+            Instruction::PushBool(val) => {
+                self.state.stack.push(YarnValue::Bool(val));
             }
-            OpCode::PushNull => {
+            Instruction::PushNull => {
                 self.state.stack.push(YarnValue::Null);
             },
-            OpCode::JumpIfFalse => {
+            Instruction::JumpIfFalse(target) => {
                 // Jump to a named label if the value on the top of the stack
                 // evaluates to the boolean value 'false'.
                 if let Some(val) = self.state.stack.last() {
                     if !val.as_bool() {
-                        if let Some(Value::StringValue(label)) = &instruction.operands[0].value {
-                            self.state.program_counter = self.find_instruction_point_for_label(label) - 1;
-                        } else {
-                            // TODO: Error.
-                        }
+                        jump_to(self, target);
                     }
                 } else {
                     // TODO: Error.
                 }
             }
-            OpCode::Pop => {
+            Instruction::Pop => {
                 self.state.stack.pop();
             }
-            OpCode::CallFunc => {
+            Instruction::CallFunc(func_name) => {
                 // Call a function, whose parameters are expected to
                 // be on the stack. Pushes the function's return value,
                 // if it returns one.
-                if let Some(Value::StringValue(func_name)) = &instruction.operands[0].value {
-                    if let Some(function) = self.library.get(func_name) {
-                        let actual_param_count = self.state.stack.pop().unwrap().as_number() as u8;
-
-                        // If a function is variadic, it takes as many parameters as it was given.
-                        let expected_param_count = match function.param_count {
-                            ParamCount::N(n) => n,
-                            ParamCount::Variadic => actual_param_count,
-                        };
-
-                        if expected_param_count != actual_param_count {
-                            panic!(
-                                "Function {} expected {}, but received {}",
-                                func_name,
-                                expected_param_count,
-                                actual_param_count,
-                            );
-                        }
-
-                        let result = if actual_param_count == 0 {
-                            function.func.call(&[])
-                        } else {
-                            // Get the parameters, which were pushed in reverse
-                            let mut parameters = vec![YarnValue::Null; actual_param_count as usize];
-                            for i in (0..actual_param_count as usize).rev() {
-                                let value = self.state.stack.pop().unwrap();
-                                parameters[i] = value;
-                            }
-
-                            function.func.call(&parameters)
-                        };
-
-                        if let Some(result) = result {
-                            // If the function returns a value, push it.
-                            self.state.stack.push(result);
-                        }
-                    } else {
-                        // TODO: Error.
-                    }
-                } else {
-                    // TODO: Error.
+                if let Err(error) = self.call_function(func_name) {
+                    warn!("Could not call function: {}", error);
+                    return Some(self.complete_dialogue());
                 }
             }
-            OpCode::PushVariable => {
-                if let Some(Value::StringValue(var_name)) = &instruction.operands[0].value {
-                    if let Some(val) = self.variable_storage.get(var_name) {
-                        self.state.stack.push(val.clone());
-                    } else {
-                        // Value is undefined, so push null.
-                        self.state.stack.push(YarnValue::Null);
-                    }
-                } else {
-                    // TODO: Error.
-                }
+            Instruction::PushVariable { name, smart_variable_node } => {
+                let val = match smart_variable_node {
+                    Some(smart_variable_node) => match self.evaluate_smart_variable(smart_variable_node, &mut Vec::new()) {
+                        Ok(val) => Some(val),
+                        Err(error) => {
+                            warn!("Could not read variable in node {}: {}", self.state.current_node_name, error);
+                            return Some(self.complete_dialogue());
+                        }
+                    },
+                    None => self.variable_storage.get(self.program.compiled().string(name)).cloned(),
+                };
+                // If the value is undefined, push null.
+                self.state.stack.push(val.unwrap_or(YarnValue::Null));
             }
-            OpCode::StoreVariable => {
-                if let Some(Value::StringValue(var_name)) = &instruction.operands[0].value {
-                    if let Some(val) = self.state.stack.last() {
-                        self.variable_storage.insert(var_name.clone(), val.clone());
+            Instruction::StoreVariable { name, is_smart_variable } => {
+                if let Some(val) = self.state.stack.last() {
+                    let var_name = self.program.compiled().string(name);
+                    if is_smart_variable || !self.enums.variables.is_empty() {
+                        if let Err(error) = self.check_variable(var_name, val) {
+                            warn!("Could not set variable in node {}: {}", self.state.current_node_name, error);
+                            return Some(self.complete_dialogue());
+                        }
+                    }
+
+                    if let Some(existing) = self.variable_storage.get_mut(var_name) {
+                        *existing = val.clone();
                     } else {
-                        // TODO: Error.
+                        self.variable_storage.insert(var_name.to_string(), val.clone());
                     }
                 } else {
                     // TODO: Error.
                 }
             }
-            OpCode::Stop => {
-                self.execution_state = ExecutionState::Stopped;
-                let last_node = self.state.current_node_name.clone();
-                self.state = VmState::new();
-                return Some(SuspendReason::DialogueComplete(last_node));
+            Instruction::Stop => {
+                return Some(self.complete_dialogue());
             }
-            OpCode::RunNode => {
+            Instruction::RunNode => {
                 if let Some(YarnValue::Str(node_name)) = self.state.stack.pop() {
-                    let old_node = self.state.current_node_name.clone();
+                    let old_node = std::mem::take(&mut self.state.current_node_name);
 
+                    // This queues up the NodeStart event for the new node, and the current node's
+                    // NodeComplete is returned instead. The new node replaces the current one, so
+                    // if the current one was detoured to, the new one returns to the same place.
+                    let return_stack = std::mem::take(&mut self.state.return_stack);
                     self.set_node(&node_name);
+                    self.state.return_stack = return_stack;
 
                     // Decrement program counter here, because it will
                     // be incremented when this function returns, and
@@ -674,26 +1401,191 @@ impl VirtualMachine {
 
                     self.execution_state = ExecutionState::Suspended;
 
-                    return Some(SuspendReason::NodeChange {
-                        start: node_name,
-                        end: old_node,
-                    })
+                    return Some(SuspendReason::NodeComplete(old_node));
                 } else {
                     // TODO: Error!
                 }
             }
+            Instruction::AddSaliencyCandidate { content_id, complexity, destination } => {
+                let condition_passed = self.state.stack.pop()
+                    .is_some_and(|val| val.as_bool());
+                self.state.saliency_candidates.push(SaliencyCandidate {
+                    content_id: self.program.compiled().string(content_id).to_string(),
+                    complexity,
+                    condition_passed,
+                    destination: self.program.compiled().string(destination).to_string(),
+                });
+            }
+            Instruction::SelectSaliencyCandidate => {
+                let candidates = std::mem::take(&mut self.state.saliency_candidates);
+                let selected = {
+                    let mut rng = self.rng.lock().unwrap();
+                    let mut context = SaliencyContext {
+                        variable_storage: &mut self.variable_storage,
+                        rng: &mut rng,
+                    };
+                    self.saliency_strategy.select(&candidates, &mut context)
+                };
+
+                match selected.and_then(|i| candidates.get(i)) {
+                    Some(candidate) if candidate.condition_passed => {
+                        debug!("Selected saliency candidate: {}", candidate.content_id);
+                        self.state.stack.push(YarnValue::Str(candidate.destination.clone()));
+                        self.state.stack.push(YarnValue::Bool(true));
+                    }
+                    _ => {
+                        self.state.stack.push(YarnValue::Bool(false));
+                    }
+                }
+            }
+            Instruction::DetourToNode(node_name) => {
+                let node_name = self.program.compiled().string(node_name).to_string();
+                return Some(self.detour_to_node(node_name));
+            }
+            Instruction::PeekAndDetourToNode => {
+                if let Some(YarnValue::Str(node_name)) = self.state.stack.last().cloned() {
+                    return Some(self.detour_to_node(node_name));
+                } else {
+                    // TODO: Error!
+                }
+            }
+            Instruction::Return => {
+                let suspend = self.return_from_node();
+                // The program counter is incremented when this function returns.
+                self.state.program_counter -= 1;
+                return Some(suspend);
+            }
         }
 
         None
     }
 
-    fn find_instruction_point_for_label(&self, label: &str) -> isize {
-        let instruction_point = self.program.nodes.get(&self.state.current_node_name)
-            .and_then(|node| node.labels.get(label));
-        if let Some(&instruction_point) = instruction_point {
-            instruction_point as isize
-        } else {
-            panic!("Unknown label {} in node {}", label, self.state.current_node_name);
+    /// Pops the values of a line's expressions off the stack, converted to strings in the order
+    /// they were pushed.
+    fn pop_substitutions(&mut self, expression_count: usize) -> Vec<String> {
+        let first = self.state.stack.len().saturating_sub(expression_count);
+        self.state.stack.drain(first..)
+            .map(|val| val.as_string())
+            .collect()
+    }
+
+    /// Finishes running the current node, and queues up the end of the dialogue.
+    ///
+    /// Returns the `NodeComplete` event for the current node. The `DialogueComplete` event is
+    /// delivered by the next call to `continue_dialogue`, which also stops the VM.
+    fn complete_dialogue(&mut self) -> SuspendReason {
+        let last_node = std::mem::take(&mut self.state.current_node_name);
+        self.execution_state = ExecutionState::Suspended;
+        self.unwind_return_stack();
+        self.pending_events.push_back(SuspendReason::DialogueComplete);
+        SuspendReason::NodeComplete(last_node)
+    }
+
+    /// Queues up the `NodeComplete` events for every node that is waiting for a detour to
+    /// return, because the dialogue is ending before they can finish.
+    fn unwind_return_stack(&mut self) {
+        while let Some(frame) = self.state.return_stack.pop() {
+            self.pending_events.push_back(SuspendReason::NodeComplete(frame.node_name));
         }
     }
+
+    /// Starts running the given node, and remembers to return to the instruction after the
+    /// current one when it finishes.
+    ///
+    /// Returns the `NodeStart` event for the node.
+    fn detour_to_node(&mut self, node_name: String) -> SuspendReason {
+        debug!("Detouring from {} to {}", self.state.current_node_name, node_name);
+        let caller = std::mem::replace(&mut self.state.current_node_name, node_name.clone());
+        self.state.return_stack.push(ReturnFrame {
+            node_name: caller,
+            program_counter: self.state.program_counter + 1,
+        });
+
+        // The program counter is incremented when run_instruction returns.
+        self.state.program_counter = -1;
+        self.execution_state = ExecutionState::Suspended;
+
+        SuspendReason::NodeStart(node_name)
+    }
+
+    /// Finishes running the current node. If it was detoured to, execution continues after the
+    /// detour, otherwise the dialogue completes.
+    ///
+    /// Returns the `NodeComplete` event for the current node.
+    fn return_from_node(&mut self) -> SuspendReason {
+        match self.state.return_stack.pop() {
+            Some(frame) => {
+                debug!("Returning from {} to {}", self.state.current_node_name, frame.node_name);
+                let last_node = std::mem::replace(&mut self.state.current_node_name, frame.node_name);
+                self.state.program_counter = frame.program_counter;
+                self.execution_state = ExecutionState::Suspended;
+                SuspendReason::NodeComplete(last_node)
+            }
+            None => self.complete_dialogue(),
+        }
+    }
+
+    /// Calculates the value of a smart variable by running the node that calculates it, which
+    /// may only contain expressions. The dialogue's state is left as it was, and failures are
+    /// returned instead of ending the dialogue.
+    ///
+    /// `evaluating` holds the nodes of the smart variables that are already being calculated,
+    /// so that a variable that depends on itself is reported instead of recursing forever.
+    fn evaluate_smart_variable(&mut self, node_index: usize, evaluating: &mut Vec<usize>) -> Result<YarnValue, VariableError> {
+        use compiled::Instruction;
+
+        let variable_name = self.program.compiled().nodes[node_index].name.clone();
+        if evaluating.contains(&node_index) {
+            return Err(VariableError::SmartVariableCycle(variable_name));
+        }
+        evaluating.push(node_index);
+
+        let dialogue_state = std::mem::replace(&mut self.state, VmState::new());
+        self.state.current_node_name = variable_name;
+
+        let mut result = Ok(());
+        while let Some(&instruction) = self.program.compiled().nodes[node_index].instructions.get(self.state.program_counter as usize) {
+            match instruction {
+                Instruction::PushVariable { smart_variable_node: Some(smart_variable_node), .. } => {
+                    match self.evaluate_smart_variable(smart_variable_node, evaluating) {
+                        Ok(val) => self.state.stack.push(val),
+                        Err(error) => {
+                            result = Err(error);
+                            break;
+                        }
+                    }
+                }
+                // Failed calls are returned rather than ending the dialogue like they do in
+                // run_instruction, since the dialogue isn't what's running.
+                Instruction::CallFunc(func_name) => {
+                    if let Err(error) = self.call_function(func_name) {
+                        result = Err(VariableError::FunctionCall(error));
+                        break;
+                    }
+                }
+                Instruction::PushString(_)
+                    | Instruction::PushFloat(_)
+                    | Instruction::PushBool(_)
+                    | Instruction::PushNull
+                    | Instruction::PushVariable { .. }
+                    | Instruction::Pop
+                    | Instruction::JumpTo(_)
+                    | Instruction::JumpIfFalse(_)
+                    => {
+                    self.run_instruction(node_index, instruction);
+                }
+                Instruction::Return | Instruction::Stop => break,
+                _ => {
+                    result = Err(VariableError::InvalidSmartVariable(self.state.current_node_name.clone()));
+                    break;
+                }
+            }
+            self.state.program_counter += 1;
+        }
+
+        let value = self.state.stack.pop().unwrap_or(YarnValue::Null);
+        self.state = dialogue_state;
+        evaluating.pop();
+        result.map(|()| value)
+    }
 }
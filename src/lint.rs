@@ -0,0 +1,397 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::{is_smart_variable, DialogueGraph, EnumDeclarations, FunctionInfo, LineInfo, Program, SMART_VARIABLE_TAG};
+use crate::yarn_proto::{
+    instruction::OpCode,
+    operand::Value,
+    Instruction,
+    Node,
+};
+
+/// A problem found in a compiled program by [`lint`].
+///
+/// Serializes to JSON as an object with a `kind` field naming the problem, along with the fields
+/// of the variant.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Lint {
+    /// The node can't be reached from the start node.
+    UnreachableNode {
+        node: String,
+    },
+    /// The variable is read in the node before it has been stored on some path from the start
+    /// node. Smart variables and the internal `$Yarn.Internal.` variables aren't reported.
+    VariableReadBeforeWrite {
+        node: String,
+        variable: String,
+    },
+    /// The variable is stored, but never read anywhere in the program.
+    VariableNeverRead {
+        variable: String,
+    },
+    /// The node calls a function that isn't in the library.
+    UnknownFunction {
+        node: String,
+        function: String,
+    },
+    /// The node runs a line that isn't in the string table.
+    MissingLine {
+        node: String,
+        line_id: String,
+    },
+    /// The string table has a line that isn't used anywhere in the program.
+    UnusedLine {
+        line_id: String,
+    },
+    /// The node has an option that leads to, or directly runs or detours to, a node that doesn't
+    /// exist.
+    MissingNode {
+        node: String,
+        destination: String,
+    },
+    /// The node stores a value in a variable declared with an enum type that isn't a case of
+    /// that enum.
+    InvalidEnumAssignment {
+        node: String,
+        variable: String,
+        enum_name: String,
+    },
+    /// The node stores a value in a smart variable, which can't be set.
+    SmartVariableAssignment {
+        node: String,
+        variable: String,
+    },
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnreachableNode { node } => {
+                write!(f, "Node {} is unreachable", node)
+            }
+            Self::VariableReadBeforeWrite { node, variable } => {
+                write!(f, "Variable {} may be read in node {} before it is set", variable, node)
+            }
+            Self::VariableNeverRead { variable } => {
+                write!(f, "Variable {} is set, but never read", variable)
+            }
+            Self::UnknownFunction { node, function } => {
+                write!(f, "Node {} calls unknown function {}", node, function)
+            }
+            Self::MissingLine { node, line_id } => {
+                write!(f, "Node {} uses line {}, which is missing from the string table", node, line_id)
+            }
+            Self::UnusedLine { line_id } => {
+                write!(f, "Line {} is never used", line_id)
+            }
+            Self::MissingNode { node, destination } => {
+                write!(f, "Node {} leads to node {}, which does not exist", node, destination)
+            }
+            Self::InvalidEnumAssignment { node, variable, enum_name } => {
+                write!(f, "Node {} sets {} to a value that is not a case of enum {}", node, variable, enum_name)
+            }
+            Self::SmartVariableAssignment { node, variable } => {
+                write!(f, "Node {} sets smart variable {}, which can't be set", node, variable)
+            }
+        }
+    }
+}
+
+fn string_operand(instruction: &Instruction, i: usize) -> Option<&str> {
+    match instruction.operands.get(i).and_then(|o| o.value.as_ref()) {
+        Some(Value::StringValue(val)) => Some(val),
+        _ => None,
+    }
+}
+
+/// Checks a compiled program and its string table for common mistakes.
+///
+/// Reachability and variable reads are checked starting from `start_node`. Function calls are
+/// checked against `library`, which should contain every function the game will register, e.g.
+/// the `library` of a [`VirtualMachine`](crate::VirtualMachine) that is set up to run the
+/// program. Values stored in enum variables are checked against `enums`, e.g. the `enums` of
+/// the same VM.
+///
+/// Problems are returned in a stable order, grouped by kind.
+pub fn lint(
+    program: &Program,
+    string_table: &[LineInfo],
+    start_node: &str,
+    library: &HashMap<String, FunctionInfo>,
+    enums: &EnumDeclarations,
+) -> Vec<Lint> {
+    let mut lints = Vec::new();
+
+    let mut node_names: Vec<&String> = program.nodes.keys().collect();
+    node_names.sort();
+
+    // Find every node that can be reached from the start node.
+    let graph = DialogueGraph::new(program, string_table);
+    let mut reachable = HashSet::new();
+    let mut to_visit = vec![start_node];
+    while let Some(node_name) = to_visit.pop() {
+        if program.nodes.contains_key(node_name) && reachable.insert(node_name) {
+            to_visit.extend(graph.edges.iter()
+                .filter(|edge| edge.from == node_name)
+                .map(|edge| edge.to.as_str()));
+        }
+    }
+    // Smart variable nodes run whenever their variable is read, rather than as dialogue.
+    for node_name in &node_names {
+        let is_smart_variable_node = program.nodes[*node_name].tags.iter().any(|tag| tag == SMART_VARIABLE_TAG);
+        if !reachable.contains(node_name.as_str()) && !is_smart_variable_node {
+            lints.push(Lint::UnreachableNode { node: node_name.to_string() });
+        }
+    }
+
+    lints.extend(find_reads_before_writes(program, start_node));
+
+    // Gather everything the program uses.
+    let mut read_variables = HashSet::new();
+    let mut stored_variables = BTreeSet::new();
+    let mut used_line_ids = HashSet::new();
+    let mut function_lints = Vec::new();
+    let mut line_lints = Vec::new();
+    let mut node_lints = Vec::new();
+    let mut enum_lints = Vec::new();
+    for node_name in &node_names {
+        let node = &program.nodes[*node_name];
+        used_line_ids.insert(node.source_text_string_id.as_str());
+
+        for (i, instruction) in node.instructions.iter().enumerate() {
+            let operand = string_operand(instruction, 0);
+            match (OpCode::from_i32(instruction.opcode), operand) {
+                (Some(OpCode::PushVariable), Some(variable)) => {
+                    read_variables.insert(variable);
+                }
+                (Some(OpCode::StoreVariable), Some(variable)) => {
+                    stored_variables.insert(variable);
+                    if is_smart_variable(program, variable) {
+                        enum_lints.push(Lint::SmartVariableAssignment {
+                            node: node_name.to_string(),
+                            variable: variable.to_string(),
+                        });
+                    }
+                    if let Some(enum_name) = enums.variables.get(variable) {
+                        if !stores_enum_case(enums, node, i, enum_name) {
+                            enum_lints.push(Lint::InvalidEnumAssignment {
+                                node: node_name.to_string(),
+                                variable: variable.to_string(),
+                                enum_name: enum_name.clone(),
+                            });
+                        }
+                    }
+                }
+                (Some(OpCode::CallFunc), Some(function)) if !library.contains_key(function) => {
+                    let lint = Lint::UnknownFunction {
+                        node: node_name.to_string(),
+                        function: function.to_string(),
+                    };
+                    if !function_lints.contains(&lint) {
+                        function_lints.push(lint);
+                    }
+                }
+                (Some(OpCode::RunLine), Some(line_id)) | (Some(OpCode::AddOption), Some(line_id)) => {
+                    used_line_ids.insert(line_id);
+                    if !string_table.iter().any(|line_info| line_info.id == line_id) {
+                        line_lints.push(Lint::MissingLine {
+                            node: node_name.to_string(),
+                            line_id: line_id.to_string(),
+                        });
+                    }
+                }
+                _ => {}
+            }
+
+            let destination = match OpCode::from_i32(instruction.opcode) {
+                Some(OpCode::AddOption) => {
+                    string_operand(instruction, 1)
+                        .filter(|destination| !node.labels.contains_key(*destination))
+                }
+                Some(OpCode::DetourToNode) => string_operand(instruction, 0),
+                Some(OpCode::RunNode) | Some(OpCode::PeekAndDetourToNode) if i > 0 => {
+                    let previous = &node.instructions[i - 1];
+                    match OpCode::from_i32(previous.opcode) {
+                        Some(OpCode::PushString) => string_operand(previous, 0),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+            if let Some(destination) = destination {
+                if !program.nodes.contains_key(destination) {
+                    node_lints.push(Lint::MissingNode {
+                        node: node_name.to_string(),
+                        destination: destination.to_string(),
+                    });
+                }
+            }
+        }
+    }
+
+    for variable in stored_variables {
+        if !read_variables.contains(variable) {
+            lints.push(Lint::VariableNeverRead { variable: variable.to_string() });
+        }
+    }
+    lints.extend(function_lints);
+    lints.extend(line_lints);
+    for line_info in string_table {
+        if !used_line_ids.contains(line_info.id.as_str()) {
+            lints.push(Lint::UnusedLine { line_id: line_info.id.clone() });
+        }
+    }
+    lints.extend(node_lints);
+    lints.extend(enum_lints);
+
+    lints
+}
+
+/// Whether the value stored by the `STORE_VARIABLE` at `pc` can be a case of the given enum.
+/// Only values pushed directly before the store are known; anything else, like the result of a
+/// function, is assumed to be valid.
+fn stores_enum_case(enums: &EnumDeclarations, node: &Node, pc: usize, enum_name: &str) -> bool {
+    let previous = match pc.checked_sub(1).map(|i| &node.instructions[i]) {
+        Some(previous) => previous,
+        None => return true,
+    };
+    match OpCode::from_i32(previous.opcode) {
+        // Enum cases are compiled to their raw values, which are the names of the cases.
+        Some(OpCode::PushString) => enums.is_case(enum_name, string_operand(previous, 0).unwrap_or_default()),
+        Some(OpCode::PushFloat) | Some(OpCode::PushBool) | Some(OpCode::PushNull) => false,
+        _ => true,
+    }
+}
+
+/// Returns the instructions that can run after the given one, as (node name, instruction index)
+/// pairs.
+fn successors<'a>(program: &'a Program, node_name: &'a str, node: &'a Node, pc: usize) -> Vec<(&'a str, usize)> {
+    let instruction = &node.instructions[pc];
+    let label = |label: &str| node.labels.get(label).map(|&i| (node_name, i as usize));
+
+    // The destinations of the options in this node, which the player might pick.
+    let option_destinations = || node.instructions.iter()
+        .filter(|instruction| OpCode::from_i32(instruction.opcode) == Some(OpCode::AddOption))
+        .filter_map(|instruction| string_operand(instruction, 1));
+
+    // The string pushed just before this instruction, if any.
+    let pushed_string = pc.checked_sub(1)
+        .map(|i| &node.instructions[i])
+        .filter(|previous| OpCode::from_i32(previous.opcode) == Some(OpCode::PushString))
+        .and_then(|previous| string_operand(previous, 0));
+
+    match OpCode::from_i32(instruction.opcode) {
+        Some(OpCode::JumpTo) => {
+            string_operand(instruction, 0).and_then(label).into_iter().collect()
+        }
+        Some(OpCode::JumpIfFalse) => {
+            let mut successors = vec![(node_name, pc + 1)];
+            successors.extend(string_operand(instruction, 0).and_then(label));
+            successors
+        }
+        Some(OpCode::Jump) => {
+            // Without a pushed label, this jumps to the chosen option or saliency candidate.
+            let candidate_destinations = node.instructions.iter()
+                .filter(|instruction| OpCode::from_i32(instruction.opcode) == Some(OpCode::AddSaliencyCandidate))
+                .filter_map(|instruction| string_operand(instruction, 2));
+            match pushed_string {
+                Some(destination) => label(destination).into_iter().collect(),
+                None => option_destinations().chain(candidate_destinations).filter_map(label).collect(),
+            }
+        }
+        Some(OpCode::RunNode) => {
+            let destinations: Vec<&str> = match pushed_string {
+                Some(destination) => vec![destination],
+                None => option_destinations().collect(),
+            };
+            destinations.into_iter()
+                .filter_map(|destination| program.nodes.get_key_value(destination))
+                .map(|(name, _)| (name.as_str(), 0))
+                .collect()
+        }
+        Some(OpCode::DetourToNode) | Some(OpCode::PeekAndDetourToNode) => {
+            // Variables stored by the detour aren't tracked, so only the return is followed.
+            vec![(node_name, pc + 1)]
+        }
+        Some(OpCode::Stop) | Some(OpCode::Return) => Vec::new(),
+        _ => vec![(node_name, pc + 1)],
+    }
+}
+
+/// The prefix of variables that the VM and compiler generate, rather than the script declaring.
+const INTERNAL_VARIABLE_PREFIX: &str = "$Yarn.Internal.";
+
+/// Finds variables that might be read before they are stored, by tracking which variables have
+/// definitely been stored before each instruction on every path from the start node.
+fn find_reads_before_writes(program: &Program, start_node: &str) -> Vec<Lint> {
+    let mut stored_before: HashMap<(&str, usize), BTreeSet<&str>> = HashMap::new();
+    let mut to_visit = VecDeque::new();
+
+    if let Some((start_node, _)) = program.nodes.get_key_value(start_node) {
+        stored_before.insert((start_node.as_str(), 0), BTreeSet::new());
+        to_visit.push_back((start_node.as_str(), 0));
+    }
+
+    while let Some((node_name, pc)) = to_visit.pop_front() {
+        let node = &program.nodes[node_name];
+        if pc >= node.instructions.len() {
+            continue;
+        }
+
+        let mut stored = stored_before[&(node_name, pc)].clone();
+        let instruction = &node.instructions[pc];
+        if OpCode::from_i32(instruction.opcode) == Some(OpCode::StoreVariable) {
+            stored.extend(string_operand(instruction, 0));
+        }
+
+        for successor in successors(program, node_name, node, pc) {
+            // A variable is only definitely stored if it's stored on every path that leads here.
+            let changed = match stored_before.get_mut(&successor) {
+                Some(existing) => {
+                    let intersection: BTreeSet<&str> = existing.intersection(&stored).cloned().collect();
+                    let changed = intersection.len() != existing.len();
+                    *existing = intersection;
+                    changed
+                }
+                None => {
+                    stored_before.insert(successor, stored.clone());
+                    true
+                }
+            };
+            if changed {
+                to_visit.push_back(successor);
+            }
+        }
+    }
+
+    let mut lints = Vec::new();
+    let mut visited: Vec<_> = stored_before.into_iter().collect();
+    visited.sort();
+    for ((node_name, pc), stored) in visited {
+        let instruction = match program.nodes[node_name].instructions.get(pc) {
+            Some(instruction) => instruction,
+            None => continue,
+        };
+        if OpCode::from_i32(instruction.opcode) != Some(OpCode::PushVariable) {
+            continue;
+        }
+        // Smart variables are calculated when they're read, so they're never stored. Internal
+        // variables, like the ones that track `once` content, are read before they're stored on
+        // purpose, since not having been stored is what they record.
+        let variable = string_operand(instruction, 0)
+            .filter(|variable| !is_smart_variable(program, variable) && !variable.starts_with(INTERNAL_VARIABLE_PREFIX));
+        if let Some(variable) = variable {
+            let lint = Lint::VariableReadBeforeWrite {
+                node: node_name.to_string(),
+                variable: variable.to_string(),
+            };
+            if !stored.contains(variable) && !lints.contains(&lint) {
+                lints.push(lint);
+            }
+        }
+    }
+    lints
+}
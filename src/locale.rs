@@ -0,0 +1,190 @@
+use std::error::Error;
+use std::fmt;
+
+use intl_pluralrules::{PluralRuleType, PluralRules};
+use unic_langid::{CharacterDirection, LanguageIdentifier};
+use unicode_bidi::Direction;
+
+use crate::utils::{expand_with_rules, try_expand_with_rules, FormatFunctionError};
+
+/// A problem that stops a [`LocaleContext`] from being created.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LocaleError {
+    /// The locale code isn't a valid IETF BCP-47 language tag.
+    Invalid(String),
+    /// There are no plural rules for the locale, or for its language.
+    Unsupported(String),
+}
+
+impl fmt::Display for LocaleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Invalid(locale) => write!(f, "\"{}\" is not a valid locale", locale),
+            Self::Unsupported(locale) => write!(f, "Locale {} has no plural rules", locale),
+        }
+    }
+}
+
+impl Error for LocaleError {}
+
+/// U+2068 FIRST STRONG ISOLATE, which starts text that is laid out on its own, in the direction
+/// of its first letter.
+const FIRST_STRONG_ISOLATE: char = '\u{2068}';
+/// U+2069 POP DIRECTIONAL ISOLATE, which ends the text started by [`FIRST_STRONG_ISOLATE`].
+const POP_DIRECTIONAL_ISOLATE: char = '\u{2069}';
+
+pub(crate) fn is_isolate_mark(c: char) -> bool {
+    c == FIRST_STRONG_ISOLATE || c == POP_DIRECTIONAL_ISOLATE
+}
+
+/// The direction that text is written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextDirection {
+    LeftToRight,
+    RightToLeft,
+}
+
+/// Everything needed to format lines for one locale, looked up once so it can be reused for
+/// every line.
+///
+/// ```
+/// # use yharnam::LocaleContext;
+/// let context = LocaleContext::new("en-GB").unwrap();
+/// assert_eq!(context.expand(r#"[plural "3" one="% apple" other="% apples"]"#), "3 apples");
+/// ```
+#[derive(Clone)]
+pub struct LocaleContext {
+    locale: LanguageIdentifier,
+    direction: TextDirection,
+    cardinal_rules: PluralRules,
+    ordinal_rules: PluralRules,
+    isolate_substitutions: bool,
+}
+
+impl LocaleContext {
+    /// Creates the context for a locale, given as an IETF BCP-47 language tag.
+    ///
+    /// When there are no plural rules for the exact locale, e.g. `en-GB`, the rules for its
+    /// language are used instead.
+    pub fn new(locale_code: &str) -> Result<Self, LocaleError> {
+        let locale: LanguageIdentifier = locale_code.parse()
+            .map_err(|_| LocaleError::Invalid(locale_code.to_string()))?;
+
+        let mut language = locale.clone();
+        language.script = None;
+        language.region = None;
+        language.clear_variants();
+
+        let rules = |rule_type| PluralRules::create(locale.clone(), rule_type)
+            .or_else(|_| PluralRules::create(language.clone(), rule_type))
+            .map_err(|_| LocaleError::Unsupported(locale.to_string()));
+        let cardinal_rules = rules(PluralRuleType::CARDINAL)?;
+        let ordinal_rules = rules(PluralRuleType::ORDINAL)?;
+
+        // Vertical scripts are laid out left to right when they're set horizontally.
+        let direction = match locale.character_direction() {
+            CharacterDirection::RTL => TextDirection::RightToLeft,
+            _ => TextDirection::LeftToRight,
+        };
+
+        Ok(Self {
+            locale,
+            direction,
+            cardinal_rules,
+            ordinal_rules,
+            isolate_substitutions: false,
+        })
+    }
+
+    pub fn locale(&self) -> &LanguageIdentifier {
+        &self.locale
+    }
+
+    /// The direction the locale's script is written in.
+    pub fn direction(&self) -> TextDirection {
+        self.direction
+    }
+
+    /// Sets whether [`compose`](Self::compose) wraps substitutions in Unicode isolate marks
+    /// (U+2068 and U+2069) when the locale is written right to left. This stops numbers and
+    /// left-to-right names from being reordered with the text around them. Off by default,
+    /// since not every font or text renderer handles the marks.
+    pub fn set_isolate_substitutions(&mut self, isolate_substitutions: bool) {
+        self.isolate_substitutions = isolate_substitutions;
+    }
+
+    /// Builds the text to show for a [`Line`](crate::Line): replaces the placeholders in
+    /// `text`, the line's text from the string table, with the line's substitutions, and then
+    /// expands its format functions.
+    ///
+    /// # Panics
+    /// Like [`expand`](Self::expand). Use [`try_compose`](Self::try_compose) for lines that may
+    /// be malformed, like ones from a translation that hasn't been checked.
+    pub fn compose(&self, text: &str, substitutions: &[String]) -> String {
+        self.try_compose(text, substitutions)
+            .unwrap_or_else(|error| panic!("{} in line \"{}\"", error, text))
+    }
+
+    /// Like [`compose`](Self::compose), but returns an error instead of panicking when a format
+    /// function is malformed, or the value of a `plural` or `ordinal` function isn't a number.
+    pub fn try_compose(&self, text: &str, substitutions: &[String]) -> Result<String, FormatFunctionError> {
+        let isolate = self.isolate_substitutions && self.direction == TextDirection::RightToLeft;
+
+        let mut text = text.to_string();
+        for (i, substitution) in substitutions.iter().enumerate() {
+            let placeholder = format!("{{{}}}", i);
+            text = if isolate {
+                let isolated = format!("{}{}{}", FIRST_STRONG_ISOLATE, substitution, POP_DIRECTIONAL_ISOLATE);
+                text.replacen(&placeholder, &isolated, 1)
+            } else {
+                text.replacen(&placeholder, substitution, 1)
+            };
+        }
+
+        self.try_expand(&text)
+    }
+
+    /// The base direction of a composed line, from its first letter that has a direction, as
+    /// in the Unicode Bidirectional Algorithm. Text inside isolate marks is skipped. A line
+    /// without any such letters, e.g. one that's only a number, takes the locale's direction.
+    pub fn line_direction(&self, line: &str) -> TextDirection {
+        match unicode_bidi::get_base_direction(line) {
+            Direction::Ltr => TextDirection::LeftToRight,
+            Direction::Rtl => TextDirection::RightToLeft,
+            Direction::Mixed => self.direction,
+        }
+    }
+
+    /// Expands all [format functions](https://yarnspinner.dev/docs/syntax#format-functions)
+    /// in a given string, like [`expand_format_functions`](crate::expand_format_functions).
+    ///
+    /// # Panics
+    /// When a format function in the string is malformed, or it contains a `plural` or
+    /// `ordinal` format function, but the specified value cannot be parsed as a number.
+    pub fn expand(&self, input: &str) -> String {
+        expand_with_rules(input, &self.cardinal_rules, &self.ordinal_rules)
+    }
+
+    /// Like [`expand`](Self::expand), but returns an error instead of panicking.
+    pub fn try_expand(&self, input: &str) -> Result<String, FormatFunctionError> {
+        try_expand_with_rules(input, &self.cardinal_rules, &self.ordinal_rules)
+    }
+
+    pub(crate) fn cardinal_rules(&self) -> &PluralRules {
+        &self.cardinal_rules
+    }
+
+    pub(crate) fn ordinal_rules(&self) -> &PluralRules {
+        &self.ordinal_rules
+    }
+}
+
+impl fmt::Debug for LocaleContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LocaleContext")
+            .field("locale", &self.locale)
+            .field("direction", &self.direction)
+            .field("isolate_substitutions", &self.isolate_substitutions)
+            .finish_non_exhaustive()
+    }
+}
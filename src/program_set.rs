@@ -0,0 +1,134 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::{LineInfo, Program};
+
+/// A problem that stops a program from being added to a [`ProgramSet`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeError {
+    /// A program with the same name is already in the set.
+    DuplicateProgram(String),
+    /// A node with the same name is already in another program in the set.
+    DuplicateNode {
+        node: String,
+        existing_program: String,
+        new_program: String,
+    },
+    /// A line with the same ID is already in the string table of another program in the set.
+    DuplicateLineId {
+        line_id: String,
+        existing_program: String,
+        new_program: String,
+    },
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::DuplicateProgram(program) => write!(f, "A program named {} has already been added", program),
+            Self::DuplicateNode { node, existing_program, new_program } => write!(
+                f,
+                "Node {} in program {} is already defined in program {}",
+                node,
+                new_program,
+                existing_program,
+            ),
+            Self::DuplicateLineId { line_id, existing_program, new_program } => write!(
+                f,
+                "Line {} in program {} is already defined in program {}",
+                line_id,
+                new_program,
+                existing_program,
+            ),
+        }
+    }
+}
+
+impl Error for MergeError {}
+
+/// Several compiled programs, usually one per `.yarn` file, that make up one dialogue.
+///
+/// Programs are identified by their names, and can be added and removed at any time, e.g. to
+/// stream in a chapter or a DLC. After changing the set, pass [`program`](Self::program) to
+/// [`VirtualMachine::set_program`](crate::VirtualMachine::set_program) to run it without
+/// losing any variables.
+#[derive(Debug, Clone, Default)]
+pub struct ProgramSet {
+    programs: Vec<(Program, Vec<LineInfo>)>,
+}
+
+impl ProgramSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a program and its string table to the set, checking that its nodes and lines don't
+    /// clash with those of the programs already in it.
+    pub fn add(&mut self, program: Program, string_table: Vec<LineInfo>) -> Result<(), MergeError> {
+        for (existing, existing_string_table) in &self.programs {
+            if existing.name == program.name {
+                return Err(MergeError::DuplicateProgram(program.name));
+            }
+
+            let mut node_names: Vec<&String> = program.nodes.keys().collect();
+            node_names.sort();
+            if let Some(node) = node_names.into_iter().find(|node| existing.nodes.contains_key(*node)) {
+                return Err(MergeError::DuplicateNode {
+                    node: node.clone(),
+                    existing_program: existing.name.clone(),
+                    new_program: program.name.clone(),
+                });
+            }
+
+            let duplicate_line = string_table.iter().find(|line_info| {
+                existing_string_table.iter().any(|existing_line| existing_line.id == line_info.id)
+            });
+            if let Some(line_info) = duplicate_line {
+                return Err(MergeError::DuplicateLineId {
+                    line_id: line_info.id.clone(),
+                    existing_program: existing.name.clone(),
+                    new_program: program.name.clone(),
+                });
+            }
+        }
+
+        self.programs.push((program, string_table));
+        Ok(())
+    }
+
+    /// Removes the program with the given name, returning it along with its string table.
+    pub fn remove(&mut self, name: &str) -> Option<(Program, Vec<LineInfo>)> {
+        let index = self.programs.iter().position(|(program, _)| program.name == name)?;
+        Some(self.programs.remove(index))
+    }
+
+    /// Whether a program with the given name is in the set.
+    pub fn contains(&self, name: &str) -> bool {
+        self.programs.iter().any(|(program, _)| program.name == name)
+    }
+
+    /// The names of the programs in the set, in the order they were added.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.programs.iter().map(|(program, _)| program.name.as_str())
+    }
+
+    /// Combines every program in the set into one. Its name is the names of the programs, joined
+    /// with `+`.
+    pub fn program(&self) -> Program {
+        let mut merged = Program {
+            name: self.names().collect::<Vec<_>>().join("+"),
+            ..Default::default()
+        };
+        for (program, _) in &self.programs {
+            merged.nodes.extend(program.nodes.clone());
+        }
+        merged
+    }
+
+    /// Combines the string tables of every program in the set.
+    pub fn string_table(&self) -> Vec<LineInfo> {
+        self.programs.iter()
+            .flat_map(|(_, string_table)| string_table.iter().cloned())
+            .collect()
+    }
+}
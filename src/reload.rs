@@ -0,0 +1,61 @@
+use crate::yarn_proto::{
+    instruction::OpCode,
+    Instruction,
+    Node,
+};
+
+/// What happened to the running dialogue when a [`VirtualMachine`](crate::VirtualMachine)
+/// reloaded its program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReloadOutcome {
+    /// No dialogue was running.
+    NotRunning,
+    /// The dialogue carries on from the equivalent position in the new program.
+    Continued,
+    /// The equivalent position couldn't be found in the new program, so the node that was
+    /// running starts again from the beginning.
+    RestartedNode(String),
+    /// The node that was running is no longer in the program, so the dialogue is stopped.
+    Stopped,
+}
+
+/// Finds the position in the new version of a node that is equivalent to `program_counter` in
+/// the old version.
+///
+/// The position is anchored to the last line, option or command that ran before it, which is
+/// looked for in the new node as close as possible to where it was. Everything that ran between
+/// the anchor and the position must be the same in both versions, so that the values the VM has
+/// on its stack still make sense.
+pub(crate) fn map_position(old: &Node, new: &Node, program_counter: usize) -> Option<usize> {
+    if old.instructions == new.instructions {
+        return Some(program_counter);
+    }
+
+    let executed = old.instructions.get(..program_counter)?;
+    let (old_start, new_start) = match executed.iter().rposition(is_anchor) {
+        Some(old_anchor) => {
+            let (new_anchor, _) = new.instructions.iter()
+                .enumerate()
+                .filter(|(_, instruction)| **instruction == old.instructions[old_anchor])
+                .min_by_key(|(i, _)| (*i as isize - old_anchor as isize).abs())?;
+            (old_anchor + 1, new_anchor + 1)
+        }
+        None => (0, 0),
+    };
+
+    let since_anchor = &executed[old_start..];
+    let new_position = new_start + since_anchor.len();
+    if new.instructions.get(new_start..new_position)? != since_anchor {
+        return None;
+    }
+    Some(new_position)
+}
+
+/// Whether an instruction can be recognized in another version of its node. Lines and options
+/// have unique IDs, and commands usually have distinctive text.
+fn is_anchor(instruction: &Instruction) -> bool {
+    matches!(
+        OpCode::from_i32(instruction.opcode),
+        Some(OpCode::RunLine | OpCode::AddOption | OpCode::RunCommand)
+    )
+}
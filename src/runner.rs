@@ -0,0 +1,282 @@
+use std::future::{self, Future};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::{
+    CommandAction,
+    CommandError,
+    CommandRegistry,
+    Line,
+    ReloadOutcome,
+    SharedProgram,
+    SuspendReason,
+    VirtualMachine,
+    YarnOption,
+};
+
+/// Receives the content produced while a [`DialogueRunner`] runs dialogue.
+///
+/// Every method returns a [`Future`], and the runner waits for it to complete before it
+/// continues running the dialogue. Handlers that finish immediately can return
+/// [`std::future::ready`], while a line handler can wait for the user to click to continue, or a
+/// command handler can block the dialogue until an animation is done.
+///
+/// Only [`line`](DialogueHandler::line), [`options`](DialogueHandler::options), and
+/// [`command`](DialogueHandler::command) must be implemented. The node and dialogue events do
+/// nothing by default.
+pub trait DialogueHandler {
+    /// Called when a line should be shown to the user.
+    fn line(&mut self, line: Line) -> impl Future<Output = ()>;
+
+    /// Called when options should be shown to the user. Resolves to the `id` of the selected
+    /// option, which must be one of the options that are available. If it isn't, the options are
+    /// shown again. At least one of the options is always available.
+    fn options(&mut self, options: Vec<YarnOption>) -> impl Future<Output = u32>;
+
+    /// Called when a command should be run by the game, and it isn't registered in the runner's
+    /// [`CommandRegistry`]. Dialogue doesn't continue until the command finishes.
+    fn command(&mut self, command: String) -> impl Future<Output = ()>;
+
+    /// Called when a registered command could not be run, because its text could not be parsed
+    /// or its arguments don't match what the command expects. Logs a warning by default.
+    fn command_error(&mut self, command: String, error: CommandError) -> impl Future<Output = ()> {
+        warn!("Could not run command \"{}\": {}", command, error);
+        future::ready(())
+    }
+
+    /// Called when a new run of dialogue starts.
+    fn dialogue_start(&mut self) -> impl Future<Output = ()> {
+        future::ready(())
+    }
+
+    /// Called when a node starts running.
+    fn node_start(&mut self, _node_name: String) -> impl Future<Output = ()> {
+        future::ready(())
+    }
+
+    /// Called when a node finishes running.
+    fn node_complete(&mut self, _node_name: String) -> impl Future<Output = ()> {
+        future::ready(())
+    }
+
+    /// Called when the dialogue finishes running.
+    fn dialogue_complete(&mut self) -> impl Future<Output = ()> {
+        future::ready(())
+    }
+
+    /// Called when a program sent to the runner's [`reloader`](DialogueRunner::reloader) has been
+    /// reloaded, before the dialogue continues in it. Handlers can swap in content that belongs
+    /// to the new program here, like its string table.
+    fn program_reloaded(&mut self, _outcome: ReloadOutcome) -> impl Future<Output = ()> {
+        future::ready(())
+    }
+}
+
+/// Runs dialogue on a [`VirtualMachine`], passing everything it produces to a
+/// [`DialogueHandler`].
+///
+/// This takes care of driving `continue_dialogue` and selecting options, so that games don't
+/// have to match on every [`SuspendReason`] themselves. Commands are dispatched to the runner's
+/// [`CommandRegistry`] first, and only passed to the handler if they aren't registered there.
+pub struct DialogueRunner<H> {
+    pub vm: VirtualMachine,
+    pub handler: H,
+    pub commands: CommandRegistry,
+    reload_sender: Sender<SharedProgram>,
+    reloads: Receiver<SharedProgram>,
+}
+
+impl<H: DialogueHandler> DialogueRunner<H> {
+    pub fn new(vm: VirtualMachine, handler: H) -> Self {
+        let (reload_sender, reloads) = mpsc::channel();
+        Self {
+            vm,
+            handler,
+            commands: CommandRegistry::new(),
+            reload_sender,
+            reloads,
+        }
+    }
+
+    /// Returns a sender for programs to reload while the dialogue is running, e.g. from a thread
+    /// that watches for changes to the `.yarnc` file. Programs are reloaded with
+    /// [`VirtualMachine::reload`] before the dialogue next continues.
+    pub fn reloader(&self) -> Sender<SharedProgram> {
+        self.reload_sender.clone()
+    }
+
+    /// Runs the dialogue, starting at the given node, until it completes.
+    pub async fn run(&mut self, start_node: &str) {
+        self.vm.set_node(start_node);
+
+        loop {
+            for program in self.reloads.try_iter() {
+                let outcome = self.vm.reload(program);
+                info!("Reloaded program: {:?}", outcome);
+                self.handler.program_reloaded(outcome).await;
+            }
+
+            match self.vm.continue_dialogue() {
+                SuspendReason::Line(line) => {
+                    self.handler.line(line).await;
+                }
+                SuspendReason::Options(options) => {
+                    loop {
+                        let selected_option_id = self.handler.options(options.clone()).await;
+                        match self.vm.set_selected_option(selected_option_id) {
+                            Ok(()) => break,
+                            Err(error) => warn!("Could not select option {}: {}", selected_option_id, error),
+                        }
+                    }
+                }
+                SuspendReason::Command(command) => {
+                    match self.commands.dispatch(&command) {
+                        Ok(CommandAction::Run(fut)) => fut.await,
+                        Ok(CommandAction::Wait(duration)) => Delay::new(duration).await,
+                        Ok(CommandAction::Stop) => self.vm.stop(),
+                        Err(CommandError::Unknown(_)) => self.handler.command(command).await,
+                        Err(error) => self.handler.command_error(command, error).await,
+                    }
+                }
+                SuspendReason::DialogueStart => {
+                    self.handler.dialogue_start().await;
+                }
+                SuspendReason::NodeStart(node_name) => {
+                    self.handler.node_start(node_name).await;
+                }
+                SuspendReason::NodeComplete(node_name) => {
+                    self.handler.node_complete(node_name).await;
+                }
+                SuspendReason::DialogueComplete => {
+                    self.handler.dialogue_complete().await;
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Runs the dialogue, starting at the given node, blocking the current thread until it
+    /// completes.
+    ///
+    /// This is useful for games that don't use an async runtime. Handlers that return futures
+    /// which don't complete immediately are still supported, as long as they wake the runner
+    /// when they're ready to make progress.
+    pub fn run_blocking(&mut self, start_node: &str) {
+        block_on(self.run(start_node));
+    }
+}
+
+/// A future that completes once a duration has passed.
+///
+/// The first time it's polled, it registers with a timer thread that wakes it up when the time
+/// is up, so it doesn't depend on any particular async runtime. Every poll updates the waker the
+/// timer will use, in case the future has moved to another task.
+struct Delay {
+    deadline: Instant,
+    waker: Option<SharedWaker>,
+}
+
+/// The waker of a [`Delay`], shared with the timer thread.
+type SharedWaker = Arc<Mutex<Waker>>;
+
+impl Delay {
+    fn new(duration: Duration) -> Self {
+        Self {
+            deadline: Instant::now() + duration,
+            waker: None,
+        }
+    }
+}
+
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        match &self.waker {
+            Some(waker) => {
+                let mut waker = waker.lock().unwrap();
+                if !waker.will_wake(cx.waker()) {
+                    *waker = cx.waker().clone();
+                }
+            }
+            None => {
+                let waker = Arc::new(Mutex::new(cx.waker().clone()));
+                wake_at(self.deadline, waker.clone());
+                self.waker = Some(waker);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// Wakes the waker once the deadline has passed, on a timer thread that is shared by every
+/// [`Delay`].
+fn wake_at(deadline: Instant, waker: SharedWaker) {
+    static TIMER: OnceLock<Sender<(Instant, SharedWaker)>> = OnceLock::new();
+
+    let timer = TIMER.get_or_init(|| {
+        let (sender, timers) = mpsc::channel();
+        thread::spawn(move || run_timer(timers));
+        sender
+    });
+    timer.send((deadline, waker)).unwrap();
+}
+
+/// Runs the timer thread, waking each waker it receives once its deadline has passed.
+fn run_timer(timers: Receiver<(Instant, SharedWaker)>) {
+    let mut pending: Vec<(Instant, SharedWaker)> = Vec::new();
+    loop {
+        let now = Instant::now();
+        pending.retain(|(deadline, waker)| {
+            let expired = *deadline <= now;
+            if expired {
+                waker.lock().unwrap().wake_by_ref();
+            }
+            !expired
+        });
+
+        let timer = match pending.iter().map(|(deadline, _)| *deadline).min() {
+            Some(deadline) => timers.recv_timeout(deadline - now),
+            None => timers.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match timer {
+            Ok(timer) => pending.push(timer),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+/// Wakes up a thread that is blocked on a future.
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Polls a future on the current thread until it completes, parking the thread while it waits
+/// to be woken up.
+fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut fut = Box::pin(fut);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+
+    loop {
+        match Pin::as_mut(&mut fut).poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{YarnRng, YarnValue};
+
+/// One of the pieces of content in a line group or node group that the VM can choose between.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SaliencyCandidate {
+    /// The line ID or node name of the content.
+    pub content_id: String,
+    /// How specific the candidate's condition is. Conditions that test more things are more
+    /// complex, and are usually better matches.
+    pub complexity: i32,
+    /// Whether the candidate's condition passed. Candidates whose conditions failed must not be
+    /// chosen.
+    pub condition_passed: bool,
+    /// The label the VM jumps to when the candidate is chosen.
+    pub(crate) destination: String,
+}
+
+/// What a [`SaliencyStrategy`] can use to choose a candidate.
+pub struct SaliencyContext<'a> {
+    /// The VM's variables. Strategies can store what they need to remember here, so that it's
+    /// saved along with the rest of the dialogue's state.
+    pub variable_storage: &'a mut HashMap<String, YarnValue>,
+    pub rng: &'a mut YarnRng,
+}
+
+/// Chooses which piece of content in a line group or node group gets run.
+pub trait SaliencyStrategy: Send + Sync {
+    /// Returns the index of the chosen candidate, or `None` if none of them should be run.
+    fn select(&mut self, candidates: &[SaliencyCandidate], context: &mut SaliencyContext) -> Option<usize>;
+}
+
+fn passing(candidates: &[SaliencyCandidate]) -> impl Iterator<Item = (usize, &SaliencyCandidate)> {
+    candidates.iter()
+        .enumerate()
+        .filter(|(_, candidate)| candidate.condition_passed)
+}
+
+/// Chooses the first candidate whose condition passed.
+#[derive(Debug, Default)]
+pub struct FirstSaliencyStrategy;
+
+impl SaliencyStrategy for FirstSaliencyStrategy {
+    fn select(&mut self, candidates: &[SaliencyCandidate], _context: &mut SaliencyContext) -> Option<usize> {
+        passing(candidates).map(|(i, _)| i).next()
+    }
+}
+
+/// Chooses a random candidate whose condition passed, using the VM's random number generator.
+#[derive(Debug, Default)]
+pub struct RandomSaliencyStrategy;
+
+impl SaliencyStrategy for RandomSaliencyStrategy {
+    fn select(&mut self, candidates: &[SaliencyCandidate], context: &mut SaliencyContext) -> Option<usize> {
+        let passing: Vec<usize> = passing(candidates).map(|(i, _)| i).collect();
+        if passing.is_empty() {
+            return None;
+        }
+        let choice = context.rng.range_inclusive(0, passing.len() as i64 - 1);
+        Some(passing[choice as usize])
+    }
+}
+
+/// Chooses the candidate whose condition passed that was chosen the longest time ago, or that has
+/// never been chosen. Ties go to the first candidate.
+///
+/// When each candidate was last chosen is remembered in variable storage.
+#[derive(Debug, Default)]
+pub struct LeastRecentlySeenSaliencyStrategy;
+
+impl LeastRecentlySeenSaliencyStrategy {
+    const COUNTER_VARIABLE: &'static str = "$Yarn.Internal.Saliency.Counter";
+
+    fn last_seen_variable(content_id: &str) -> String {
+        format!("$Yarn.Internal.Saliency.LastSeen.{}", content_id)
+    }
+}
+
+impl SaliencyStrategy for LeastRecentlySeenSaliencyStrategy {
+    fn select(&mut self, candidates: &[SaliencyCandidate], context: &mut SaliencyContext) -> Option<usize> {
+        let last_seen = |candidate: &SaliencyCandidate| {
+            context.variable_storage.get(&Self::last_seen_variable(&candidate.content_id))
+                .map_or(-1.0, YarnValue::as_number)
+        };
+
+        let mut chosen: Option<(usize, f32)> = None;
+        for (i, candidate) in passing(candidates) {
+            let seen = last_seen(candidate);
+            if chosen.is_none_or(|(_, best)| seen < best) {
+                chosen = Some((i, seen));
+            }
+        }
+        let (chosen, _) = chosen?;
+
+        // Count up every time something is chosen, so that it can be compared with other choices.
+        let counter = context.variable_storage.get(Self::COUNTER_VARIABLE)
+            .map_or(0.0, YarnValue::as_number) + 1.0;
+        context.variable_storage.insert(Self::COUNTER_VARIABLE.to_string(), counter.into());
+        context.variable_storage.insert(Self::last_seen_variable(&candidates[chosen].content_id), counter.into());
+
+        Some(chosen)
+    }
+}
+
+/// Chooses the candidate whose condition passed with the highest complexity, which is the one
+/// that most specifically matches the current situation. Ties go to the first candidate.
+#[derive(Debug, Default)]
+pub struct BestMatchSaliencyStrategy;
+
+impl SaliencyStrategy for BestMatchSaliencyStrategy {
+    fn select(&mut self, candidates: &[SaliencyCandidate], _context: &mut SaliencyContext) -> Option<usize> {
+        let mut chosen: Option<(usize, i32)> = None;
+        for (i, candidate) in passing(candidates) {
+            if chosen.is_none_or(|(_, best)| candidate.complexity > best) {
+                chosen = Some((i, candidate.complexity));
+            }
+        }
+        chosen.map(|(i, _)| i)
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::{FunctionInfo, Program, YarnType, YarnValue};
+use crate::yarn_proto::{
+    instruction::OpCode,
+    operand::Value,
+    Instruction,
+};
+
+/// The parameter and return types of a function in a [`VirtualMachine`](crate::VirtualMachine)'s
+/// library.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FunctionSignature {
+    /// The types of the parameters the function always takes.
+    pub params: Vec<YarnType>,
+    /// If set, the function takes any number of extra parameters of this type after `params`.
+    pub variadic: Option<YarnType>,
+    /// The type of value the function returns, or `None` if it doesn't return one.
+    pub returns: Option<YarnType>,
+}
+
+impl FunctionSignature {
+    pub fn new(params: &[YarnType], returns: Option<YarnType>) -> Self {
+        Self {
+            params: params.to_vec(),
+            variadic: None,
+            returns,
+        }
+    }
+
+    pub fn new_variadic(params: &[YarnType], variadic: YarnType, returns: Option<YarnType>) -> Self {
+        Self {
+            params: params.to_vec(),
+            variadic: Some(variadic),
+            returns,
+        }
+    }
+
+    /// Whether the function can be called with the given number of arguments.
+    pub fn accepts_count(&self, count: usize) -> bool {
+        if self.variadic.is_some() {
+            count >= self.params.len()
+        } else {
+            count == self.params.len()
+        }
+    }
+
+    /// The type of the parameter at the given index, if the function takes that many.
+    pub fn param_type(&self, index: usize) -> Option<YarnType> {
+        self.params.get(index).copied().or(self.variadic)
+    }
+
+    /// Finds the first argument whose type the function doesn't accept, returning its index, the
+    /// type that was expected and the type it has.
+    pub(crate) fn mismatched_argument(&self, arguments: &[YarnValue]) -> Option<(usize, YarnType, YarnType)> {
+        arguments.iter()
+            .enumerate()
+            .map(|(index, argument)| (index, self.param_type(index).unwrap_or(YarnType::Any), argument.yarn_type()))
+            .find(|&(_, expected, received)| !types_match(expected, received))
+    }
+}
+
+impl fmt::Display for FunctionSignature {
+    /// Formats the signature like `(Number, String, ...Any) -> Bool`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut params: Vec<String> = self.params.iter()
+            .map(|param| format!("{:?}", param))
+            .collect();
+        if let Some(variadic) = self.variadic {
+            params.push(format!("...{:?}", variadic));
+        }
+        write!(f, "({})", params.join(", "))?;
+        if let Some(returns) = self.returns {
+            write!(f, " -> {:?}", returns)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returned by [`FunctionInfo::with_signature`] when the signature has a return type but the
+/// function doesn't return a value, or the other way around.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignatureMismatch(pub FunctionSignature);
+
+impl fmt::Display for SignatureMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Function signature {} does not match whether the function returns a value", self.0)
+    }
+}
+
+impl Error for SignatureMismatch {}
+
+/// A function call in a compiled program that doesn't match the library, found by
+/// [`VirtualMachine::check_function_calls`](crate::VirtualMachine::check_function_calls), or when
+/// the call is run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FunctionCallError {
+    /// The function isn't in the library.
+    UnknownFunction {
+        node: String,
+        function: String,
+    },
+    /// The function is called with the wrong number of arguments.
+    WrongArgumentCount {
+        node: String,
+        function: String,
+        signature: FunctionSignature,
+        received: usize,
+    },
+    /// An argument is known to be of a type the function doesn't accept.
+    WrongArgumentType {
+        node: String,
+        function: String,
+        index: usize,
+        expected: YarnType,
+        received: YarnType,
+    },
+    /// A built-in operator was applied to values of types it doesn't support. Only found when
+    /// the call is run.
+    InvalidOperands {
+        node: String,
+        function: String,
+        received: Vec<YarnType>,
+    },
+}
+
+impl fmt::Display for FunctionCallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnknownFunction { node, function } => write!(
+                f,
+                "Node {} calls unknown function {}",
+                node,
+                function,
+            ),
+            Self::WrongArgumentCount { node, function, signature, received } => write!(
+                f,
+                "Node {} calls {}{} with {} arguments",
+                node,
+                function,
+                signature,
+                received,
+            ),
+            Self::WrongArgumentType { node, function, index, expected, received } => write!(
+                f,
+                "Node {} passes a {:?} as argument {} of function {}, which expects a {:?}",
+                node,
+                received,
+                index,
+                function,
+                expected,
+            ),
+            Self::InvalidOperands { node, function, received } => write!(
+                f,
+                "Node {} applies {} to values of types {:?}",
+                node,
+                function,
+                received,
+            ),
+        }
+    }
+}
+
+impl Error for FunctionCallError {}
+
+fn types_match(expected: YarnType, received: YarnType) -> bool {
+    expected == YarnType::Any || received == YarnType::Any || expected == received
+}
+
+fn operand(instruction: &Instruction, i: usize) -> Option<&Value> {
+    instruction.operands.get(i).and_then(|o| o.value.as_ref())
+}
+
+/// Checks every `CALL_FUNC` in a program against the signatures in a library.
+///
+/// The number of arguments comes from the value pushed just before each call. Argument types are
+/// found by tracking the types of the values pushed onto the stack in each node. Values whose
+/// types can't be known ahead of time, like variables, are assumed to be valid.
+pub(crate) fn check_function_calls(
+    program: &Program,
+    library: &HashMap<String, FunctionInfo>,
+) -> Vec<FunctionCallError> {
+    let mut errors = Vec::new();
+
+    let mut node_names: Vec<&String> = program.nodes.keys().collect();
+    node_names.sort();
+
+    for node_name in node_names {
+        let node = &program.nodes[node_name];
+
+        // The types of the values on the stack. Values that were pushed before a jump target
+        // can't be tracked, so any values popped beyond the tracked ones are treated as Any.
+        let mut stack: Vec<YarnType> = Vec::new();
+        let pop_n = |stack: &mut Vec<YarnType>, n: usize| {
+            let n = n.min(stack.len());
+            stack.split_off(stack.len() - n)
+        };
+
+        for (i, instruction) in node.instructions.iter().enumerate() {
+            if node.labels.values().any(|&label| label as usize == i) {
+                stack.clear();
+            }
+
+            let expression_count = |operand_index: usize| match operand(instruction, operand_index) {
+                Some(Value::FloatValue(count)) => *count as usize,
+                _ => 0,
+            };
+
+            match OpCode::from_i32(instruction.opcode) {
+                Some(OpCode::PushString) => stack.push(YarnType::String),
+                Some(OpCode::PushFloat) => stack.push(YarnType::Number),
+                Some(OpCode::PushBool) => stack.push(YarnType::Bool),
+                Some(OpCode::PushNull) | Some(OpCode::PushVariable) => stack.push(YarnType::Any),
+                Some(OpCode::Pop) => {
+                    stack.pop();
+                }
+                Some(OpCode::RunLine) | Some(OpCode::RunCommand) => {
+                    pop_n(&mut stack, expression_count(1));
+                }
+                Some(OpCode::AddOption) => {
+                    let has_condition = matches!(operand(instruction, 3), Some(Value::BoolValue(true)));
+                    pop_n(&mut stack, expression_count(2) + has_condition as usize);
+                }
+                Some(OpCode::ShowOptions) => {
+                    // The selected option's destination is pushed when execution resumes.
+                    stack.push(YarnType::String);
+                }
+                Some(OpCode::RunNode) | Some(OpCode::AddSaliencyCandidate) => {
+                    stack.pop();
+                }
+                Some(OpCode::SelectSaliencyCandidate) => {
+                    // The chosen candidate's label is only pushed if one was chosen.
+                    stack.push(YarnType::String);
+                    stack.push(YarnType::Bool);
+                }
+                Some(OpCode::CallFunc) => {
+                    let function_name = match operand(instruction, 0) {
+                        Some(Value::StringValue(name)) => name,
+                        _ => continue,
+                    };
+
+                    // The argument count is pushed just before the call.
+                    stack.pop();
+                    let arg_count = match i.checked_sub(1).and_then(|i| operand(&node.instructions[i], 0)) {
+                        Some(Value::FloatValue(count)) => *count as usize,
+                        _ => 0,
+                    };
+                    let args = pop_n(&mut stack, arg_count);
+
+                    let function = match library.get(function_name) {
+                        Some(function) => function,
+                        None => {
+                            errors.push(FunctionCallError::UnknownFunction {
+                                node: node_name.clone(),
+                                function: function_name.clone(),
+                            });
+                            stack.push(YarnType::Any);
+                            continue;
+                        }
+                    };
+                    let signature = &function.signature;
+
+                    if !signature.accepts_count(arg_count) {
+                        errors.push(FunctionCallError::WrongArgumentCount {
+                            node: node_name.clone(),
+                            function: function_name.clone(),
+                            signature: signature.clone(),
+                            received: arg_count,
+                        });
+                    } else {
+                        // Only the arguments that were tracked are known, and they're the last ones.
+                        let first_known = arg_count - args.len();
+                        for (index, &received) in args.iter().enumerate() {
+                            let index = first_known + index;
+                            let expected = signature.param_type(index).unwrap_or(YarnType::Any);
+                            if !types_match(expected, received) {
+                                errors.push(FunctionCallError::WrongArgumentType {
+                                    node: node_name.clone(),
+                                    function: function_name.clone(),
+                                    index,
+                                    expected,
+                                    received,
+                                });
+                            }
+                        }
+                    }
+
+                    if let Some(returns) = signature.returns {
+                        stack.push(returns);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    errors
+}
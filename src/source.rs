@@ -0,0 +1,267 @@
+use std::collections::HashSet;
+use std::iter;
+
+use crate::LineInfo;
+
+/// A line in a `.yarn` file that is shown to the player, so it needs a line ID to be localized.
+///
+/// Lines are found by scanning the file's node bodies line by line, so this doesn't need the
+/// file to be compiled. Dialogue, shortcut options (`->`), line group items (`=>`) and options
+/// with a destination (`[[Text|Node]]`) are lines, while commands, jumps and comments aren't.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    /// The title of the node the line is in.
+    pub node: String,
+    pub line_number: u32,
+    /// The line's text as it appears in the string table, with inline expressions replaced by
+    /// `{0}`, `{1}` and so on.
+    pub text: String,
+    /// The line's `#line:` ID, if it has one.
+    pub id: Option<String>,
+}
+
+/// Finds every line in a `.yarn` file.
+pub fn scan_lines(source: &str) -> Vec<SourceLine> {
+    let mut lines = Vec::new();
+    for_each_line(source, |node, line_number, line| {
+        if let Some(parsed) = parse_line(line) {
+            lines.push(SourceLine {
+                node: node.to_string(),
+                line_number,
+                text: string_table_text(parsed.text),
+                id: parsed.id.map(str::to_string),
+            });
+        }
+        None
+    });
+    lines
+}
+
+/// Builds the string table for a `.yarn` file, in the same shape as the `.csv` file the
+/// compiler writes next to the `.yarnc` file. Lines without an ID are left out.
+pub fn extract_string_table(source: &str, file_name: &str) -> Vec<LineInfo> {
+    scan_lines(source).into_iter()
+        .filter_map(|line| Some(LineInfo {
+            id: line.id?,
+            text: line.text,
+            file: file_name.to_string(),
+            node: line.node,
+            line_number: line.line_number,
+        }))
+        .collect()
+}
+
+/// Adds a `#line:` ID to every line in a `.yarn` file that doesn't have one, returning the new
+/// source. Lines that already have an ID are left alone.
+///
+/// IDs are generated from the file name, node and text of the line, so tagging the same file
+/// always gives the same IDs. `used_ids` holds the IDs used by every file in the project, and
+/// the new IDs are added to it, so that IDs stay unique when several files are tagged.
+pub fn tag_lines(source: &str, file_name: &str, used_ids: &mut HashSet<String>) -> String {
+    for_each_line(source, |node, _, line| {
+        let parsed = parse_line(line)?;
+        if parsed.id.is_some() {
+            return None;
+        }
+
+        let text = string_table_text(parsed.text);
+        let id = (0..)
+            .map(|salt| generate_line_id(file_name, node, &text, salt))
+            .find(|id| !used_ids.contains(id))
+            .unwrap();
+        used_ids.insert(id.clone());
+
+        // Put the tag straight after the line's content, keeping any comment after it.
+        let content = line[..parsed.tag_position].trim_end();
+        Some(format!("{} #{}{}", content, id, &line[content.len()..]))
+    })
+}
+
+/// Calls `f` with every line in the body of a node, along with the node's title and the line's
+/// number, and returns the source with the lines that `f` replaced.
+fn for_each_line(source: &str, mut f: impl FnMut(&str, u32, &str) -> Option<String>) -> String {
+    let mut output = Vec::new();
+    let mut node = String::new();
+    let mut in_body = false;
+
+    for (i, raw_line) in source.split('\n').enumerate() {
+        // Keep Windows line endings as they are.
+        let (line, line_ending) = match raw_line.strip_suffix('\r') {
+            Some(line) => (line, "\r"),
+            None => (raw_line, ""),
+        };
+
+        let trimmed = line.trim();
+        let replacement = if !in_body {
+            if trimmed == "---" {
+                in_body = true;
+            } else if let Some(title) = trimmed.strip_prefix("title:") {
+                node = title.trim().to_string();
+            }
+            None
+        } else if trimmed == "===" {
+            in_body = false;
+            node.clear();
+            None
+        } else {
+            f(&node, i as u32 + 1, line)
+        };
+
+        match replacement {
+            Some(replacement) => output.push(replacement + line_ending),
+            None => output.push(raw_line.to_string()),
+        }
+    }
+
+    output.join("\n")
+}
+
+struct ParsedLine<'a> {
+    /// The line's text, without its option markup, condition, hashtags or comment.
+    text: &'a str,
+    id: Option<&'a str>,
+    /// Where a hashtag can be added to the line, which is before any comment.
+    tag_position: usize,
+}
+
+fn parse_line(line: &str) -> Option<ParsedLine<'_>> {
+    let trimmed = line.trim_start();
+    let indent = line.len() - trimmed.len();
+    if trimmed.is_empty() || trimmed.starts_with("//") || trimmed.starts_with("<<") {
+        return None;
+    }
+
+    // Options with a destination keep their text before the `|`, and their hashtags after the
+    // brackets. Without a `|`, they're just a jump.
+    let (text, markup_start) = if let Some(option) = trimmed.strip_prefix("[[") {
+        let end = option.find("]]")?;
+        let (text, _) = option[..end].split_once('|')?;
+        (text.trim(), indent + 2 + end + 2)
+    } else {
+        let prefix_len = if trimmed.starts_with("->") || trimmed.starts_with("=>") { 2 } else { 0 };
+        let start = indent + prefix_len;
+        let (text_end, _) = split_markup(&line[start..]);
+        (line[start..start + text_end].trim(), start + text_end)
+    };
+    if text.is_empty() {
+        return None;
+    }
+
+    let (_, comment_start) = split_markup(&line[markup_start..]);
+    let markup = &line[markup_start..markup_start + comment_start];
+    // Hashtags come after the line's condition, if it has one.
+    let hashtags = markup.rfind(">>").map_or(markup, |end| &markup[end + 2..]);
+    let id = hashtags.split_whitespace()
+        .filter_map(|tag| tag.strip_prefix('#'))
+        .find(|tag| tag.starts_with("line:"));
+
+    Some(ParsedLine {
+        text,
+        id,
+        tag_position: markup_start + comment_start,
+    })
+}
+
+/// Finds where a line's text ends, at its condition or its first hashtag, and where its comment
+/// starts. Markup inside inline expressions, format functions and strings doesn't count.
+///
+/// A comment's `//` has to be at the start or after whitespace, so that URLs like
+/// `https://yarnspinner.dev` stay part of the text.
+fn split_markup(s: &str) -> (usize, usize) {
+    let mut text_end = None;
+    let mut in_expression = false;
+    let mut in_function = false;
+    let mut in_string = false;
+
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '"' if in_expression || in_function => in_string = !in_string,
+            _ if in_string => {}
+            '{' => in_expression = true,
+            '}' => in_expression = false,
+            _ if in_expression => {}
+            '[' => in_function = true,
+            ']' => in_function = false,
+            '/' if s[i..].starts_with("//") && (i == 0 || s[..i].ends_with(char::is_whitespace)) => {
+                return (text_end.unwrap_or(i), i);
+            }
+            '#' if text_end.is_none() => text_end = Some(i),
+            '<' if text_end.is_none() && s[i..].starts_with("<<") => text_end = Some(i),
+            _ => {}
+        }
+    }
+
+    (text_end.unwrap_or(s.len()), s.len())
+}
+
+/// Converts a line's source text into the text the compiler puts in the string table. Inline
+/// expressions become numbered placeholders, which are quoted when they're the value of a
+/// format function, and escaped characters are unescaped.
+fn string_table_text(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut expression_count = 0;
+    let mut in_function = false;
+    let mut in_string = false;
+
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped @ ('#' | '{' | '}' | '<' | '>' | '/' | '\\')) => output.push(escaped),
+                Some(other) => {
+                    output.push(c);
+                    output.push(other);
+                }
+                None => output.push(c),
+            },
+            '"' if in_function => {
+                in_string = !in_string;
+                output.push(c);
+            }
+            '{' if !in_string => {
+                chars.by_ref().find(|&c| c == '}');
+                if in_function {
+                    output.push_str(&format!("\"{{{}}}\"", expression_count));
+                } else {
+                    output.push_str(&format!("{{{}}}", expression_count));
+                }
+                expression_count += 1;
+            }
+            '[' if !in_string => {
+                in_function = true;
+                output.push(c);
+            }
+            ']' if !in_string => {
+                in_function = false;
+                output.push(c);
+            }
+            _ => output.push(c),
+        }
+    }
+
+    output
+}
+
+/// Generates a line ID from a 32-bit FNV-1a hash, which unlike the standard library's hasher is
+/// the same on every platform and Rust version. `salt` tells apart lines with the same text.
+fn generate_line_id(file_name: &str, node: &str, text: &str, salt: u32) -> String {
+    let salt = salt.to_le_bytes();
+    let bytes = file_name.bytes()
+        .chain(iter::once(0))
+        .chain(node.bytes())
+        .chain(iter::once(0))
+        .chain(text.bytes())
+        .chain(iter::once(0))
+        .chain(salt.iter().copied());
+
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in bytes {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    format!("line:{:08x}", hash)
+}
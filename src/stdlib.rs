@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::{Callable, FunctionInfo, FunctionSignature, YarnRng, YarnType, YarnValue};
+
+fn returning<F>(params: &[YarnType], returns: YarnType, func: F) -> FunctionInfo
+where
+    F: Fn(&[YarnValue]) -> YarnValue + Send + Sync + 'static,
+{
+    FunctionInfo {
+        signature: FunctionSignature::new(params, Some(returns)),
+        func: Callable::Returning(Box::new(func)),
+    }
+}
+
+/// Adds the standard library of functions that Yarn content usually expects to a library.
+///
+/// Random functions draw from the given generator, so that a seeded generator produces the
+/// same results every time the dialogue is run.
+pub(crate) fn register(library: &mut HashMap<String, FunctionInfo>, rng: &Arc<Mutex<YarnRng>>) {
+    use YarnType::*;
+
+    // Random numbers.
+    let random_rng = rng.clone();
+    library.insert("random".to_string(), returning(&[], Number, move |_| {
+        random_rng.lock().unwrap().next_f32().into()
+    }));
+
+    let random_range_rng = rng.clone();
+    library.insert("random_range".to_string(), returning(&[Number, Number], Number, move |parameters| {
+        let min = parameters[0].as_number() as i64;
+        let max = parameters[1].as_number() as i64;
+        (random_range_rng.lock().unwrap().range_inclusive(min, max) as f32).into()
+    }));
+
+    let dice_rng = rng.clone();
+    library.insert("dice".to_string(), returning(&[Number], Number, move |parameters| {
+        let sides = (parameters[0].as_number() as i64).max(1);
+        (dice_rng.lock().unwrap().range_inclusive(1, sides) as f32).into()
+    }));
+
+    // Rounding.
+    library.insert("round".to_string(), returning(&[Number], Number, |parameters| {
+        parameters[0].as_number().round().into()
+    }));
+
+    library.insert("floor".to_string(), returning(&[Number], Number, |parameters| {
+        parameters[0].as_number().floor().into()
+    }));
+
+    library.insert("ceil".to_string(), returning(&[Number], Number, |parameters| {
+        parameters[0].as_number().ceil().into()
+    }));
+
+    // The next integer above the number, or the number plus one if it's already an integer.
+    library.insert("inc".to_string(), returning(&[Number], Number, |parameters| {
+        let val = parameters[0].as_number();
+        let result = if val.fract() == 0.0 { val + 1.0 } else { val.ceil() };
+        result.into()
+    }));
+
+    // The next integer below the number, or the number minus one if it's already an integer.
+    library.insert("dec".to_string(), returning(&[Number], Number, |parameters| {
+        let val = parameters[0].as_number();
+        let result = if val.fract() == 0.0 { val - 1.0 } else { val.floor() };
+        result.into()
+    }));
+
+    library.insert("decimal".to_string(), returning(&[Number], Number, |parameters| {
+        parameters[0].as_number().fract().into()
+    }));
+
+    library.insert("int".to_string(), returning(&[Number], Number, |parameters| {
+        parameters[0].as_number().trunc().into()
+    }));
+
+    // Conversions.
+    library.insert("string".to_string(), returning(&[Any], String, |parameters| {
+        parameters[0].as_string().into()
+    }));
+
+    library.insert("number".to_string(), returning(&[Any], Number, |parameters| {
+        parameters[0].as_number().into()
+    }));
+
+    // Numbers are always formatted the same way, regardless of the player's locale.
+    library.insert("format_invariant".to_string(), returning(&[Number], String, |parameters| {
+        YarnValue::Number(parameters[0].as_number()).as_string().into()
+    }));
+}
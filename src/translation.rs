@@ -0,0 +1,420 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::{LineInfo, LocaleContext, MessageSyntax};
+use crate::translation_check::{check_line, read_translation, PluralCategories, TranslationIssue};
+
+/// The version of XLIFF to export a string table to. Both versions can be imported.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum XliffVersion {
+    V1_2,
+    V2_0,
+}
+
+/// A problem with a translation file, or with one of the translations in it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TranslationError {
+    /// The file couldn't be read.
+    Syntax(String),
+    /// The file has a translation for a line that isn't in the string table.
+    UnknownLine(String),
+    /// The translation doesn't have the same `{0}` substitution markers, format functions or
+    /// markup as the line, or its plural and ordinal functions don't have keys for the plural
+    /// categories of the translation's locale. Select functions must keep their keys.
+    InvalidTranslation(TranslationIssue),
+}
+
+impl fmt::Display for TranslationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Syntax(message) => write!(f, "Could not read translations: {}", message),
+            Self::UnknownLine(line_id) => write!(f, "Line {} is not in the string table", line_id),
+            Self::InvalidTranslation(issue) => write!(f, "{}", issue),
+        }
+    }
+}
+
+impl Error for TranslationError {}
+
+/// Exports a string table to XLIFF, for translating it with a CAT tool.
+///
+/// Lines are keyed by their IDs, and their node, file and line number are added as notes for
+/// the translator. Lines that are already translated in `translation` get their translation as
+/// the target.
+pub fn export_xliff(
+    string_table: &[LineInfo],
+    translation: &[LineInfo],
+    source_locale: &str,
+    target_locale: &str,
+    version: XliffVersion,
+) -> String {
+    let translations = translation_map(translation);
+
+    let mut xliff = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    match version {
+        XliffVersion::V1_2 => xliff.push_str("<xliff version=\"1.2\" xmlns=\"urn:oasis:names:tc:xliff:document:1.2\">\n"),
+        XliffVersion::V2_0 => xliff.push_str(&format!(
+            "<xliff version=\"2.0\" xmlns=\"urn:oasis:names:tc:xliff:document:2.0\" srcLang=\"{}\" trgLang=\"{}\">\n",
+            escape_xml(source_locale),
+            escape_xml(target_locale),
+        )),
+    }
+
+    for (i, (file, lines)) in group_by_file(string_table).into_iter().enumerate() {
+        match version {
+            XliffVersion::V1_2 => xliff.push_str(&format!(
+                "  <file original=\"{}\" source-language=\"{}\" target-language=\"{}\" datatype=\"plaintext\">\n    <body>\n",
+                escape_xml(file),
+                escape_xml(source_locale),
+                escape_xml(target_locale),
+            )),
+            XliffVersion::V2_0 => xliff.push_str(&format!(
+                "  <file id=\"f{}\" original=\"{}\">\n",
+                i + 1,
+                escape_xml(file),
+            )),
+        }
+
+        for line_info in lines {
+            let target = translations.get(line_info.id.as_str())
+                .map(|text| format!("<target>{}</target>", escape_xml(text)))
+                .unwrap_or_default();
+            match version {
+                XliffVersion::V1_2 => {
+                    xliff.push_str(&format!("      <trans-unit id=\"{}\">\n", escape_xml(&line_info.id)));
+                    xliff.push_str(&format!("        <source>{}</source>\n", escape_xml(&line_info.text)));
+                    if !target.is_empty() {
+                        xliff.push_str(&format!("        {}\n", target));
+                    }
+                    xliff.push_str(&format!("        <note>{}</note>\n", escape_xml(&translator_note(line_info))));
+                    xliff.push_str("      </trans-unit>\n");
+                }
+                XliffVersion::V2_0 => {
+                    xliff.push_str(&format!("    <unit id=\"{}\">\n", escape_xml(&line_info.id)));
+                    xliff.push_str(&format!(
+                        "      <notes>\n        <note category=\"location\">{}</note>\n      </notes>\n",
+                        escape_xml(&translator_note(line_info)),
+                    ));
+                    xliff.push_str("      <segment>\n");
+                    xliff.push_str(&format!("        <source>{}</source>\n", escape_xml(&line_info.text)));
+                    if !target.is_empty() {
+                        xliff.push_str(&format!("        {}\n", target));
+                    }
+                    xliff.push_str("      </segment>\n");
+                    xliff.push_str("    </unit>\n");
+                }
+            }
+        }
+
+        match version {
+            XliffVersion::V1_2 => xliff.push_str("    </body>\n  </file>\n"),
+            XliffVersion::V2_0 => xliff.push_str("  </file>\n"),
+        }
+    }
+
+    xliff.push_str("</xliff>\n");
+    xliff
+}
+
+/// Imports the translations in an XLIFF 1.2 or 2.0 file, returning the string table for the
+/// translated locale. Lines without a translation are left out.
+///
+/// Every translation is checked against the line in `string_table` with the same ID and the
+/// plural categories of `locale`, and all of the problems that were found are returned.
+/// Translations written in ICU MessageFormat are converted to format functions first.
+pub fn import_xliff(
+    input: &str,
+    string_table: &[LineInfo],
+    locale: &LocaleContext,
+    syntax: MessageSyntax,
+) -> Result<Vec<LineInfo>, Vec<TranslationError>> {
+    let document = roxmltree::Document::parse(input)
+        .map_err(|error| vec![TranslationError::Syntax(error.to_string())])?;
+    let root = document.root_element();
+    if root.tag_name().name() != "xliff" {
+        return Err(vec![TranslationError::Syntax(format!("Expected an xliff element, found {}", root.tag_name().name()))]);
+    }
+    let unit_name = match root.attribute("version") {
+        Some("1.2") => "trans-unit",
+        Some("2.0") => "unit",
+        version => return Err(vec![TranslationError::Syntax(format!("Unsupported XLIFF version {:?}", version))]),
+    };
+
+    let translations = root.descendants()
+        .filter(|node| node.has_tag_name(unit_name))
+        .filter_map(|unit| {
+            let id = unit.attribute("id")?;
+            // XLIFF 2.0 splits units into segments, each of which has its own target. Targets
+            // nested anywhere else, like the suggestions in an XLIFF 1.2 `alt-trans`, aren't the
+            // translation.
+            let segments: Vec<_> = match unit_name {
+                "unit" => unit.children().filter(|node| node.has_tag_name("segment")).collect(),
+                _ => vec![unit],
+            };
+            let text: String = segments.iter()
+                .flat_map(|segment| segment.children().filter(|node| node.has_tag_name("target")))
+                .flat_map(|target| target.descendants().filter(|node| node.is_text()).filter_map(|node| node.text()))
+                .collect();
+            Some((id.to_string(), text))
+        });
+    import_translations(translations, string_table, locale, syntax)
+}
+
+/// Exports a string table to a gettext `.po` file, for translating it with a CAT tool.
+///
+/// Lines are keyed by their IDs, which are used as the entries' contexts. Their node is added as
+/// a comment for the translator, and their file and line number as a reference. Lines that are
+/// already translated in `translation` get their translation as the `msgstr`.
+pub fn export_po(string_table: &[LineInfo], translation: &[LineInfo], target_locale: &str) -> String {
+    let translations = translation_map(translation);
+
+    let mut po = String::new();
+    po.push_str("msgid \"\"\nmsgstr \"\"\n");
+    po.push_str(&format!("\"Language: {}\\n\"\n", escape_po(target_locale)));
+    po.push_str("\"MIME-Version: 1.0\\n\"\n");
+    po.push_str("\"Content-Type: text/plain; charset=UTF-8\\n\"\n");
+    po.push_str("\"Content-Transfer-Encoding: 8bit\\n\"\n");
+
+    for line_info in string_table {
+        po.push('\n');
+        // Comments end at the end of the line, so they're escaped like strings.
+        po.push_str(&format!("#. Node: {}\n", escape_po(&line_info.node)));
+        po.push_str(&format!("#: {}:{}\n", escape_po(&line_info.file), line_info.line_number));
+        po.push_str(&format!("msgctxt \"{}\"\n", escape_po(&line_info.id)));
+        po.push_str(&format!("msgid \"{}\"\n", escape_po(&line_info.text)));
+        let translated = translations.get(line_info.id.as_str()).copied().unwrap_or_default();
+        po.push_str(&format!("msgstr \"{}\"\n", escape_po(translated)));
+    }
+
+    po
+}
+
+/// Imports the translations in a gettext `.po` file, returning the string table for the
+/// translated locale. Lines without a translation, and fuzzy translations, are left out.
+///
+/// Translations are checked and converted the same way as in [`import_xliff`].
+pub fn import_po(
+    input: &str,
+    string_table: &[LineInfo],
+    locale: &LocaleContext,
+    syntax: MessageSyntax,
+) -> Result<Vec<LineInfo>, Vec<TranslationError>> {
+    let entries = parse_po(input).map_err(|error| vec![error])?;
+    let translations = entries.into_iter()
+        .filter(|entry| !entry.is_fuzzy)
+        .filter_map(|entry| Some((entry.context?, entry.translation)));
+    import_translations(translations, string_table, locale, syntax)
+}
+
+fn import_translations(
+    translations: impl Iterator<Item = (String, String)>,
+    string_table: &[LineInfo],
+    locale: &LocaleContext,
+    syntax: MessageSyntax,
+) -> Result<Vec<LineInfo>, Vec<TranslationError>> {
+    let categories = PluralCategories::new(locale);
+    let lines: HashMap<&str, &LineInfo> = string_table.iter()
+        .map(|line_info| (line_info.id.as_str(), line_info))
+        .collect();
+
+    let mut errors = Vec::new();
+    let mut translated = HashMap::new();
+    for (line_id, translation) in translations {
+        if translation.is_empty() {
+            continue;
+        }
+        let line_info = match lines.get(line_id.as_str()) {
+            Some(line_info) => line_info,
+            None => {
+                errors.push(TranslationError::UnknownLine(line_id));
+                continue;
+            }
+        };
+        let translation = match read_translation(&line_id, &translation, syntax) {
+            Ok(translation) => translation,
+            Err(issue) => {
+                errors.push(TranslationError::InvalidTranslation(issue));
+                continue;
+            }
+        };
+        let issues = check_line(&line_id, &line_info.text, &translation, &categories);
+        if issues.is_empty() {
+            translated.insert(line_id, translation);
+        } else {
+            errors.extend(issues.into_iter().map(TranslationError::InvalidTranslation));
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    // Keep the order of the string table, rather than the order of the file.
+    Ok(string_table.iter()
+        .filter_map(|line_info| Some(LineInfo {
+            text: translated.remove(&line_info.id)?,
+            ..line_info.clone()
+        }))
+        .collect())
+}
+
+fn translation_map(translation: &[LineInfo]) -> HashMap<&str, &str> {
+    translation.iter()
+        .map(|line_info| (line_info.id.as_str(), line_info.text.as_str()))
+        .collect()
+}
+
+/// Groups lines by the file they're in, keeping the order the files first appear in.
+fn group_by_file(string_table: &[LineInfo]) -> Vec<(&str, Vec<&LineInfo>)> {
+    let mut files: Vec<(&str, Vec<&LineInfo>)> = Vec::new();
+    for line_info in string_table {
+        match files.iter_mut().find(|(file, _)| *file == line_info.file) {
+            Some((_, lines)) => lines.push(line_info),
+            None => files.push((&line_info.file, vec![line_info])),
+        }
+    }
+    files
+}
+
+fn translator_note(line_info: &LineInfo) -> String {
+    format!("Node: {}, file: {}, line: {}", line_info.node, line_info.file, line_info.line_number)
+}
+
+fn escape_xml(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn escape_po(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[derive(Default)]
+struct PoEntry {
+    context: Option<String>,
+    translation: String,
+    is_fuzzy: bool,
+}
+
+#[derive(Copy, Clone)]
+enum PoField {
+    Context,
+    Id,
+    Translation,
+}
+
+/// Reads the entries in a `.po` file. Only the parts that are needed to import translations are
+/// kept, and plural forms aren't supported, since lines use format functions instead.
+fn parse_po(input: &str) -> Result<Vec<PoEntry>, TranslationError> {
+    let syntax_error = |line_number: usize, message: &str| {
+        TranslationError::Syntax(format!("Line {}: {}", line_number, message))
+    };
+
+    let mut entries = Vec::new();
+    let mut entry = PoEntry::default();
+    let mut field = None;
+
+    for (i, line) in input.lines().enumerate() {
+        let line = line.trim();
+
+        // Comments and new keywords after a translation start the next entry.
+        let starts_entry = line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with("msgctxt")
+            || line.starts_with("msgid");
+        if starts_entry && matches!(field, Some(PoField::Translation)) {
+            entries.push(std::mem::take(&mut entry));
+            field = None;
+        }
+
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(flags) = line.strip_prefix("#,") {
+            if flags.split(',').any(|flag| flag.trim() == "fuzzy") {
+                entry.is_fuzzy = true;
+            }
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+
+        let (keyword, string) = match line.find(char::is_whitespace) {
+            Some(end) if !line.starts_with('"') => (Some(&line[..end]), line[end..].trim_start()),
+            _ => (None, line),
+        };
+        let value = unescape_po(string)
+            .ok_or_else(|| syntax_error(i + 1, "Expected a quoted string"))?;
+
+        match keyword {
+            Some("msgctxt") => {
+                entry.context = Some(value);
+                field = Some(PoField::Context);
+            }
+            Some("msgid") => field = Some(PoField::Id),
+            Some("msgstr") => {
+                entry.translation = value;
+                field = Some(PoField::Translation);
+            }
+            Some(keyword) if keyword == "msgid_plural" || keyword.starts_with("msgstr[") => {
+                return Err(syntax_error(i + 1, "Plural forms aren't supported, use format functions instead"));
+            }
+            Some(keyword) => return Err(syntax_error(i + 1, &format!("Unknown keyword {}", keyword))),
+            // A string on its own continues the previous one.
+            None => match field {
+                Some(PoField::Context) => {
+                    if let Some(context) = &mut entry.context {
+                        context.push_str(&value);
+                    }
+                }
+                Some(PoField::Id) => {}
+                Some(PoField::Translation) => entry.translation.push_str(&value),
+                None => return Err(syntax_error(i + 1, "Expected a keyword")),
+            },
+        }
+    }
+
+    if field.is_some() {
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn unescape_po(s: &str) -> Option<String> {
+    let s = s.strip_prefix('"')?.strip_suffix('"')?;
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'n' => unescaped.push('\n'),
+            't' => unescaped.push('\t'),
+            escaped => unescaped.push(escaped),
+        }
+    }
+    Some(unescaped)
+}
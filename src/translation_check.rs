@@ -0,0 +1,388 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+use intl_pluralrules::PluralRules;
+use serde::Serialize;
+
+use crate::{icu_to_format_functions, LineInfo, LocaleContext, MessageSyntax};
+use crate::utils::{get_plural_case_str, parse_format_functions, FormatFunctionError, FormatFunctionKind, ParsedFormatFunction};
+
+/// A problem found in a translated string table by [`check_translations`].
+///
+/// Serializes to JSON as an object with a `kind` field naming the problem, along with the fields
+/// of the variant.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TranslationIssue {
+    /// The line isn't translated.
+    UntranslatedLine {
+        line_id: String,
+    },
+    /// The translation is for a line that is no longer in the base string table.
+    StaleLine {
+        line_id: String,
+    },
+    /// The translation is missing a substitution placeholder that the line has.
+    MissingPlaceholder {
+        line_id: String,
+        placeholder: String,
+    },
+    /// The translation has a substitution placeholder that the line doesn't have.
+    ExtraPlaceholder {
+        line_id: String,
+        placeholder: String,
+    },
+    /// A format function in the translation is malformed.
+    InvalidFormatFunction {
+        line_id: String,
+        error: String,
+    },
+    /// The translation doesn't have the same format functions as the line, or a select function
+    /// has different keys.
+    FormatFunctionMismatch {
+        line_id: String,
+        expected: Vec<String>,
+        found: Vec<String>,
+    },
+    /// The translation doesn't have the same markup tags as the line, like `[b]`, `[/b]` or
+    /// `[pause/]`.
+    MarkupMismatch {
+        line_id: String,
+        expected: Vec<String>,
+        found: Vec<String>,
+    },
+    /// A plural or ordinal function in the translation has a key that isn't a plural category
+    /// of the translation's locale.
+    InvalidPluralCategory {
+        line_id: String,
+        category: String,
+    },
+    /// A plural or ordinal function in the translation doesn't have a key for one of the plural
+    /// categories of the translation's locale.
+    MissingPluralCategory {
+        line_id: String,
+        category: String,
+    },
+}
+
+impl fmt::Display for TranslationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UntranslatedLine { line_id } => {
+                write!(f, "Line {} is not translated", line_id)
+            }
+            Self::StaleLine { line_id } => {
+                write!(f, "Line {} is translated, but is no longer in the base string table", line_id)
+            }
+            Self::MissingPlaceholder { line_id, placeholder } => {
+                write!(f, "Translation of line {} is missing placeholder {}", line_id, placeholder)
+            }
+            Self::ExtraPlaceholder { line_id, placeholder } => {
+                write!(f, "Translation of line {} has extra placeholder {}", line_id, placeholder)
+            }
+            Self::InvalidFormatFunction { line_id, error } => {
+                write!(f, "Translation of line {}: {}", line_id, error)
+            }
+            Self::FormatFunctionMismatch { line_id, expected, found } => {
+                write!(
+                    f,
+                    "Translation of line {} has format functions [{}], expected [{}]",
+                    line_id,
+                    found.join(", "),
+                    expected.join(", "),
+                )
+            }
+            Self::MarkupMismatch { line_id, expected, found } => {
+                write!(
+                    f,
+                    "Translation of line {} has markup [{}], expected [{}]",
+                    line_id,
+                    found.join(", "),
+                    expected.join(", "),
+                )
+            }
+            Self::InvalidPluralCategory { line_id, category } => {
+                write!(f, "Translation of line {} uses plural category {}, which the locale doesn't have", line_id, category)
+            }
+            Self::MissingPluralCategory { line_id, category } => {
+                write!(f, "Translation of line {} has no text for plural category {}", line_id, category)
+            }
+        }
+    }
+}
+
+/// Checks a translated string table against the base string table it was translated from.
+///
+/// Every line in the base string table should be translated, with the same substitution
+/// placeholders, format functions and markup tags. Plural and ordinal functions should have a
+/// key for every plural category of the translation's locale, and no others. Format functions
+/// nested in the branches of other functions are checked too.
+///
+/// Translations written in ICU MessageFormat are converted to format functions before they're
+/// checked.
+///
+/// Problems are returned in the order of the base string table, followed by stale lines.
+pub fn check_translations(
+    base: &[LineInfo],
+    translation: &[LineInfo],
+    locale: &LocaleContext,
+    syntax: MessageSyntax,
+) -> Vec<TranslationIssue> {
+    let categories = PluralCategories::new(locale);
+
+    let translations: HashMap<&str, &str> = translation.iter()
+        .map(|line_info| (line_info.id.as_str(), line_info.text.as_str()))
+        .collect();
+
+    let mut issues = Vec::new();
+    for line_info in base {
+        let line_id = &line_info.id;
+        let translated = match translations.get(line_id.as_str()) {
+            Some(translated) if !translated.is_empty() => translated,
+            _ => {
+                issues.push(TranslationIssue::UntranslatedLine { line_id: line_id.clone() });
+                continue;
+            }
+        };
+        match read_translation(line_id, translated, syntax) {
+            Ok(translated) => issues.extend(check_line(line_id, &line_info.text, &translated, &categories)),
+            Err(issue) => issues.push(issue),
+        }
+    }
+
+    let base_ids: BTreeSet<&str> = base.iter().map(|line_info| line_info.id.as_str()).collect();
+    for line_info in translation {
+        if !base_ids.contains(line_info.id.as_str()) {
+            issues.push(TranslationIssue::StaleLine { line_id: line_info.id.clone() });
+        }
+    }
+
+    issues
+}
+
+/// Converts a translation to format functions, if it's written in ICU MessageFormat.
+pub(crate) fn read_translation(line_id: &str, translated: &str, syntax: MessageSyntax) -> Result<String, TranslationIssue> {
+    match syntax {
+        MessageSyntax::FormatFunctions => Ok(translated.to_string()),
+        MessageSyntax::Icu => icu_to_format_functions(translated)
+            .map_err(|error| TranslationIssue::InvalidFormatFunction { line_id: line_id.to_string(), error: error.to_string() }),
+    }
+}
+
+/// The plural categories of a translation's locale, which its plural and ordinal functions
+/// should have keys for.
+pub(crate) struct PluralCategories {
+    cardinal: BTreeSet<&'static str>,
+    ordinal: BTreeSet<&'static str>,
+}
+
+impl PluralCategories {
+    pub(crate) fn new(locale: &LocaleContext) -> Self {
+        Self {
+            cardinal: plural_categories(locale.cardinal_rules()),
+            ordinal: plural_categories(locale.ordinal_rules()),
+        }
+    }
+}
+
+/// Checks that a translation has the same substitution placeholders, format functions and
+/// markup as the line it translates, and that its plural and ordinal functions, including
+/// nested ones, have keys for the locale's plural categories. Translations are checked this way
+/// when they're imported, too.
+pub(crate) fn check_line(line_id: &str, text: &str, translated: &str, categories: &PluralCategories) -> Vec<TranslationIssue> {
+    let mut issues = Vec::new();
+
+    let expected = placeholders(text);
+    let found = placeholders(translated);
+    for placeholder in difference(&expected, &found) {
+        issues.push(TranslationIssue::MissingPlaceholder { line_id: line_id.to_string(), placeholder });
+    }
+    for placeholder in difference(&found, &expected) {
+        issues.push(TranslationIssue::ExtraPlaceholder { line_id: line_id.to_string(), placeholder });
+    }
+
+    match (format_function_structure(text), format_function_structure(translated)) {
+        (_, Err(error)) => {
+            issues.push(TranslationIssue::InvalidFormatFunction { line_id: line_id.to_string(), error: error.to_string() });
+        }
+        // A line that is malformed itself can't be checked against.
+        (Ok(expected), Ok(found)) if expected != found => {
+            issues.push(TranslationIssue::FormatFunctionMismatch { line_id: line_id.to_string(), expected, found });
+        }
+        _ => {}
+    }
+
+    // Malformed format functions have already been reported.
+    for function in all_format_functions(translated).unwrap_or_default() {
+        let categories = match function.kind {
+            FormatFunctionKind::Select => continue,
+            FormatFunctionKind::Plural => &categories.cardinal,
+            FormatFunctionKind::Ordinal => &categories.ordinal,
+        };
+        let keys: BTreeSet<&str> = function.data.keys().map(String::as_str).collect();
+        let invalid = keys.difference(categories)
+            .map(|&key| TranslationIssue::InvalidPluralCategory { line_id: line_id.to_string(), category: key.to_string() });
+        let missing = categories.difference(&keys)
+            .map(|&category| TranslationIssue::MissingPluralCategory { line_id: line_id.to_string(), category: category.to_string() });
+        for issue in invalid.chain(missing) {
+            // Nested functions that are repeated in several branches are only reported once.
+            if !issues.contains(&issue) {
+                issues.push(issue);
+            }
+        }
+    }
+
+    // Markup inside format functions isn't checked, since their text differs between locales.
+    if let (Ok((text, _)), Ok((translated, _))) = (parse_format_functions(text), parse_format_functions(translated)) {
+        let expected = markup(&text);
+        let found = markup(&translated);
+        if expected != found {
+            issues.push(TranslationIssue::MarkupMismatch { line_id: line_id.to_string(), expected, found });
+        }
+    }
+
+    issues
+}
+
+/// The `{0}`, `{1}`... substitution markers in a line, sorted, since translations can reorder
+/// them.
+fn placeholders(text: &str) -> Vec<String> {
+    let mut placeholders = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('{') {
+        rest = &rest[start + 1..];
+        if let Some(end) = rest.find('}') {
+            if end > 0 && rest[..end].bytes().all(|b| b.is_ascii_digit()) {
+                placeholders.push(format!("{{{}}}", &rest[..end]));
+                rest = &rest[end + 1..];
+            }
+        }
+    }
+    placeholders.sort();
+    placeholders
+}
+
+/// The markup tags in a line, like `[b]`, `[/b]`, `[pause/]` or `[/]`, sorted, since
+/// translations can reorder them. Attributes are left out, since their values may be
+/// translated. Brackets escaped with `\` and brackets that don't hold a tag name are text.
+fn markup(text: &str) -> Vec<String> {
+    let mut tags = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('[') {
+        let escaped = rest[..start].ends_with('\\');
+        rest = &rest[start + 1..];
+        let end = match rest.find(']') {
+            Some(end) if !escaped => end,
+            _ => continue,
+        };
+        let tag = rest[..end].trim();
+        let (closing, tag) = match tag.strip_prefix('/') {
+            Some(tag) => (true, tag.trim_start()),
+            None => (false, tag),
+        };
+        let (self_closing, tag) = match tag.strip_suffix('/') {
+            Some(tag) => (true, tag.trim_end()),
+            None => (false, tag),
+        };
+        let name: String = tag.chars().take_while(|&c| c.is_alphanumeric() || c == '_').collect();
+        let starts_with_letter = name.starts_with(|c: char| c.is_alphabetic() || c == '_');
+        let tag = match (closing, self_closing) {
+            (true, false) if tag.is_empty() => "[/]".to_string(),
+            (true, false) if starts_with_letter && name.len() == tag.len() => format!("[/{}]", name),
+            (false, true) if starts_with_letter => format!("[{}/]", name),
+            (false, false) if starts_with_letter => format!("[{}]", name),
+            _ => continue,
+        };
+        tags.push(tag);
+        rest = &rest[end + 1..];
+    }
+    tags.sort();
+    tags
+}
+
+/// Describes the format functions in a line, sorted, since translations can reorder them.
+///
+/// Functions nested in a select function's branches are described along with the key of their
+/// branch. Plural and ordinal functions have different keys in different locales, so the
+/// functions nested in any of their branches are described together.
+fn format_function_structure(text: &str) -> Result<Vec<String>, FormatFunctionError> {
+    let (_, functions) = parse_format_functions(text)?;
+    let mut structure = Vec::new();
+    for function in &functions {
+        let mut branches: Vec<(&String, &String)> = function.data.iter().collect();
+        branches.sort_unstable();
+
+        let description = match function.kind {
+            FormatFunctionKind::Select => {
+                let keys: Vec<&str> = branches.iter().map(|(key, _)| key.as_str()).collect();
+                format!("select \"{}\" ({})", function.value, keys.join(", "))
+            }
+            FormatFunctionKind::Plural => format!("plural \"{}\"", function.value),
+            FormatFunctionKind::Ordinal => format!("ordinal \"{}\"", function.value),
+        };
+
+        let mut nested = Vec::new();
+        for (key, branch) in branches {
+            for inner in format_function_structure(branch)? {
+                nested.push(match function.kind {
+                    FormatFunctionKind::Select => format!("{} {}: {}", description, key, inner),
+                    FormatFunctionKind::Plural | FormatFunctionKind::Ordinal => format!("{}: {}", description, inner),
+                });
+            }
+        }
+        if function.kind != FormatFunctionKind::Select {
+            nested.sort();
+            nested.dedup();
+        }
+
+        structure.push(description);
+        structure.extend(nested);
+    }
+    structure.sort();
+    Ok(structure)
+}
+
+/// The format functions in a line, followed by the ones nested in their branches.
+fn all_format_functions(text: &str) -> Result<Vec<ParsedFormatFunction>, FormatFunctionError> {
+    let (_, mut functions) = parse_format_functions(text)?;
+    let mut i = 0;
+    while i < functions.len() {
+        let mut branches: Vec<&String> = functions[i].data.values().collect();
+        branches.sort_unstable();
+        let mut nested = Vec::new();
+        for branch in branches {
+            nested.extend(parse_format_functions(branch)?.1);
+        }
+        functions.extend(nested);
+        i += 1;
+    }
+    Ok(functions)
+}
+
+/// Finds the plural categories that a locale uses, by trying numbers that cover every rule in
+/// CLDR. Decimals matter, since some categories are only used for numbers with fractions.
+fn plural_categories(rules: &PluralRules) -> BTreeSet<&'static str> {
+    let integers = (0..=200).chain([1000, 10_000, 100_000, 1_000_000].iter().copied())
+        .map(|n: u64| rules.select(n));
+    let decimals = ["0.0", "0.5", "1.0", "1.5", "2.0", "2.5", "3.5", "10.0", "100.5"].iter()
+        .map(|&n| rules.select(n));
+    integers.chain(decimals)
+        .filter_map(Result::ok)
+        .map(get_plural_case_str)
+        .collect()
+}
+
+/// The items in `a` that aren't in `b`, counting repeated items.
+fn difference(a: &[String], b: &[String]) -> Vec<String> {
+    let mut remaining = b.to_vec();
+    a.iter()
+        .filter(|item| match remaining.iter().position(|other| other == *item) {
+            Some(i) => {
+                remaining.remove(i);
+                false
+            }
+            None => true,
+        })
+        .cloned()
+        .collect()
+}
@@ -1,9 +1,13 @@
 use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
 use std::iter::Peekable;
-use std::str::Chars;
 
-use intl_pluralrules::{PluralCategory, PluralRules, PluralRuleType};
-use unic_langid::LanguageIdentifier;
+use intl_pluralrules::{PluralCategory, PluralRules};
+use unicode_segmentation::{Graphemes, UnicodeSegmentation};
+
+use crate::LocaleContext;
+use crate::locale::is_isolate_mark;
 
 const FORMAT_FUNCTION_VALUE_PLACEHOLDER: &str = "<VALUE PLACEHOLDER>";
 
@@ -11,49 +15,75 @@ const FORMAT_FUNCTION_VALUE_PLACEHOLDER: &str = "<VALUE PLACEHOLDER>";
 /// in a given string, using pluralisation rules specified by the given locale (as an IETF
 /// BCP-47 language tag).
 ///
+/// When a function has no text for the value, it uses the text for `other`, if it has one.
+/// The text can contain format functions of its own, which are expanded too:
+///
+/// ```text
+/// [select "{0}" f="She has [plural "{1}" one="a cat" other="% cats"]" other="They have pets"]
+/// ```
+///
+/// Brackets that don't start a `select`, `plural` or `ordinal` function, like markup, are left
+/// as they are.
+///
+/// This looks up the locale's plural rules every time it's called. When expanding many lines,
+/// create a [`LocaleContext`](crate::LocaleContext) once and use its
+/// [`expand`](crate::LocaleContext::expand) method instead.
+///
 /// # Panics
-/// When the string contains a `plural` or `ordinal` format function, but the
-/// specified value cannot be parsed as a number.
+/// When the locale isn't supported, a format function in the string is malformed, or it
+/// contains a `plural` or `ordinal` format function, but the specified value cannot be parsed
+/// as a number.
 pub fn expand_format_functions(input: &str, locale_code: &str) -> String {
-    let (mut line_with_replacements, format_functions) = parse_format_functions(input);
+    let context = LocaleContext::new(locale_code)
+        .unwrap_or_else(|error| panic!("{}", error));
+    context.expand(input)
+}
 
-    let langid: LanguageIdentifier = locale_code.parse().unwrap();
+pub(crate) fn expand_with_rules(input: &str, cardinal_rules: &PluralRules, ordinal_rules: &PluralRules) -> String {
+    try_expand_with_rules(input, cardinal_rules, ordinal_rules)
+        .unwrap_or_else(|error| panic!("{} in line \"{}\"", error, input))
+}
 
-    let ordinal_rules = PluralRules::create(langid.clone(), PluralRuleType::ORDINAL).unwrap();
-    let cardinal_rules = PluralRules::create(langid.clone(), PluralRuleType::CARDINAL).unwrap();
+pub(crate) fn try_expand_with_rules(
+    input: &str,
+    cardinal_rules: &PluralRules,
+    ordinal_rules: &PluralRules,
+) -> Result<String, FormatFunctionError> {
+    let (mut line_with_replacements, format_functions) = parse_format_functions(input)?;
 
     for (i, function) in format_functions.iter().enumerate() {
+        // A substitution may have been wrapped in isolate marks, which aren't part of its value.
+        let value = function.value.trim_matches(is_isolate_mark);
+        let number = || value.parse::<f64>().map_err(|_| FormatFunctionError::NotANumber(value.to_string()));
+
         // Get the key str to look up in the function data.
         let data_key = match function.kind {
-            FormatFunctionKind::Select => &function.value,
-            FormatFunctionKind::Plural => {
-                let value: f64 = function.value.parse()
-                    .unwrap_or_else(|_| panic!("Error while pluralising line '{}': '{}' is not a number", input, &function.value));
-                let plural_case = cardinal_rules.select(value).unwrap();
-                get_plural_case_str(plural_case)
-            }
-            FormatFunctionKind::Ordinal => {
-                let value: f64 = function.value.parse()
-                    .unwrap_or_else(|_| panic!("Error while pluralising line '{}': '{}' is not a number", input, &function.value));
-                let plural_case = ordinal_rules.select(value).unwrap();
-                get_plural_case_str(plural_case)
-            }
+            FormatFunctionKind::Select => value,
+            FormatFunctionKind::Plural => get_plural_case_str(cardinal_rules.select(number()?).unwrap()),
+            FormatFunctionKind::Ordinal => get_plural_case_str(ordinal_rules.select(number()?).unwrap()),
         };
 
         let mut replacement = function.data.get(data_key)
+            .or_else(|| function.data.get("other"))
             .cloned()
             .unwrap_or_else(|| format!("<no replacement for {}>", data_key));
 
+        // Expand any format functions nested inside the replacement, before the value is
+        // inserted so that the value itself is never parsed. Other brackets are left as text.
+        if replacement.contains('[') {
+            replacement = try_expand_with_rules(&replacement, cardinal_rules, ordinal_rules)?;
+        }
+
         // Insert the value if needed
         replacement = replacement.replace(FORMAT_FUNCTION_VALUE_PLACEHOLDER, &function.value);
 
         line_with_replacements = line_with_replacements.replacen(&format!("{{{}}}", i), &replacement, 1);
     }
 
-    line_with_replacements
+    Ok(line_with_replacements)
 }
 
-fn get_plural_case_str(plural_case: PluralCategory) -> &'static str {
+pub(crate) fn get_plural_case_str(plural_case: PluralCategory) -> &'static str {
     match plural_case {
         PluralCategory::ZERO => "zero",
         PluralCategory::ONE => "one",
@@ -64,74 +94,100 @@ fn get_plural_case_str(plural_case: PluralCategory) -> &'static str {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
-enum FormatFunctionKind {
-    Select,
-    Plural,
-    Ordinal,
+/// A problem with the syntax of a format function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormatFunctionError {
+    /// Something other than what the function's syntax needs next was found.
+    Expected(String),
+    /// The line ended in the middle of a function.
+    UnexpectedEnd,
+    /// The function has the same key more than once.
+    DuplicateKey(String),
+    /// The ICU message uses syntax that format functions have no equivalent for.
+    UnsupportedIcu(String),
+    /// The value of a `plural` or `ordinal` function isn't a number.
+    NotANumber(String),
 }
 
-impl Default for FormatFunctionKind {
-    fn default() -> Self {
-        Self::Select
+impl fmt::Display for FormatFunctionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Expected(expected) => write!(f, "Expected {} inside a format function", expected),
+            Self::UnexpectedEnd => write!(f, "Unexpected end of line inside a format function"),
+            Self::DuplicateKey(key) => write!(f, "Duplicate value '{}' in format function", key),
+            Self::UnsupportedIcu(syntax) => write!(f, "Unsupported ICU message syntax: {}", syntax),
+            Self::NotANumber(value) => write!(f, "'{}' is not a number, so it can't be pluralised", value),
+        }
     }
 }
 
+impl Error for FormatFunctionError {}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) enum FormatFunctionKind {
+    #[default]
+    Select,
+    Plural,
+    Ordinal,
+}
+
 #[derive(Debug, Default)]
-struct ParsedFormatFunction {
-    kind: FormatFunctionKind,
-    value: String,
-    data: HashMap<String, String>,
+pub(crate) struct ParsedFormatFunction {
+    pub kind: FormatFunctionKind,
+    pub value: String,
+    pub data: HashMap<String, String>,
 }
 
-fn parse_format_functions(input: &str) -> (String, Vec<ParsedFormatFunction>) {
-    // TODO: Do we wanna iterate over grapheme clusters instead??
-    let mut chars = input.chars().peekable();
+/// Replaces each format function in a line with a `{0}`, `{1}`... placeholder, returning the
+/// line and the functions in order.
+///
+/// The line is read a grapheme cluster at a time, so a combining mark after a quote or bracket
+/// belongs to that character instead of being mistaken for syntax on its own.
+pub(crate) fn parse_format_functions(input: &str) -> Result<(String, Vec<ParsedFormatFunction>), FormatFunctionError> {
+    let mut graphemes = input.graphemes(true).peekable();
 
     let mut line_with_replacements = String::with_capacity(input.len());
 
     let mut parsed_functions = Vec::new();
 
     // Read the entirety of the line
-    while let Some(c) = chars.next() {
-        if c != '[' {
-            // plain text!
-            line_with_replacements.push(c);
-            continue;
-        }
+    while let Some(g) = graphemes.next() {
+        let kind = match format_function_kind(g, &graphemes) {
+            Some(kind) => kind,
+            None => {
+                // plain text, including brackets that aren't a format function, like markup!
+                line_with_replacements.push_str(g);
+                continue;
+            }
+        };
 
         // the start of a format function!
 
         // Structure of a format function:
         // [ name "value" key1="value1" key2="value2" ]
 
-        // Ensure that only valid function names are used
-        let kind = match expect_id(&mut chars).as_ref() {
-            "select" => FormatFunctionKind::Select,
-            "plural" => FormatFunctionKind::Plural,
-            "ordinal" => FormatFunctionKind::Ordinal,
-            name => panic!("Invalid formatting function {} in line \"{}\"", name, input),
-        };
+        // Skip the name, which has already been read
+        expect_id(&mut graphemes)?;
 
-        let value = expect_string(&mut chars);
+        let value = expect_string(&mut graphemes)?;
 
         // parse and read the data for this format function
         let mut data = HashMap::new();
         loop {
-            consume_whitespace(&mut chars, false);
+            consume_whitespace(&mut graphemes, false)?;
 
-            if let Some(']') = chars.peek() {
+            if let Some(&"]") = graphemes.peek() {
                 // we're done adding parameters
                 break;
             }
 
             // this is a key-value pair
-            let key = expect_id(&mut chars);
-            expect_character(&mut chars, '=');
-            let value = expect_string(&mut chars);
+            let key = expect_id(&mut graphemes)?;
+            expect_grapheme(&mut graphemes, "=")?;
+            let value = expect_string(&mut graphemes)?;
 
             if data.contains_key(&key) {
-                panic!("Duplicate value '{}' in format function inside line \"{}\"", &key, input)
+                return Err(FormatFunctionError::DuplicateKey(key));
             }
 
             data.insert(key, value);
@@ -144,7 +200,7 @@ fn parse_format_functions(input: &str) -> (String, Vec<ParsedFormatFunction>) {
         };
 
         // We now expect the end of this format function
-        expect_character(&mut chars, ']');
+        expect_grapheme(&mut graphemes, "]")?;
 
         // reached the end of this function; add it to the
         // list
@@ -154,109 +210,139 @@ fn parse_format_functions(input: &str) -> (String, Vec<ParsedFormatFunction>) {
         line_with_replacements.push_str(&format!("{{{}}}", parsed_functions.len() - 1));
     }
 
-    (line_with_replacements, parsed_functions)
+    Ok((line_with_replacements, parsed_functions))
+}
+
+/// If the grapheme is a `[` followed by the name of a format function, returns the kind of
+/// function it starts. The graphemes after it aren't consumed.
+fn format_function_kind(g: &str, graphemes: &Peekable<Graphemes>) -> Option<FormatFunctionKind> {
+    if g != "[" {
+        return None;
+    }
+    match expect_id(&mut graphemes.clone()).ok()?.as_str() {
+        "select" => Some(FormatFunctionKind::Select),
+        "plural" => Some(FormatFunctionKind::Plural),
+        "ordinal" => Some(FormatFunctionKind::Ordinal),
+        _ => None,
+    }
 }
 
 // id = [_\w][\w0-9_]*
-fn expect_id(chars: &mut Peekable<Chars>) -> String {
-    consume_whitespace(chars, false);
+fn expect_id(graphemes: &mut Peekable<Graphemes>) -> Result<String, FormatFunctionError> {
+    consume_whitespace(graphemes, false)?;
 
     let mut id_string = String::new();
 
-    // Read the first character, which must be a letter
-    let mut next_char = chars.next()
-        .unwrap();
+    // Read the first grapheme, which must start with a letter
+    let next = graphemes.next()
+        .ok_or(FormatFunctionError::UnexpectedEnd)?;
 
-    if next_char.is_alphabetic() || next_char == '_' {
-        id_string.push(next_char);
+    if starts_with(next, |c| c.is_alphabetic() || c == '_') {
+        id_string.push_str(next);
     } else {
-        panic!("Expected an identifier inside a format function in line");
+        return Err(FormatFunctionError::Expected("an identifier".to_string()));
     }
 
     // Read zero or more letters, numbers, or underscores
-    loop {
-        let temp_next = chars.peek();
-        if temp_next.is_none() {
-            break;
-        }
-        next_char = *temp_next.unwrap();
-
-        if next_char.is_alphanumeric() || next_char == '_' {
-            id_string.push(next_char);
-            chars.next(); // consume it
+    while let Some(&next) = graphemes.peek() {
+        if starts_with(next, |c| c.is_alphanumeric() || c == '_') {
+            id_string.push_str(next);
+            graphemes.next(); // consume it
         } else {
             // no more
             break;
         }
     }
 
-    id_string
+    Ok(id_string)
 }
 
 // string = " (\"|\\|^["])* "
-fn expect_string(chars: &mut Peekable<Chars>) -> String {
-    consume_whitespace(chars, false);
+fn expect_string(graphemes: &mut Peekable<Graphemes>) -> Result<String, FormatFunctionError> {
+    consume_whitespace(graphemes, false)?;
 
     let mut string = String::new();
 
-    let mut next_char = chars.next().unwrap();
-    if next_char != '"' {
-        panic!("Expected a string inside a format function in line");
+    let mut next = graphemes.next().ok_or(FormatFunctionError::UnexpectedEnd)?;
+    if next != "\"" {
+        return Err(FormatFunctionError::Expected("a string".to_string()));
     }
 
-    loop {
-        next_char = chars.next().unwrap();
+    // Format functions nested inside the string are kept as they are, to be parsed when the
+    // string is expanded, so their quotes and `%`s aren't the outer function's.
+    let mut nesting_depth = 0;
+    let mut in_nested_string = false;
 
-        if next_char == '"' {
+    loop {
+        next = graphemes.next().ok_or(FormatFunctionError::UnexpectedEnd)?;
+
+        if nesting_depth > 0 {
+            string.push_str(next);
+            match next {
+                "\\" if in_nested_string => string.push_str(graphemes.next().ok_or(FormatFunctionError::UnexpectedEnd)?),
+                "\"" => in_nested_string = !in_nested_string,
+                "[" if !in_nested_string => nesting_depth += 1,
+                "]" if !in_nested_string => nesting_depth -= 1,
+                _ => {}
+            }
+        } else if format_function_kind(next, graphemes).is_some() {
+            nesting_depth = 1;
+            string.push_str(next);
+        } else if next == "\"" {
             // end of string - consume it but don't
             // append to the final collection
             break;
-        } else if next_char == '\\' {
+        } else if next == "\\" {
             // an escaped quote or backslash
-            let escaped_char = chars.next().unwrap();
-            if escaped_char == '\\' || escaped_char == '"' || escaped_char == '%' {
-                string.push(escaped_char);
+            let escaped = graphemes.next().ok_or(FormatFunctionError::UnexpectedEnd)?;
+            if escaped == "\\" || escaped == "\"" || escaped == "%" {
+                string.push_str(escaped);
             }
-        } else if next_char == '%' {
+        } else if next == "%" {
             string.push_str(FORMAT_FUNCTION_VALUE_PLACEHOLDER);
         } else {
-            string.push(next_char);
+            string.push_str(next);
         }
 
     }
 
-    string
+    Ok(string)
 }
 
-// Consume a character, and throw an exception if it
+// Consume a grapheme, and return an error if it
 // isn't the one we expect.
-fn expect_character(chars: &mut Peekable<Chars>, expected_char: char) {
-    consume_whitespace(chars, false);
+fn expect_grapheme(graphemes: &mut Peekable<Graphemes>, expected: &str) -> Result<(), FormatFunctionError> {
+    consume_whitespace(graphemes, false)?;
 
-    let next_char = chars.next();
-    if next_char != Some(expected_char) {
-        panic!("Expected a {} inside a format function in line", expected_char);
+    let next = graphemes.next();
+    if next != Some(expected) {
+        return Err(FormatFunctionError::Expected(format!("a {}", expected)));
     }
+    Ok(())
 }
 
 // Read and discard all whitespace until we hit
 // something that isn't whitespace.
-fn consume_whitespace(chars: &mut Peekable<Chars>, allow_end_of_line: bool) {
+fn consume_whitespace(graphemes: &mut Peekable<Graphemes>, allow_end_of_line: bool) -> Result<(), FormatFunctionError> {
     loop {
-        let next_char = chars.peek();
-        if next_char.is_none() && !allow_end_of_line {
-            panic!("Unexpected end of line inside a format function in line");
-        }
-        let next_char = *next_char.unwrap();
+        let next = match graphemes.peek() {
+            Some(&next) => next,
+            None if allow_end_of_line => return Ok(()),
+            None => return Err(FormatFunctionError::UnexpectedEnd),
+        };
 
-        if next_char.is_whitespace() {
+        if next.chars().all(char::is_whitespace) {
             // consume it and continue
-            chars.next();
+            graphemes.next();
         } else {
             // no more whitespace ahead; don't
             // consume it, but instead stop eating
             // whitespace
-            return;
+            return Ok(());
         }
     }
 }
+
+fn starts_with(grapheme: &str, predicate: impl Fn(char) -> bool) -> bool {
+    grapheme.chars().next().is_some_and(predicate)
+}
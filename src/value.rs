@@ -1,13 +1,92 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+/// The types of values that can be passed to commands and functions.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum YarnType {
+    Number,
+    String,
+    Bool,
+    /// Any type of value is accepted.
+    Any,
+}
+
 // TODO: Manually implement PartialEq and PartialOrd to match C# implementation?
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub enum YarnValue {
     Str(String),
     Bool(bool),
     Number(f32),
     Null,
+    /// A case of one of the enums in the VM's [`EnumDeclarations`]. Converts to a string as the
+    /// name of the case.
+    Enum {
+        enum_name: String,
+        case: String,
+    },
+}
+
+/// The enums declared with `<<enum>>`, and the variables declared with an enum type.
+///
+/// Compiled programs don't include declarations: enum cases are compiled to their raw values,
+/// which are the names of the cases, and stored that way. Give the VM the declarations from the
+/// compiler's output to check the values stored in enum variables, and to read them back as
+/// [`YarnValue::Enum`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnumDeclarations {
+    /// The names of each enum's cases, by the name of the enum.
+    pub enums: HashMap<String, Vec<String>>,
+    /// The name of the enum that each enum variable was declared with, by variable name.
+    pub variables: HashMap<String, String>,
+}
+
+impl EnumDeclarations {
+    /// Whether an enum with the given case is declared.
+    pub fn is_case(&self, enum_name: &str, case: &str) -> bool {
+        self.enums.get(enum_name)
+            .is_some_and(|cases| cases.iter().any(|c| c == case))
+    }
+
+    /// Whether a value can be stored in a variable. Values stored in enum variables must be
+    /// cases of the variable's enum, either as a [`YarnValue::Enum`] or as the raw value that
+    /// compiled programs use.
+    pub(crate) fn can_store(&self, variable: &str, value: &YarnValue) -> bool {
+        match (self.variables.get(variable), value) {
+            (None, _) => true,
+            (Some(enum_name), YarnValue::Enum { enum_name: value_enum, case }) => {
+                value_enum == enum_name && self.is_case(enum_name, case)
+            }
+            (Some(enum_name), YarnValue::Str(case)) => self.is_case(enum_name, case),
+            (Some(_), _) => false,
+        }
+    }
+
+    /// Converts a stored value of a variable back to a [`YarnValue::Enum`] if the variable has
+    /// an enum type.
+    pub(crate) fn to_typed(&self, variable: &str, value: YarnValue) -> YarnValue {
+        match (self.variables.get(variable), value) {
+            (Some(enum_name), YarnValue::Str(case)) if self.is_case(enum_name, &case) => YarnValue::Enum {
+                enum_name: enum_name.clone(),
+                case,
+            },
+            (_, value) => value,
+        }
+    }
 }
 
 impl YarnValue {
+    /// The type of the value, as far as function signatures are concerned. Null can be passed as
+    /// any type, and enum cases are strings.
+    pub(crate) fn yarn_type(&self) -> YarnType {
+        match self {
+            Self::Str(_) | Self::Enum { .. } => YarnType::String,
+            Self::Bool(_) => YarnType::Bool,
+            Self::Number(_) => YarnType::Number,
+            Self::Null => YarnType::Any,
+        }
+    }
+
     pub fn as_string(&self) -> String {
         match self {
             Self::Str(val) => {
@@ -23,12 +102,15 @@ impl YarnValue {
             Self::Bool(val) => {
                 match val {
                     true => "True".to_string(),
-                    false => "Frue".to_string(),
+                    false => "False".to_string(),
                 }
             }
             Self::Null => {
                 "null".to_string()
             }
+            Self::Enum { case, .. } => {
+                case.clone()
+            }
         }
     }
 
@@ -44,7 +126,7 @@ impl YarnValue {
             Self::Bool(val) => {
                 if *val { 1.0 } else { 0.0 }
             }
-            Self::Null => {
+            Self::Null | Self::Enum { .. } => {
                 0.0
             }
         }
@@ -64,6 +146,9 @@ impl YarnValue {
             Self::Null => {
                 false
             }
+            Self::Enum { .. } => {
+                true
+            }
         }
     }
 
@@ -157,7 +242,7 @@ impl YarnValue {
                 Self::Number(-0.0)
             }
             _ => {
-                Self::Number(std::f32::NAN)
+                Self::Number(f32::NAN)
             }
         }
     }
@@ -27,13 +27,11 @@ fn set_up_vm(yarnc_path: &str) -> VirtualMachine {
         .map(|result| result.unwrap())
         .collect();
 
-    let mut vm = VirtualMachine::new(program);
+    let mut vm = VirtualMachine::new(SharedProgram::new(program).unwrap());
     vm.library.insert(
         "assert".to_string(),
         FunctionInfo::new(1, &|parameters: &[YarnValue]| {
-            if !parameters[0].as_bool() {
-                assert!(false, "Assertion failed");
-            }
+            assert!(parameters[0].as_bool(), "Assertion failed");
         }),
     );
     vm.library.insert(
@@ -0,0 +1,98 @@
+use yharnam::*;
+
+#[test]
+fn test_select_fallback_and_nested_format_functions() {
+    // Select falls back to `other` when there's no text for the value.
+    let line = "[select \"nb\" m=\"He\" f=\"She\" other=\"They\"] waved.";
+    assert_eq!(expand_format_functions(line, "en"), "They waved.");
+    assert_eq!(expand_format_functions(&line.replace("nb", "f"), "en"), "She waved.");
+
+    // Branches can contain format functions of their own, with their own `%`.
+    let line = "[select \"f\" f=\"She has [plural \"3\" one=\"a \\\"cat\\\"\" other=\"% cats\"]\" other=\"%\"].";
+    assert_eq!(expand_format_functions(line, "en"), "She has 3 cats.");
+    assert_eq!(expand_format_functions(&line.replace("\"f\"", "\"m\""), "en"), "m.");
+
+    // Other brackets are text, wherever they are.
+    let line = "[b][select \"a\" a=\"see [note]\" other=\"x\"][/b]";
+    assert_eq!(expand_format_functions(line, "en"), "[b]see [note][/b]");
+
+    // ICU MessageFormat converts into format functions.
+    let message = "{0, select, female {{1, plural, one {She has a cat} other {She has # cats}}} \
+        other {They have {1, selectordinal, one {a #st} other {a #th}} \"pet\"}}, {2}'{'sic'}'.";
+    let line = icu_to_format_functions(message).unwrap();
+    assert_eq!(
+        line,
+        "[select \"{0}\" female=\"[plural \"{1}\" one=\"She has a cat\" other=\"She has % cats\"]\" \
+        other=\"They have [ordinal \"{1}\" one=\"a %st\" other=\"a %th\"] \\\"pet\\\"\"], {2}{sic}.",
+    );
+    let expand = |substitutions: &[&str]| {
+        let mut line = line.clone();
+        for (i, substitution) in substitutions.iter().enumerate() {
+            line = line.replace(&format!("{{{}}}", i), substitution);
+        }
+        expand_format_functions(&line, "en")
+    };
+    assert_eq!(expand(&["female", "2", "Ok"]), "She has 2 cats, Ok{sic}.");
+    assert_eq!(expand(&["male", "1", "Ok"]), "They have a 1st \"pet\", Ok{sic}.");
+
+    assert_eq!(
+        icu_to_format_functions("{0, plural, =0 {none} other {#}}"),
+        Err(FormatFunctionError::UnsupportedIcu("explicit values like =0".to_string())),
+    );
+    assert_eq!(
+        icu_to_format_functions("{count}"),
+        Err(FormatFunctionError::UnsupportedIcu("argument \"count\" isn't a substitution number".to_string())),
+    );
+}
+
+#[test]
+fn test_locale_context() {
+    let context = LocaleContext::new("en-GB").unwrap();
+    assert_eq!(context.locale().to_string(), "en-GB");
+    assert_eq!(context.expand("[plural \"1\" one=\"% apple\" other=\"% apples\"]"), "1 apple");
+    assert_eq!(context.expand("[ordinal \"22\" one=\"%st\" two=\"%nd\" few=\"%rd\" other=\"%th\"]"), "22nd");
+
+    let context = LocaleContext::new("ru").unwrap();
+    assert_eq!(context.expand("[plural \"5\" one=\"% кот\" few=\"% кота\" many=\"% котов\"]"), "5 котов");
+
+    // Malformed lines are errors instead of panics with try_compose.
+    let cats = "[plural \"{0}\" one=\"% кот\" other=\"% котов\"]";
+    assert_eq!(context.try_compose(cats, &["5".to_string()]), Ok("5 котов".to_string()));
+    assert_eq!(context.try_compose(cats, &["many".to_string()]), Err(FormatFunctionError::NotANumber("many".to_string())));
+    assert_eq!(context.try_compose("[plural \"{0}\" one=]", &[]), Err(FormatFunctionError::Expected("a string".to_string())));
+
+    assert_eq!(LocaleContext::new("not a locale").unwrap_err(), LocaleError::Invalid("not a locale".to_string()));
+    assert_eq!(LocaleContext::new("tlh").unwrap_err(), LocaleError::Unsupported("tlh".to_string()));
+}
+
+#[test]
+fn test_bidi_isolates_and_line_direction() {
+    let mut context = LocaleContext::new("ar").unwrap();
+    assert_eq!(context.direction(), TextDirection::RightToLeft);
+    assert_eq!(LocaleContext::new("en").unwrap().direction(), TextDirection::LeftToRight);
+
+    let text = "قال {0} [plural \"{1}\" one=\"تفاحة\" other=\"% تفاحات\"]";
+    let substitutions = ["Bob".to_string(), "3".to_string()];
+    assert_eq!(context.compose(text, &substitutions), "قال Bob 3 تفاحات");
+
+    // Substitutions are isolated, including format function values inserted with `%`.
+    context.set_isolate_substitutions(true);
+    let line = context.compose(text, &substitutions);
+    assert_eq!(line, "قال \u{2068}Bob\u{2069} \u{2068}3\u{2069} تفاحات");
+    assert_eq!(context.line_direction(&line), TextDirection::RightToLeft);
+
+    // Isolated text doesn't count towards the line's direction, and neutral lines take the
+    // locale's direction.
+    assert_eq!(context.line_direction(&context.compose("{0} قال", &substitutions)), TextDirection::RightToLeft);
+    assert_eq!(context.line_direction("Hello"), TextDirection::LeftToRight);
+    assert_eq!(context.line_direction("42!"), TextDirection::RightToLeft);
+
+    // Left-to-right locales are left alone.
+    let mut context = LocaleContext::new("en").unwrap();
+    context.set_isolate_substitutions(true);
+    assert_eq!(context.compose("Hi {0}", &substitutions), "Hi Bob");
+
+    // A combining mark on a quote is part of the quote's grapheme cluster, so it doesn't end
+    // the string.
+    assert_eq!(context.expand("[select \"a\" a=\"\"\u{301}x\"]"), "\"\u{301}x");
+}
@@ -0,0 +1,51 @@
+use std::collections::HashSet;
+
+use yharnam::*;
+
+#[test]
+fn test_tag_and_extract_lines() {
+    let source = "title: Start\n\
+        ---\n\
+        // A comment\n\
+        Sally: Hello, {$name}! #happy\n\
+        -> Buy <<if $gold > 1>> // Costs gold\n    \
+            You have [plural {$gold} one=\"% coin\" other=\"% coins\"] left. #line:thanks\n\
+        -> Leave\n\
+        <<set $visited to true>>\n\
+        [[Left]]\n\
+        ===\n";
+
+    let mut used_ids = HashSet::new();
+    let tagged = tag_lines(source, "Test.yarn", &mut used_ids);
+    assert_eq!(used_ids.len(), 3);
+
+    // Tagging is deterministic, and leaves lines that already have IDs alone.
+    assert_eq!(tag_lines(source, "Test.yarn", &mut HashSet::new()), tagged);
+    assert_eq!(tag_lines(&tagged, "Test.yarn", &mut HashSet::new()), tagged);
+    let lines: Vec<_> = tagged.lines().collect();
+    assert!(lines[4].starts_with("-> Buy <<if $gold > 1>> #line:") && lines[4].ends_with(" // Costs gold"));
+    assert!(lines[5].ends_with("left. #line:thanks"));
+
+    let string_table = extract_string_table(&tagged, "Test.yarn");
+    assert_eq!(
+        string_table.iter().map(|line_info| (line_info.text.as_str(), line_info.line_number)).collect::<Vec<_>>(),
+        [
+            ("Sally: Hello, {0}!", 4),
+            ("Buy", 5),
+            ("You have [plural \"{0}\" one=\"% coin\" other=\"% coins\"] left.", 6),
+            ("Leave", 7),
+        ],
+    );
+    assert_eq!(string_table[2].id, "line:thanks");
+    assert!(string_table.iter().all(|line_info| line_info.node == "Start" && line_info.file == "Test.yarn"));
+
+    // URLs aren't comments.
+    let source = "title: Start\n---\nSee https://yarnspinner.dev // Docs\n===\n";
+    let string_table = extract_string_table(&tag_lines(source, "Test.yarn", &mut HashSet::new()), "Test.yarn");
+    assert_eq!(string_table[0].text, "See https://yarnspinner.dev");
+
+    // Lines with the same text still get different IDs.
+    let source = "title: Start\n---\nOK\nOK\n===\n";
+    let tagged = extract_string_table(&tag_lines(source, "Test.yarn", &mut HashSet::new()), "Test.yarn");
+    assert_ne!(tagged[0].id, tagged[1].id);
+}
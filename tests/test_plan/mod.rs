@@ -50,21 +50,32 @@ pub struct TestPlan {
     steps: Vec<PlanStep>,
     next_step_index: usize,
     options: Vec<String>,
+    /// The seed for the VM's random number generator, set with a `seed: <number>` line.
+    seed: Option<u64>,
 }
 
 impl TestPlan {
     pub fn load(plan_path: &Path) -> io::Result<Self> {
         let plan_text = fs::read_to_string(plan_path)?;
-        let steps: Vec<_> = plan_text.lines()
+        let lines = plan_text.lines()
             .map(|line| line.trim_start())
-            .filter(|line| !line.is_empty() && !line.starts_with('#'))
-            .map(|line| PlanStep::new(line))
-            .collect();
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+
+        let mut steps = Vec::new();
+        let mut seed = None;
+        for line in lines {
+            if let Some(seed_text) = line.strip_prefix("seed: ") {
+                seed = Some(seed_text.parse().expect("Seed must be a number."));
+            } else {
+                steps.push(PlanStep::new(line));
+            }
+        }
 
         Ok(Self {
             steps,
             next_step_index: 0,
             options: Vec::new(),
+            seed,
         })
     }
 
@@ -76,7 +87,7 @@ impl TestPlan {
         // type.
 
         let prev_step = match self.next_step_index {
-            i if i > 0 && i <= self.steps.len() => Some(&self.steps[i as usize - 1]),
+            i if i > 0 && i <= self.steps.len() => Some(&self.steps[i - 1]),
             _ => None,
         };
         if let Some(PlanStep::Select(_)) = prev_step {
@@ -102,14 +113,13 @@ impl TestPlan {
 
         // We've fallen off the end of the test plan step list. We
         // expect a stop here.
-        return;
     }
 
     pub fn get_current_step(&self) -> Option<&PlanStep> {
         match self.next_step_index {
             0 => None,
             i if i <= self.steps.len() => {
-                Some(&self.steps[self.next_step_index as usize - 1])
+                Some(&self.steps[self.next_step_index - 1])
             }
             _ => Some(&PlanStep::Stop)
         }
@@ -120,7 +130,7 @@ pub struct PlanRunner {
     vm: VirtualMachine,
     string_table: Vec<LineInfo>,
     plan: TestPlan,
-    locale: String,
+    locale: LocaleContext,
 }
 
 impl PlanRunner {
@@ -144,13 +154,11 @@ impl PlanRunner {
             .map(|result| result.unwrap())
             .collect();
 
-        let mut vm = VirtualMachine::new(program);
+        let mut vm = VirtualMachine::new(SharedProgram::new(program).unwrap());
         vm.library.insert(
             "assert".to_string(),
             FunctionInfo::new(1, &|parameters: &[YarnValue]| {
-                if !parameters[0].as_bool() {
-                    assert!(false, "Assertion failed");
-                }
+                assert!(parameters[0].as_bool(), "Assertion failed");
             }),
         );
 
@@ -158,25 +166,26 @@ impl PlanRunner {
         let plan = TestPlan::load(&plan_path)
             .unwrap();
 
+        // Make randomized content repeatable.
+        if let Some(seed) = plan.seed {
+            vm.set_seed(seed);
+        }
+
         Self {
             vm,
             string_table,
             plan,
-            locale: "en".to_string(),
+            locale: LocaleContext::new("en").unwrap(),
         }
     }
 
     fn get_composed_text_for_line(&self, line: &Line) -> String {
-        let mut line_text = self.string_table.iter()
+        let line_text = self.string_table.iter()
             .find(|line_info| line_info.id == line.id)
             .map(|line_info| &line_info.text)
-            .unwrap()
-            .clone();
-        for (i, substitution) in line.substitutions.iter().enumerate() {
-            line_text = line_text.replacen(&format!("{{{}}}", i), &substitution, 1);
-        }
+            .unwrap();
 
-        yharnam::expand_format_functions(&line_text, &self.locale)
+        self.locale.compose(line_text, &line.substitutions)
     }
 
     pub fn run(&mut self) {
@@ -195,11 +204,15 @@ impl PlanRunner {
                     self.plan.next();
                     let plan_step = self.plan.get_current_step().unwrap();
                     for (option, plan_option) in options.into_iter().zip(&self.plan.options) {
-                        let option_text = self.get_composed_text_for_line(&option.line);
+                        // Unavailable options are written as `option: Text [disabled]`.
+                        let mut option_text = self.get_composed_text_for_line(&option.line);
+                        if !option.is_available {
+                            option_text.push_str(" [disabled]");
+                        }
                         assert_eq!(option_text, *plan_option);
                     }
                     match plan_step {
-                        PlanStep::Select(i) => self.vm.set_selected_option(*i),
+                        PlanStep::Select(i) => self.vm.set_selected_option(*i).unwrap(),
                         step => panic!("Expected PlanStep::Select, got {:?}", step),
                     }
                 }
@@ -209,8 +222,11 @@ impl PlanRunner {
                     let plan_step = self.plan.get_current_step().unwrap();
                     assert!(matches!(plan_step, PlanStep::Command(plan_text) if *plan_text == command), "Expected the command {:?}, got \"{}\"", plan_step, command);
                 }
-                SuspendReason::NodeChange { .. } => {}
-                SuspendReason::DialogueComplete(_) => {
+                SuspendReason::DialogueStart
+                    | SuspendReason::NodeStart(_)
+                    | SuspendReason::NodeComplete(_)
+                    => {}
+                SuspendReason::DialogueComplete => {
                     // Assert that the test plan expects the end of dialogue.
                     self.plan.next();
                     let plan_step = self.plan.get_current_step().unwrap();
@@ -0,0 +1,226 @@
+use yharnam::*;
+
+fn line_info(id: &str, text: &str) -> LineInfo {
+    LineInfo {
+        id: id.to_string(),
+        text: text.to_string(),
+        file: "Test.yarn".to_string(),
+        node: "Start".to_string(),
+        line_number: 1,
+    }
+}
+
+#[test]
+fn test_translation_import_export() {
+    let string_table = vec![
+        line_info("line:1", "Hello, {0}!"),
+        line_info("line:2", "You have [plural \"{0}\" one=\"% \\\"coin\\\"\" other=\"% coins\"] & {1} <gems>."),
+        line_info("line:3", "[select \"{0}\" m=\"He\" f=\"She\"] waves."),
+    ];
+    let translation = vec![
+        line_info("line:1", "Hallo, {0}!"),
+        line_info("line:2", "Du hast [plural \"{0}\" one=\"% \\\"Münze\\\"\" other=\"% Münzen\"] & {1} <Edelsteine>."),
+    ];
+    let de = LocaleContext::new("de").unwrap();
+
+    // Exports keep the existing translations, and round trip through every format.
+    let exports = [
+        export_xliff(&string_table, &translation, "en", "de", XliffVersion::V1_2),
+        export_xliff(&string_table, &translation, "en", "de", XliffVersion::V2_0),
+    ];
+    for xliff in &exports {
+        assert!(xliff.contains("Node: Start, file: Test.yarn, line: 1"));
+        let imported = import_xliff(xliff, &string_table, &de, MessageSyntax::FormatFunctions).unwrap();
+        assert_eq!(
+            imported.iter().map(|line_info| (line_info.id.as_str(), line_info.text.as_str())).collect::<Vec<_>>(),
+            translation.iter().map(|line_info| (line_info.id.as_str(), line_info.text.as_str())).collect::<Vec<_>>(),
+        );
+    }
+    let po = export_po(&string_table, &translation, "de");
+    assert!(po.contains("#. Node: Start\n#: Test.yarn:1\nmsgctxt \"line:1\"\n"));
+    let imported = import_po(&po, &string_table, &de, MessageSyntax::FormatFunctions).unwrap();
+    assert_eq!(imported[1].text, translation[1].text);
+    assert_eq!(imported.len(), 2);
+
+    // Suggestions in an alt-trans aren't the translation.
+    let xliff = r#"<xliff version="1.2"><file><body>
+        <trans-unit id="line:1">
+            <source>Hello, {0}!</source>
+            <target>Hallo, {0}!</target>
+            <alt-trans><target>Servus, {0}!</target></alt-trans>
+        </trans-unit>
+    </body></file></xliff>"#;
+    assert_eq!(import_xliff(xliff, &string_table, &de, MessageSyntax::FormatFunctions).unwrap()[0].text, "Hallo, {0}!");
+
+    // Comments can't span lines.
+    let mut multiline = line_info("line:1", "Hello, {0}!");
+    multiline.node = "Start\nmsgstr \"oops\"".to_string();
+    let multiline_po = export_po(&[multiline], &[], "de");
+    assert!(multiline_po.contains("#. Node: Start\\nmsgstr \\\"oops\\\"\n"));
+    assert!(import_po(&multiline_po, &string_table, &de, MessageSyntax::FormatFunctions).unwrap().is_empty());
+
+    // Fuzzy translations aren't imported.
+    let fuzzy = po.replacen("#. Node: Start\n", "#, fuzzy\n", 1);
+    assert_eq!(import_po(&fuzzy, &string_table, &de, MessageSyntax::FormatFunctions).unwrap().len(), 1);
+
+    // Broken translations are all reported.
+    let broken = "msgctxt \"line:1\"\nmsgid \"Hello, {0}!\"\nmsgstr \"Hallo!\"\n\n\
+        msgctxt \"line:2\"\nmsgid \"\"\nmsgstr \"Du hast [plural \\\"{0}\\\" one=\\\"% Münze\\\"] {1}.\"\n\n\
+        msgctxt \"line:3\"\nmsgid \"\"\nmsgstr \"[select \\\"{0}\\\" m=\\\"Er\\\"] winkt.\"\n\n\
+        msgctxt \"line:4\"\nmsgid \"\"\nmsgstr \"Neu\"\n";
+    assert_eq!(import_po(broken, &string_table, &de, MessageSyntax::FormatFunctions).unwrap_err(), [
+        TranslationError::InvalidTranslation(TranslationIssue::MissingPlaceholder {
+            line_id: "line:1".to_string(),
+            placeholder: "{0}".to_string(),
+        }),
+        TranslationError::InvalidTranslation(TranslationIssue::MissingPluralCategory {
+            line_id: "line:2".to_string(),
+            category: "other".to_string(),
+        }),
+        TranslationError::InvalidTranslation(TranslationIssue::FormatFunctionMismatch {
+            line_id: "line:3".to_string(),
+            expected: vec!["select \"{0}\" (f, m)".to_string()],
+            found: vec!["select \"{0}\" (m)".to_string()],
+        }),
+        TranslationError::UnknownLine("line:4".to_string()),
+    ]);
+    assert_eq!(
+        import_po("msgctxt \"line:2\"\nmsgstr \"[plural \\\"{0}\\\" one=] {1}\"\n", &string_table, &de, MessageSyntax::FormatFunctions).unwrap_err(),
+        [TranslationError::InvalidTranslation(TranslationIssue::InvalidFormatFunction {
+            line_id: "line:2".to_string(),
+            error: FormatFunctionError::Expected("a string".to_string()).to_string(),
+        })],
+    );
+}
+
+#[test]
+fn test_check_translations() {
+    let base = vec![
+        line_info("line:1", "Hello, {0}! You have {1} gems."),
+        line_info("line:2", "You have [plural \"{0}\" one=\"% coin\" other=\"% coins\"]."),
+        line_info("line:3", "You came [ordinal \"{0}\" one=\"%st\" two=\"%nd\" few=\"%rd\" other=\"%th\"]."),
+        line_info("line:4", "[select \"{0}\" m=\"He\" f=\"She\"] waves."),
+        line_info("line:5", "Goodbye."),
+    ];
+    let translation = vec![
+        line_info("line:1", "Привет, {0}! {0}"),
+        line_info("line:2", "У тебя [plural \"{0}\" one=\"% монета\" two=\"% монеты\" many=\"% монет\"]."),
+        line_info("line:3", "Ты [ordinal \"{0}\" other=\"%-й\"]."),
+        line_info("line:4", "[select \"{0}\" m=\"Он\" f=\"Она\"] машет."),
+        line_info("line:6", "Старая строка."),
+    ];
+
+    assert_eq!(check_translations(&base, &translation, &LocaleContext::new("ru").unwrap(), MessageSyntax::FormatFunctions), [
+        TranslationIssue::MissingPlaceholder {
+            line_id: "line:1".to_string(),
+            placeholder: "{1}".to_string(),
+        },
+        TranslationIssue::ExtraPlaceholder {
+            line_id: "line:1".to_string(),
+            placeholder: "{0}".to_string(),
+        },
+        TranslationIssue::InvalidPluralCategory {
+            line_id: "line:2".to_string(),
+            category: "two".to_string(),
+        },
+        TranslationIssue::MissingPluralCategory {
+            line_id: "line:2".to_string(),
+            category: "few".to_string(),
+        },
+        TranslationIssue::MissingPluralCategory {
+            line_id: "line:2".to_string(),
+            category: "other".to_string(),
+        },
+        TranslationIssue::UntranslatedLine {
+            line_id: "line:5".to_string(),
+        },
+        TranslationIssue::StaleLine {
+            line_id: "line:6".to_string(),
+        },
+    ]);
+
+    // Select keys have to stay the same, and format functions have to be valid.
+    let translation = vec![
+        line_info("line:4", "[select \"{0}\" m=\"Он\"] машет."),
+        line_info("line:5", "[plural \"{0}\" one=] До свидания."),
+    ];
+    let issues = check_translations(&base[3..], &translation, &LocaleContext::new("ru").unwrap(), MessageSyntax::FormatFunctions);
+    assert_eq!(issues.len(), 3);
+    assert!(matches!(&issues[0], TranslationIssue::FormatFunctionMismatch { line_id, .. } if line_id == "line:4"));
+    assert!(matches!(&issues[1], TranslationIssue::ExtraPlaceholder { line_id, .. } if line_id == "line:5"));
+    assert!(matches!(&issues[2], TranslationIssue::InvalidFormatFunction { line_id, .. } if line_id == "line:5"));
+
+    // Markup isn't a format function, so it doesn't stop the format functions being checked.
+    let translation = vec![line_info("line:4", "[b][select \"{0}\" m=\"Он\"][/b] машет.")];
+    let issues = check_translations(&base[3..4], &translation, &LocaleContext::new("ru").unwrap(), MessageSyntax::FormatFunctions);
+    assert!(matches!(&issues[..], [TranslationIssue::FormatFunctionMismatch { .. }, TranslationIssue::MarkupMismatch { .. }]));
+
+    // Markup has to stay the same, but can be reordered and have its attributes translated.
+    let base = vec![line_info("line:1", "[wave size=2]Hello[/wave], [b]friend[/b]![pause/] \\[not markup]")];
+    let translation = vec![line_info("line:1", "[pause/][wave size=3]Привет[/wave], друг!")];
+    assert_eq!(check_translations(&base, &translation, &LocaleContext::new("ru").unwrap(), MessageSyntax::FormatFunctions), [
+        TranslationIssue::MarkupMismatch {
+            line_id: "line:1".to_string(),
+            expected: vec!["[/b]", "[/wave]", "[b]", "[pause/]", "[wave]"].into_iter().map(String::from).collect(),
+            found: vec!["[/wave]", "[pause/]", "[wave]"].into_iter().map(String::from).collect(),
+        },
+    ]);
+    let translation = vec![line_info("line:1", "[pause/][wave size=3]Привет[/wave], [b]друг[/b]!")];
+    assert_eq!(check_translations(&base, &translation, &LocaleContext::new("ru").unwrap(), MessageSyntax::FormatFunctions), []);
+}
+
+#[test]
+fn test_nested_translation_checks() {
+    let ru = LocaleContext::new("ru").unwrap();
+    let base = vec![
+        line_info("line:1", "[select \"{0}\" m=\"He has [plural \"{1}\" one=\"% cat\" other=\"% cats\"]\" f=\"She waves\"]."),
+    ];
+
+    // Functions nested in select branches have to stay in the same branch, and nested plural
+    // functions need the locale's plural categories too.
+    let translation = vec![
+        line_info("line:1", "[select \"{0}\" m=\"Он машет\" f=\"У неё [plural \"{1}\" one=\"% кот\" other=\"% котов\"]\"]."),
+    ];
+    assert_eq!(check_translations(&base, &translation, &ru, MessageSyntax::FormatFunctions), [
+        TranslationIssue::FormatFunctionMismatch {
+            line_id: "line:1".to_string(),
+            expected: vec!["select \"{0}\" (f, m)".to_string(), "select \"{0}\" (f, m) m: plural \"{1}\"".to_string()],
+            found: vec!["select \"{0}\" (f, m)".to_string(), "select \"{0}\" (f, m) f: plural \"{1}\"".to_string()],
+        },
+        TranslationIssue::MissingPluralCategory {
+            line_id: "line:1".to_string(),
+            category: "few".to_string(),
+        },
+        TranslationIssue::MissingPluralCategory {
+            line_id: "line:1".to_string(),
+            category: "many".to_string(),
+        },
+    ]);
+
+    // Translations can be written in ICU MessageFormat, when importing them too.
+    let icu = "{0, select, m {У него {1, plural, one {# кот} few {# кота} many {# котов} other {# кота}}} \
+        f {Она машет}}.";
+    let translation = vec![line_info("line:1", icu)];
+    assert_eq!(check_translations(&base, &translation, &ru, MessageSyntax::Icu), []);
+    let po = format!("msgctxt \"line:1\"\nmsgstr \"{}\"\n", icu);
+    let imported = import_po(&po, &base, &ru, MessageSyntax::Icu).unwrap();
+    assert_eq!(imported[0].text, icu_to_format_functions(icu).unwrap());
+    assert_eq!(ru.expand(&imported[0].text.replace("{0}", "m").replace("{1}", "3")), "У него 3 кота.");
+
+    // Nested plural functions are checked on import, and so is ICU that can't be converted.
+    let po = "msgctxt \"line:1\"\nmsgstr \"{0, select, m {{1, plural, one {# кот} other {# котов}}} f {Она машет}}.\"\n";
+    assert_eq!(import_po(po, &base, &ru, MessageSyntax::Icu).unwrap_err(), [
+        TranslationError::InvalidTranslation(TranslationIssue::MissingPluralCategory {
+            line_id: "line:1".to_string(),
+            category: "few".to_string(),
+        }),
+        TranslationError::InvalidTranslation(TranslationIssue::MissingPluralCategory {
+            line_id: "line:1".to_string(),
+            category: "many".to_string(),
+        }),
+    ]);
+    assert!(matches!(
+        &import_po("msgctxt \"line:1\"\nmsgstr \"{0, select, m {\"\n", &base, &ru, MessageSyntax::Icu).unwrap_err()[..],
+        [TranslationError::InvalidTranslation(TranslationIssue::InvalidFormatFunction { .. })],
+    ));
+}
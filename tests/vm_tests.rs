@@ -0,0 +1,1471 @@
+use std::collections::HashMap;
+use std::future::{self, Future};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use yharnam::*;
+use yharnam::yarn_proto::{
+    instruction::OpCode,
+    operand::Value,
+    Instruction,
+    Node,
+    Operand,
+};
+
+fn instruction(opcode: OpCode, operands: &[Value]) -> Instruction {
+    Instruction {
+        opcode: opcode as i32,
+        operands: operands.iter()
+            .map(|value| Operand { value: Some(value.clone()) })
+            .collect(),
+    }
+}
+
+fn string(val: &str) -> Value {
+    Value::StringValue(val.to_string())
+}
+
+fn node(name: &str, instructions: Vec<Instruction>) -> (String, Node) {
+    let node = Node {
+        name: name.to_string(),
+        instructions,
+        labels: HashMap::new(),
+        tags: Vec::new(),
+        source_text_string_id: String::new(),
+    };
+    (name.to_string(), node)
+}
+
+fn program(nodes: Vec<(String, Node)>) -> Program {
+    Program {
+        name: "Test".to_string(),
+        nodes: nodes.into_iter().collect(),
+    }
+}
+
+/// Runs the VM until the dialogue completes, describing every event it produced.
+fn collect_events(vm: &mut VirtualMachine) -> Vec<String> {
+    let mut events = Vec::new();
+    loop {
+        let event = match vm.continue_dialogue() {
+            SuspendReason::Line(line) => format!("line {}", line.id),
+            SuspendReason::Options(options) => {
+                vm.set_selected_option(0).unwrap();
+                format!("options {}", options.len())
+            }
+            SuspendReason::Command(command) => format!("command {}", command),
+            SuspendReason::DialogueStart => "dialogue start".to_string(),
+            SuspendReason::NodeStart(name) => format!("node start {}", name),
+            SuspendReason::NodeComplete(name) => format!("node complete {}", name),
+            SuspendReason::DialogueComplete => {
+                events.push("dialogue complete".to_string());
+                break;
+            }
+        };
+        events.push(event);
+    }
+    events
+}
+
+#[test]
+fn test_node_events() {
+    let program = program(vec![
+        node("Start", vec![
+            instruction(OpCode::RunLine, &[string("line:1")]),
+            instruction(OpCode::PushString, &[string("Second")]),
+            instruction(OpCode::RunNode, &[]),
+        ]),
+        node("Second", vec![
+            instruction(OpCode::RunLine, &[string("line:2")]),
+            instruction(OpCode::Stop, &[]),
+        ]),
+    ]);
+    let mut vm = VirtualMachine::new(SharedProgram::new(program).unwrap());
+    vm.set_node("Start");
+
+    assert_eq!(collect_events(&mut vm), [
+        "dialogue start",
+        "node start Start",
+        "line line:1",
+        "node complete Start",
+        "node start Second",
+        "line line:2",
+        "node complete Second",
+        "dialogue complete",
+    ]);
+    assert_eq!(vm.execution_state, ExecutionState::Stopped);
+}
+
+#[test]
+fn test_node_events_at_end_of_node() {
+    let program = program(vec![
+        node("Start", vec![
+            instruction(OpCode::RunCommand, &[string("wave")]),
+        ]),
+    ]);
+    let mut vm = VirtualMachine::new(SharedProgram::new(program).unwrap());
+    vm.set_node("Start");
+
+    assert_eq!(collect_events(&mut vm), [
+        "dialogue start",
+        "node start Start",
+        "command wave",
+        "node complete Start",
+        "dialogue complete",
+    ]);
+
+    // Running the dialogue again starts a new run.
+    vm.set_node("Start");
+    assert_eq!(collect_events(&mut vm).first().map(String::as_str), Some("dialogue start"));
+}
+
+#[test]
+fn test_set_node_while_running() {
+    let program = program(vec![
+        node("Start", vec![
+            instruction(OpCode::RunLine, &[string("line:1")]),
+            instruction(OpCode::RunLine, &[string("line:2")]),
+        ]),
+        node("Other", vec![
+            instruction(OpCode::RunLine, &[string("line:3")]),
+        ]),
+        node("Detour", vec![
+            instruction(OpCode::DetourToNode, &[string("Jump")]),
+        ]),
+        node("Jump", vec![
+            instruction(OpCode::PushString, &[string("Other")]),
+            instruction(OpCode::RunNode, &[]),
+        ]),
+    ]);
+    let mut vm = VirtualMachine::new(SharedProgram::new(program).unwrap());
+    let next_line = |vm: &mut VirtualMachine| loop {
+        if let SuspendReason::Line(line) = vm.continue_dialogue() {
+            return line.id;
+        }
+    };
+    let other_events = [
+        "node start Other",
+        "line line:3",
+        "node complete Other",
+        "dialogue complete",
+    ];
+
+    // Leaving a node that has started completes it.
+    vm.set_node("Start");
+    assert_eq!(next_line(&mut vm), "line:1");
+    vm.set_node("Other");
+    assert_eq!(collect_events(&mut vm)[..2], ["node complete Start", "node start Other"]);
+
+    // A node that hasn't started yet is replaced.
+    vm.set_node("Start");
+    vm.set_node("Other");
+    assert_eq!(collect_events(&mut vm)[..2], ["dialogue start", "node start Other"]);
+
+    // A stopped dialogue carries on in the new node instead of completing.
+    vm.set_node("Start");
+    assert_eq!(next_line(&mut vm), "line:1");
+    vm.stop();
+    vm.set_node("Other");
+    let mut events = vec!["node complete Start"];
+    events.extend(other_events);
+    assert_eq!(collect_events(&mut vm), events);
+
+    // The nodes waiting on a detour complete even if the node they'd return to hasn't started.
+    vm.set_node("Detour");
+    assert!(matches!(vm.continue_dialogue(), SuspendReason::DialogueStart));
+    assert!(matches!(vm.continue_dialogue(), SuspendReason::NodeStart(name) if name == "Detour"));
+    assert!(matches!(vm.continue_dialogue(), SuspendReason::NodeStart(name) if name == "Jump"));
+    assert!(matches!(vm.continue_dialogue(), SuspendReason::NodeComplete(name) if name == "Jump"));
+    vm.set_node("Other");
+    let mut events = vec!["node complete Detour"];
+    events.extend(other_events);
+    assert_eq!(collect_events(&mut vm), events);
+}
+
+/// Compiled from:
+///
+/// ```yarn
+/// title: Start
+/// ---
+/// Which way? #line:1
+/// -> Left #line:2
+///     <<jump Left>>
+/// -> Right #line:3
+///     You stay here. #line:5
+/// ===
+/// title: Left
+/// ---
+/// You went left. #line:4
+/// <<jump End>>
+/// ===
+/// title: End
+/// ---
+/// The end. #line:6
+/// ===
+/// ```
+fn lookahead_program() -> Program {
+    let (start_name, mut start) = node("Start", vec![
+        instruction(OpCode::RunLine, &[string("line:1")]),
+        instruction(OpCode::AddOption, &[string("line:2"), string("L0shortcutoption_Start_1")]),
+        instruction(OpCode::AddOption, &[string("line:3"), string("L1shortcutoption_Start_2")]),
+        instruction(OpCode::ShowOptions, &[]),
+        instruction(OpCode::Jump, &[]),
+        // L0shortcutoption_Start_1
+        instruction(OpCode::PushString, &[string("Left")]),
+        instruction(OpCode::RunNode, &[]),
+        instruction(OpCode::JumpTo, &[string("L2shortcutoption_end")]),
+        // L1shortcutoption_Start_2
+        instruction(OpCode::RunLine, &[string("line:5")]),
+        instruction(OpCode::JumpTo, &[string("L2shortcutoption_end")]),
+        // L2shortcutoption_end
+        instruction(OpCode::Pop, &[]),
+        instruction(OpCode::Stop, &[]),
+    ]);
+    start.labels.insert("L0shortcutoption_Start_1".to_string(), 5);
+    start.labels.insert("L1shortcutoption_Start_2".to_string(), 8);
+    start.labels.insert("L2shortcutoption_end".to_string(), 10);
+    program(vec![
+        (start_name, start),
+        node("Left", vec![
+            instruction(OpCode::RunLine, &[string("line:4")]),
+            instruction(OpCode::PushString, &[string("End")]),
+            instruction(OpCode::RunNode, &[]),
+            instruction(OpCode::Stop, &[]),
+        ]),
+        node("End", vec![
+            instruction(OpCode::RunLine, &[string("line:6")]),
+            instruction(OpCode::Stop, &[]),
+        ]),
+    ])
+}
+
+#[test]
+fn test_lookahead() {
+    let mut vm = VirtualMachine::new(SharedProgram::new(lookahead_program()).unwrap());
+    assert!(vm.lookahead(10).is_empty());
+
+    vm.set_node("Start");
+    assert_eq!(vm.lookahead(10), ["line:1", "line:2", "line:3", "line:5", "line:4", "line:6"]);
+    assert_eq!(vm.lookahead(2), ["line:1", "line:2"]);
+
+    // Skip the start events and the first line.
+    for _ in 0..3 {
+        vm.continue_dialogue();
+    }
+    assert_eq!(vm.lookahead(10), ["line:2", "line:3", "line:5", "line:4", "line:6"]);
+
+    // While the options are shown, both of their labels could be next, and once one is selected,
+    // only that one is.
+    assert!(matches!(vm.continue_dialogue(), SuspendReason::Options(_)));
+    assert_eq!(vm.lookahead(10), ["line:5", "line:4", "line:6"]);
+    vm.set_selected_option(1).unwrap();
+    assert_eq!(vm.lookahead(10), ["line:5"]);
+}
+
+/// A future that is pending the first time it's polled, to simulate waiting on the game.
+struct YieldOnce(bool);
+
+impl Future for YieldOnce {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+#[derive(Default)]
+struct RecordingHandler {
+    events: Vec<String>,
+}
+
+impl DialogueHandler for RecordingHandler {
+    fn line(&mut self, line: Line) -> impl Future<Output = ()> {
+        self.events.push(format!("line {}", line.id));
+        future::ready(())
+    }
+
+    fn options(&mut self, options: Vec<YarnOption>) -> impl Future<Output = u32> {
+        self.events.push(format!("options {}", options.len()));
+        future::ready(options.last().unwrap().id)
+    }
+
+    fn command(&mut self, command: String) -> impl Future<Output = ()> {
+        self.events.push(format!("command {}", command));
+        YieldOnce(false)
+    }
+
+    fn node_start(&mut self, node_name: String) -> impl Future<Output = ()> {
+        self.events.push(format!("node start {}", node_name));
+        future::ready(())
+    }
+}
+
+#[test]
+fn test_dialogue_runner() {
+    let program = program(vec![
+        node("Start", vec![
+            instruction(OpCode::RunCommand, &[string("wave")]),
+            instruction(OpCode::AddOption, &[string("line:1"), string("Left")]),
+            instruction(OpCode::AddOption, &[string("line:2"), string("Right")]),
+            instruction(OpCode::ShowOptions, &[]),
+            instruction(OpCode::RunNode, &[]),
+        ]),
+        node("Left", vec![
+            instruction(OpCode::RunLine, &[string("line:3")]),
+        ]),
+        node("Right", vec![
+            instruction(OpCode::RunLine, &[string("line:4")]),
+        ]),
+    ]);
+    let mut runner = DialogueRunner::new(VirtualMachine::new(SharedProgram::new(program).unwrap()), RecordingHandler::default());
+    runner.run_blocking("Start");
+
+    assert_eq!(runner.handler.events, [
+        "node start Start",
+        "command wave",
+        "options 2",
+        "node start Right",
+        "line line:4",
+    ]);
+    assert_eq!(runner.vm.execution_state, ExecutionState::Stopped);
+}
+
+#[test]
+fn test_split_command() {
+    assert_eq!(split_command("flip Harley3 +1").unwrap(), ["flip", "Harley3", "+1"]);
+    assert_eq!(split_command("hide Collision:GermOnPorch").unwrap(), ["hide", "Collision:GermOnPorch"]);
+    assert_eq!(split_command(r#"say  "hello there" "a \"quote\"""#).unwrap(), ["say", "hello there", "a \"quote\""]);
+    assert_eq!(split_command("   "), Err(CommandError::Empty));
+    assert_eq!(split_command("say \"hello"), Err(CommandError::UnterminatedString));
+}
+
+#[test]
+fn test_command_registry() {
+    let flipped = Arc::new(Mutex::new(Vec::new()));
+
+    let mut registry = CommandRegistry::new();
+    let flipped_clone = flipped.clone();
+    registry.add_command("flip", &[YarnType::String, YarnType::Number], move |args| {
+        flipped_clone.lock().unwrap().push((args[0].as_string(), args[1].as_number()));
+    });
+
+    assert!(matches!(registry.dispatch("flip Harley3 +1"), Ok(CommandAction::Run(_))));
+    assert_eq!(*flipped.lock().unwrap(), [("Harley3".to_string(), 1.0)]);
+
+    assert_eq!(registry.dispatch("flip Harley3").err(), Some(CommandError::WrongArgumentCount {
+        command: "flip".to_string(),
+        expected: 2,
+        received: 1,
+    }));
+    assert_eq!(registry.dispatch("flip Harley3 lots").err(), Some(CommandError::InvalidArgument {
+        command: "flip".to_string(),
+        index: 1,
+        expected: YarnType::Number,
+        argument: "lots".to_string(),
+    }));
+    assert_eq!(registry.dispatch("toggle").err(), Some(CommandError::Unknown("toggle".to_string())));
+
+    // Built-in commands.
+    assert!(matches!(registry.dispatch("wait 0.5"), Ok(CommandAction::Wait(d)) if d == Duration::from_millis(500)));
+    assert!(matches!(registry.dispatch("wait -1"), Err(CommandError::InvalidArgument { .. })));
+    assert!(matches!(registry.dispatch("stop"), Ok(CommandAction::Stop)));
+}
+
+#[test]
+fn test_dialogue_runner_commands() {
+    let program = program(vec![
+        node("Start", vec![
+            instruction(OpCode::RunCommand, &[string("wait 0.01")]),
+            instruction(OpCode::RunCommand, &[string("toggle")]),
+            instruction(OpCode::RunCommand, &[string("stop")]),
+            instruction(OpCode::RunLine, &[string("line:1")]),
+        ]),
+    ]);
+    let mut runner = DialogueRunner::new(VirtualMachine::new(SharedProgram::new(program).unwrap()), RecordingHandler::default());
+    runner.run_blocking("Start");
+
+    // The unregistered command is passed to the handler, and the line is never reached.
+    assert_eq!(runner.handler.events, [
+        "node start Start",
+        "command toggle",
+    ]);
+    assert_eq!(runner.vm.execution_state, ExecutionState::Stopped);
+}
+
+#[test]
+fn test_evaluate_expression() {
+    let mut vm = VirtualMachine::new(SharedProgram::new(program(vec![])).unwrap());
+    vm.variable_storage.insert("$gold".to_string(), YarnValue::Number(15.0));
+    vm.library.insert(
+        "visited".to_string(),
+        FunctionInfo::new_returning(1, &|parameters: &[YarnValue]| {
+            (parameters[0].as_string() == "Shop").into()
+        }),
+    );
+
+    assert_eq!(vm.evaluate_expression("1 + 2 * 3"), Ok(YarnValue::Number(7.0)));
+    assert_eq!(vm.evaluate_expression("(1 + 2) * 3"), Ok(YarnValue::Number(9.0)));
+    assert_eq!(vm.evaluate_expression("10 - 4 - 3"), Ok(YarnValue::Number(3.0)));
+    assert_eq!(vm.evaluate_expression("-$gold % 4"), Ok(YarnValue::Number(-3.0)));
+    assert_eq!(vm.evaluate_expression("\"gold: \" + $gold"), Ok(YarnValue::Str("gold: 15".to_string())));
+    assert_eq!(vm.evaluate_expression("$gold > 10 and visited(\"Shop\")"), Ok(YarnValue::Bool(true)));
+    assert_eq!(vm.evaluate_expression("$gold gte 20 || not visited(\"Inn\")"), Ok(YarnValue::Bool(true)));
+    assert_eq!(vm.evaluate_expression("$missing == null"), Ok(YarnValue::Bool(true)));
+    assert_eq!(vm.evaluate_expression("true != false"), Ok(YarnValue::Bool(true)));
+
+    assert_eq!(vm.evaluate_expression("1 +"), Err(ExpressionError::UnexpectedEnd));
+    assert_eq!(vm.evaluate_expression("1 2"), Err(ExpressionError::UnexpectedToken(2, "2".to_string())));
+    assert_eq!(vm.evaluate_expression("1 # 2"), Err(ExpressionError::UnexpectedCharacter(2, '#')));
+    assert_eq!(vm.evaluate_expression("shop()"), Err(ExpressionError::UnknownFunction("shop".to_string())));
+    assert_eq!(vm.evaluate_expression("visited()"), Err(ExpressionError::WrongArgumentCount {
+        function: "visited".to_string(),
+        signature: FunctionSignature::new(&[YarnType::Any], Some(YarnType::Any)),
+        received: 0,
+    }));
+    assert_eq!(vm.evaluate_expression("1 - true"), Err(ExpressionError::InvalidOperands {
+        function: "Minus".to_string(),
+        received: vec![YarnType::Number, YarnType::Bool],
+    }));
+    assert_eq!(vm.evaluate_expression("\"a\" * 2"), Err(ExpressionError::InvalidOperands {
+        function: "Multiply".to_string(),
+        received: vec![YarnType::String, YarnType::Number],
+    }));
+}
+
+fn line_info(id: &str, text: &str) -> LineInfo {
+    LineInfo {
+        id: id.to_string(),
+        text: text.to_string(),
+        file: "Test.yarn".to_string(),
+        node: "Start".to_string(),
+        line_number: 1,
+    }
+}
+
+#[test]
+fn test_dialogue_graph() {
+    let (name, mut start) = node("Start", vec![
+        instruction(OpCode::AddOption, &[string("line:1"), string("Shop")]),
+        instruction(OpCode::AddOption, &[string("line:2"), string("L0")]),
+        instruction(OpCode::ShowOptions, &[]),
+        instruction(OpCode::Jump, &[]),
+        instruction(OpCode::PushString, &[string("End")]),
+        instruction(OpCode::RunNode, &[]),
+    ]);
+    start.labels.insert("L0".to_string(), 4);
+    let program = program(vec![
+        (name, start),
+        node("Shop", vec![]),
+        node("End", vec![]),
+    ]);
+    let string_table = [line_info("line:1", "Go to the \"shop\"")];
+
+    let graph = DialogueGraph::new(&program, &string_table);
+    assert_eq!(graph.nodes, ["End", "Shop", "Start"]);
+    assert_eq!(graph.edges, [
+        GraphEdge {
+            from: "Start".to_string(),
+            to: "Shop".to_string(),
+            kind: EdgeKind::Option,
+            label: Some("Go to the \"shop\"".to_string()),
+        },
+        GraphEdge {
+            from: "Start".to_string(),
+            to: "End".to_string(),
+            kind: EdgeKind::RunNode,
+            label: None,
+        },
+    ]);
+
+    assert_eq!(graph.to_dot(), "digraph dialogue {\n    \"End\";\n    \"Shop\";\n    \"Start\";\n    \"Start\" -> \"Shop\" [label=\"Go to the \\\"shop\\\"\"];\n    \"Start\" -> \"End\" [style=dashed];\n}\n");
+    assert_eq!(graph.to_mermaid(), "flowchart TD\n    n0[\"End\"]\n    n1[\"Shop\"]\n    n2[\"Start\"]\n    n2 -->|\"Go to the #quot;shop#quot;\"| n1\n    n2 -.-> n0\n");
+    assert!(graph.to_json().contains("\"kind\": \"run_node\""));
+}
+
+#[test]
+fn test_lint() {
+    let float = Value::FloatValue;
+    let (name, mut start) = node("Start", vec![
+        // if $met
+        instruction(OpCode::PushVariable, &[string("$met")]),
+        instruction(OpCode::JumpIfFalse, &[string("L0")]),
+        instruction(OpCode::Pop, &[]),
+        // <<set $gold to 10>>
+        instruction(OpCode::PushFloat, &[float(10.0)]),
+        instruction(OpCode::StoreVariable, &[string("$gold")]),
+        instruction(OpCode::Pop, &[]),
+        // endif
+        instruction(OpCode::RunLine, &[string("line:1")]),
+        instruction(OpCode::PushVariable, &[string("$gold")]),
+        instruction(OpCode::PushFloat, &[float(1.0)]),
+        instruction(OpCode::CallFunc, &[string("visited")]),
+        instruction(OpCode::Pop, &[]),
+        instruction(OpCode::PushBool, &[Value::BoolValue(true)]),
+        instruction(OpCode::StoreVariable, &[string("$met")]),
+        instruction(OpCode::StoreVariable, &[string("$unused")]),
+        instruction(OpCode::AddOption, &[string("line:2"), string("Shop")]),
+        instruction(OpCode::AddOption, &[string("line:3"), string("Missing")]),
+        instruction(OpCode::ShowOptions, &[]),
+        instruction(OpCode::RunNode, &[]),
+    ]);
+    start.labels.insert("L0".to_string(), 6);
+    let program = program(vec![
+        (name, start),
+        node("Shop", vec![
+            instruction(OpCode::PushVariable, &[string("$met")]),
+            instruction(OpCode::Pop, &[]),
+        ]),
+        node("Secret", vec![]),
+    ]);
+    let string_table = [
+        line_info("line:1", "Hello"),
+        line_info("line:2", "Shop"),
+        line_info("line:4", "Unused"),
+    ];
+    let vm = VirtualMachine::new(SharedProgram::new(program).unwrap());
+
+    let lints = lint(&vm.program, &string_table, "Start", &vm.library, &vm.enums);
+    assert_eq!(lints, [
+        Lint::UnreachableNode { node: "Secret".to_string() },
+        Lint::VariableReadBeforeWrite { node: "Start".to_string(), variable: "$met".to_string() },
+        Lint::VariableReadBeforeWrite { node: "Start".to_string(), variable: "$gold".to_string() },
+        Lint::VariableNeverRead { variable: "$unused".to_string() },
+        Lint::UnknownFunction { node: "Start".to_string(), function: "visited".to_string() },
+        Lint::MissingLine { node: "Start".to_string(), line_id: "line:3".to_string() },
+        Lint::UnusedLine { line_id: "line:4".to_string() },
+        Lint::MissingNode { node: "Start".to_string(), destination: "Missing".to_string() },
+    ]);
+}
+
+#[test]
+fn test_check_function_calls() {
+    let float = Value::FloatValue;
+    let program = program(vec![
+        node("Start", vec![
+            // <<if visited("Shop")>>
+            instruction(OpCode::PushString, &[string("Shop")]),
+            instruction(OpCode::PushFloat, &[float(1.0)]),
+            instruction(OpCode::CallFunc, &[string("visited")]),
+            instruction(OpCode::Pop, &[]),
+            // {repeat(3, "ha")}
+            instruction(OpCode::PushFloat, &[float(3.0)]),
+            instruction(OpCode::PushString, &[string("ha")]),
+            instruction(OpCode::PushFloat, &[float(2.0)]),
+            instruction(OpCode::CallFunc, &[string("repeat")]),
+            instruction(OpCode::Pop, &[]),
+            // {repeat("ha", 3)}
+            instruction(OpCode::PushString, &[string("ha")]),
+            instruction(OpCode::PushFloat, &[float(3.0)]),
+            instruction(OpCode::PushFloat, &[float(2.0)]),
+            instruction(OpCode::CallFunc, &[string("repeat")]),
+            instruction(OpCode::Pop, &[]),
+            // {visited()}
+            instruction(OpCode::PushFloat, &[float(0.0)]),
+            instruction(OpCode::CallFunc, &[string("visited")]),
+            instruction(OpCode::Pop, &[]),
+            // {$gold + max(1, 2, $bonus)}
+            instruction(OpCode::PushVariable, &[string("$gold")]),
+            instruction(OpCode::PushFloat, &[float(1.0)]),
+            instruction(OpCode::PushFloat, &[float(2.0)]),
+            instruction(OpCode::PushVariable, &[string("$bonus")]),
+            instruction(OpCode::PushFloat, &[float(3.0)]),
+            instruction(OpCode::CallFunc, &[string("max")]),
+            instruction(OpCode::PushFloat, &[float(2.0)]),
+            instruction(OpCode::CallFunc, &[string("Add")]),
+            instruction(OpCode::Pop, &[]),
+            instruction(OpCode::PushFloat, &[float(0.0)]),
+            instruction(OpCode::CallFunc, &[string("missing")]),
+        ]),
+    ]);
+    let mut vm = VirtualMachine::new(SharedProgram::new(program).unwrap());
+    vm.library.insert(
+        "visited".to_string(),
+        FunctionInfo::with_signature(
+            FunctionSignature::new(&[YarnType::String], Some(YarnType::Bool)),
+            YarnFunction::Returning(&|_: &[YarnValue]| false.into()),
+        ).unwrap(),
+    );
+    vm.library.insert(
+        "repeat".to_string(),
+        FunctionInfo::with_signature(
+            FunctionSignature::new(&[YarnType::Number, YarnType::String], Some(YarnType::String)),
+            YarnFunction::Returning(&|_: &[YarnValue]| YarnValue::Null),
+        ).unwrap(),
+    );
+    vm.library.insert(
+        "max".to_string(),
+        FunctionInfo::with_signature(
+            FunctionSignature::new_variadic(&[YarnType::Number], YarnType::Number, Some(YarnType::Number)),
+            YarnFunction::Returning(&|_: &[YarnValue]| YarnValue::Null),
+        ).unwrap(),
+    );
+
+    let errors = vm.check_function_calls().unwrap_err();
+    assert_eq!(errors, [
+        FunctionCallError::WrongArgumentType {
+            node: "Start".to_string(),
+            function: "repeat".to_string(),
+            index: 0,
+            expected: YarnType::Number,
+            received: YarnType::String,
+        },
+        FunctionCallError::WrongArgumentType {
+            node: "Start".to_string(),
+            function: "repeat".to_string(),
+            index: 1,
+            expected: YarnType::String,
+            received: YarnType::Number,
+        },
+        FunctionCallError::WrongArgumentCount {
+            node: "Start".to_string(),
+            function: "visited".to_string(),
+            signature: FunctionSignature::new(&[YarnType::String], Some(YarnType::Bool)),
+            received: 0,
+        },
+        FunctionCallError::UnknownFunction {
+            node: "Start".to_string(),
+            function: "missing".to_string(),
+        },
+    ]);
+
+    let signatures: Vec<String> = vm.function_signatures().into_iter()
+        .filter(|(name, _)| ["EqualTo", "max", "repeat"].contains(name))
+        .map(|(name, signature)| format!("{}{}", name, signature))
+        .collect();
+    assert_eq!(signatures, [
+        "EqualTo(Any, Any) -> Bool",
+        "max(Number, ...Number) -> Number",
+        "repeat(Number, String) -> String",
+    ]);
+
+    assert_eq!(vm.evaluate_expression("repeat(\"ha\", 3)"), Err(ExpressionError::WrongArgumentType {
+        function: "repeat".to_string(),
+        index: 0,
+        expected: YarnType::Number,
+        received: YarnType::String,
+    }));
+
+    // The dialogue stops at the first call that doesn't match, instead of calling the function.
+    vm.set_node("Start");
+    assert_eq!(collect_events(&mut vm), [
+        "dialogue start",
+        "node start Start",
+        "node complete Start",
+        "dialogue complete",
+    ]);
+
+    let mismatch = FunctionInfo::with_signature(
+        FunctionSignature::new(&[], None),
+        YarnFunction::Returning(&|_: &[YarnValue]| YarnValue::Null),
+    );
+    assert_eq!(mismatch.err(), Some(SignatureMismatch(FunctionSignature::new(&[], None))));
+}
+
+#[test]
+fn test_standard_library() {
+    let mut vm = VirtualMachine::new(SharedProgram::new(program(vec![])).unwrap());
+    vm.add_standard_library();
+    let eval = |vm: &mut VirtualMachine, expression: &str| vm.evaluate_expression(expression).unwrap();
+
+    assert_eq!(eval(&mut vm, "round(2.5)"), YarnValue::Number(3.0));
+    assert_eq!(eval(&mut vm, "floor(-2.5)"), YarnValue::Number(-3.0));
+    assert_eq!(eval(&mut vm, "ceil(2.1)"), YarnValue::Number(3.0));
+    assert_eq!(eval(&mut vm, "inc(2)"), YarnValue::Number(3.0));
+    assert_eq!(eval(&mut vm, "inc(2.5)"), YarnValue::Number(3.0));
+    assert_eq!(eval(&mut vm, "dec(2)"), YarnValue::Number(1.0));
+    assert_eq!(eval(&mut vm, "dec(2.5)"), YarnValue::Number(2.0));
+    assert_eq!(eval(&mut vm, "decimal(2.25)"), YarnValue::Number(0.25));
+    assert_eq!(eval(&mut vm, "int(-2.75)"), YarnValue::Number(-2.0));
+    assert_eq!(eval(&mut vm, "string(1.5) + string(false)"), YarnValue::Str("1.5False".to_string()));
+    assert_eq!(eval(&mut vm, "number(\"12\") + 1"), YarnValue::Number(13.0));
+    assert_eq!(eval(&mut vm, "format_invariant(1234.5)"), YarnValue::Str("1234.5".to_string()));
+
+    for _ in 0..100 {
+        let random = eval(&mut vm, "random()").as_number();
+        assert!((0.0..1.0).contains(&random));
+        let roll = eval(&mut vm, "dice(6)").as_number();
+        assert!((1.0..=6.0).contains(&roll) && roll.fract() == 0.0);
+        let range = eval(&mut vm, "random_range(-2, 2)").as_number();
+        assert!((-2.0..=2.0).contains(&range) && range.fract() == 0.0);
+    }
+
+    // The same seed produces the same rolls.
+    let mut rolls = Vec::new();
+    for _ in 0..2 {
+        vm.set_seed(42);
+        rolls.push((0..10).map(|_| eval(&mut vm, "dice(20)")).collect::<Vec<_>>());
+    }
+    assert_eq!(rolls[0], rolls[1]);
+}
+
+#[test]
+fn test_snapshot_restores_rng() {
+    let program = program(vec![
+        node("Start", vec![
+            // {roll()}
+            instruction(OpCode::PushFloat, &[Value::FloatValue(0.0)]),
+            instruction(OpCode::CallFunc, &[string("roll")]),
+            instruction(OpCode::RunLine, &[string("line:1"), Value::FloatValue(1.0)]),
+            instruction(OpCode::PushFloat, &[Value::FloatValue(0.0)]),
+            instruction(OpCode::CallFunc, &[string("roll")]),
+            instruction(OpCode::RunLine, &[string("line:2"), Value::FloatValue(1.0)]),
+        ]),
+    ]);
+    let mut vm = VirtualMachine::new(SharedProgram::new(program).unwrap());
+
+    // A custom function that uses the VM's random number generator.
+    let rng = vm.rng.clone();
+    vm.library.insert(
+        "roll".to_string(),
+        FunctionInfo::from_returning_fn(0, move |_: &[YarnValue]| {
+            (rng.lock().unwrap().range_inclusive(1, 1000) as f32).into()
+        }),
+    );
+    vm.set_seed(7);
+    vm.set_node("Start");
+
+    let next_line = |vm: &mut VirtualMachine| loop {
+        if let SuspendReason::Line(line) = vm.continue_dialogue() {
+            return line.substitutions;
+        }
+    };
+    let first = next_line(&mut vm);
+    let snapshot = vm.snapshot();
+    let second = next_line(&mut vm);
+
+    // Restoring the snapshot, even after reseeding, replays the same roll.
+    vm.set_seed(8);
+    vm.variable_storage.insert("$changed".to_string(), true.into());
+    vm.restore(snapshot.clone());
+    assert_eq!(next_line(&mut vm), second);
+    assert!(vm.variable_storage.is_empty());
+
+    // The same seed produces the same rolls.
+    vm.set_seed(7);
+    vm.set_node("Start");
+    assert_eq!(next_line(&mut vm), first);
+
+    // Snapshots can be saved to disk.
+    let json = serde_json::to_string(&snapshot).unwrap();
+    let loaded: VmSnapshot = serde_json::from_str(&json).unwrap();
+    assert_eq!(loaded.rng_state, snapshot.rng_state);
+}
+
+/// A line group with three lines, where only the first two conditions pass:
+///
+/// ```text
+/// => Line 1 <<if true>>
+/// => Line 2 <<if true and true>>
+/// => Line 3 <<if false>>
+/// ```
+fn line_group_program() -> Program {
+    let float = Value::FloatValue;
+    let (name, mut start) = node("Start", vec![
+        instruction(OpCode::PushBool, &[Value::BoolValue(true)]),
+        instruction(OpCode::AddSaliencyCandidate, &[string("line:1"), float(1.0), string("L1")]),
+        instruction(OpCode::PushBool, &[Value::BoolValue(true)]),
+        instruction(OpCode::AddSaliencyCandidate, &[string("line:2"), float(2.0), string("L2")]),
+        instruction(OpCode::PushBool, &[Value::BoolValue(false)]),
+        instruction(OpCode::AddSaliencyCandidate, &[string("line:3"), float(1.0), string("L3")]),
+        instruction(OpCode::SelectSaliencyCandidate, &[]),
+        instruction(OpCode::JumpIfFalse, &[string("End")]),
+        instruction(OpCode::Pop, &[]),
+        instruction(OpCode::Jump, &[]),
+        // L1
+        instruction(OpCode::Pop, &[]),
+        instruction(OpCode::RunLine, &[string("line:1")]),
+        instruction(OpCode::JumpTo, &[string("End")]),
+        // L2
+        instruction(OpCode::Pop, &[]),
+        instruction(OpCode::RunLine, &[string("line:2")]),
+        instruction(OpCode::JumpTo, &[string("End")]),
+        // L3
+        instruction(OpCode::Pop, &[]),
+        instruction(OpCode::RunLine, &[string("line:3")]),
+        instruction(OpCode::JumpTo, &[string("End")]),
+        // End
+        instruction(OpCode::Stop, &[]),
+    ]);
+    start.labels.insert("L1".to_string(), 10);
+    start.labels.insert("L2".to_string(), 13);
+    start.labels.insert("L3".to_string(), 16);
+    start.labels.insert("End".to_string(), 19);
+    program(vec![(name, start)])
+}
+
+fn run_line_group(vm: &mut VirtualMachine) -> Vec<String> {
+    vm.set_node("Start");
+    collect_events(vm).into_iter()
+        .filter(|event| event.starts_with("line"))
+        .collect()
+}
+
+#[test]
+fn test_line_groups() {
+    let mut vm = VirtualMachine::new(SharedProgram::new(line_group_program()).unwrap());
+    assert_eq!(run_line_group(&mut vm), ["line line:1"]);
+
+    vm.saliency_strategy = Box::new(BestMatchSaliencyStrategy);
+    assert_eq!(run_line_group(&mut vm), ["line line:2"]);
+
+    vm.saliency_strategy = Box::new(LeastRecentlySeenSaliencyStrategy);
+    assert_eq!(run_line_group(&mut vm), ["line line:1"]);
+    assert_eq!(run_line_group(&mut vm), ["line line:2"]);
+    assert_eq!(run_line_group(&mut vm), ["line line:1"]);
+
+    vm.saliency_strategy = Box::new(RandomSaliencyStrategy);
+    for _ in 0..10 {
+        assert_ne!(run_line_group(&mut vm), ["line line:3"]);
+    }
+
+    // Nothing is run when no conditions pass.
+    struct NoneStrategy;
+    impl SaliencyStrategy for NoneStrategy {
+        fn select(&mut self, _: &[SaliencyCandidate], _: &mut SaliencyContext) -> Option<usize> {
+            None
+        }
+    }
+    vm.saliency_strategy = Box::new(NoneStrategy);
+    assert!(run_line_group(&mut vm).is_empty());
+
+    let vm = VirtualMachine::new(SharedProgram::new(line_group_program()).unwrap());
+    let string_table = [line_info("line:1", "One"), line_info("line:2", "Two"), line_info("line:3", "Three")];
+    assert_eq!(lint(&vm.program, &string_table, "Start", &vm.library, &vm.enums), []);
+}
+
+#[test]
+fn test_conditional_options() {
+    let (name, mut start) = node("Start", vec![
+        instruction(OpCode::PushBool, &[Value::BoolValue(false)]),
+        instruction(OpCode::AddOption, &[string("line:locked"), string("Locked"), Value::FloatValue(0.0), Value::BoolValue(true)]),
+        instruction(OpCode::PushBool, &[Value::BoolValue(true)]),
+        instruction(OpCode::AddOption, &[string("line:open"), string("Open"), Value::FloatValue(0.0), Value::BoolValue(true)]),
+        instruction(OpCode::AddOption, &[string("line:plain"), string("Open"), Value::FloatValue(0.0)]),
+        instruction(OpCode::ShowOptions, &[]),
+        instruction(OpCode::Jump, &[]),
+        // Locked
+        instruction(OpCode::Stop, &[]),
+        // Open
+        instruction(OpCode::RunLine, &[string("line:opened"), Value::FloatValue(0.0)]),
+        instruction(OpCode::Stop, &[]),
+    ]);
+    start.labels.insert("Locked".to_string(), 7);
+    start.labels.insert("Open".to_string(), 8);
+    let mut vm = VirtualMachine::new(SharedProgram::new(program(vec![(name, start)])).unwrap());
+    assert_eq!(vm.check_function_calls(), Ok(()));
+
+    assert_eq!(vm.set_selected_option(0), Err(SelectOptionError::NotWaitingForSelection));
+
+    vm.set_node("Start");
+    let options = loop {
+        if let SuspendReason::Options(options) = vm.continue_dialogue() {
+            break options;
+        }
+    };
+    let availability: Vec<bool> = options.iter().map(|option| option.is_available).collect();
+    assert_eq!(availability, [false, true, true]);
+
+    assert_eq!(vm.set_selected_option(0), Err(SelectOptionError::UnavailableOption(0)));
+    assert_eq!(vm.set_selected_option(3), Err(SelectOptionError::InvalidOption { id: 3, option_count: 3 }));
+    assert_eq!(vm.set_selected_option(1), Ok(()));
+    assert!(matches!(vm.continue_dialogue(), SuspendReason::Line(line) if line.id == "line:opened"));
+
+    // The condition is pushed before the option's substitutions.
+    let (name, mut start) = node("Start", vec![
+        instruction(OpCode::PushBool, &[Value::BoolValue(false)]),
+        instruction(OpCode::PushFloat, &[Value::FloatValue(10.0)]),
+        instruction(OpCode::PushString, &[string("sword")]),
+        instruction(OpCode::AddOption, &[string("line:buy"), string("Buy"), Value::FloatValue(2.0), Value::BoolValue(true)]),
+        instruction(OpCode::AddOption, &[string("line:leave"), string("Buy"), Value::FloatValue(0.0)]),
+        instruction(OpCode::ShowOptions, &[]),
+        // Buy
+        instruction(OpCode::Stop, &[]),
+    ]);
+    start.labels.insert("Buy".to_string(), 6);
+    let mut vm = VirtualMachine::new(SharedProgram::new(program(vec![(name, start)])).unwrap());
+    vm.set_node("Start");
+    let options = loop {
+        if let SuspendReason::Options(options) = vm.continue_dialogue() {
+            break options;
+        }
+    };
+    assert_eq!(options[0].line.substitutions, ["10", "sword"]);
+    assert!(!options[0].is_available);
+    assert!(vm.state.stack.is_empty());
+
+    // When none of the options are available, the node ends instead of showing them. A node
+    // that was detoured to returns to its caller.
+    let (name, mut locked) = node("Locked", vec![
+        instruction(OpCode::PushBool, &[Value::BoolValue(false)]),
+        instruction(OpCode::AddOption, &[string("line:locked"), string("Locked"), Value::FloatValue(0.0), Value::BoolValue(true)]),
+        instruction(OpCode::ShowOptions, &[]),
+        instruction(OpCode::Jump, &[]),
+        // Locked
+        instruction(OpCode::RunLine, &[string("line:unreachable"), Value::FloatValue(0.0)]),
+    ]);
+    locked.labels.insert("Locked".to_string(), 4);
+    let start = node("Start", vec![
+        instruction(OpCode::PushString, &[string("Locked")]),
+        instruction(OpCode::PeekAndDetourToNode, &[]),
+        instruction(OpCode::Pop, &[]),
+        instruction(OpCode::RunLine, &[string("line:back"), Value::FloatValue(0.0)]),
+    ]);
+    let mut vm = VirtualMachine::new(SharedProgram::new(program(vec![start, (name, locked)])).unwrap());
+    vm.set_node("Start");
+    assert_eq!(collect_events(&mut vm), [
+        "dialogue start",
+        "node start Start",
+        "node start Locked",
+        "node complete Locked",
+        "line line:back",
+        "node complete Start",
+        "dialogue complete",
+    ]);
+}
+
+/// A node with a `<<once>>` block, followed by a `once` option and a normal option:
+///
+/// ```text
+/// <<once>>
+///     Line 1
+/// <<endonce>>
+/// -> Option 1 <<once>>
+/// -> Option 2
+/// ```
+///
+/// `once` compiles to ordinary variables, named by [`VirtualMachine::once_variable_name`].
+fn once_program() -> Program {
+    let count = Value::FloatValue(0.0);
+    let block = VirtualMachine::once_variable_name("line:1");
+    let option = VirtualMachine::once_variable_name("line:option1");
+    let (name, mut start) = node("Start", vec![
+        instruction(OpCode::PushVariable, &[string(&block)]),
+        instruction(OpCode::PushFloat, &[Value::FloatValue(1.0)]),
+        instruction(OpCode::CallFunc, &[string("Not")]),
+        instruction(OpCode::JumpIfFalse, &[string("EndBlock")]),
+        instruction(OpCode::PushBool, &[Value::BoolValue(true)]),
+        instruction(OpCode::StoreVariable, &[string(&block)]),
+        instruction(OpCode::Pop, &[]),
+        instruction(OpCode::RunLine, &[string("line:1"), count.clone()]),
+        // EndBlock
+        instruction(OpCode::Pop, &[]),
+        instruction(OpCode::PushVariable, &[string(&option)]),
+        instruction(OpCode::PushFloat, &[Value::FloatValue(1.0)]),
+        instruction(OpCode::CallFunc, &[string("Not")]),
+        instruction(OpCode::AddOption, &[string("line:option1"), string("Option1"), count.clone(), Value::BoolValue(true)]),
+        instruction(OpCode::AddOption, &[string("line:option2"), string("Option2"), count.clone()]),
+        instruction(OpCode::ShowOptions, &[]),
+        instruction(OpCode::Jump, &[]),
+        // Option1
+        instruction(OpCode::PushBool, &[Value::BoolValue(true)]),
+        instruction(OpCode::StoreVariable, &[string(&option)]),
+        instruction(OpCode::Pop, &[]),
+        // Option2
+        instruction(OpCode::Stop, &[]),
+    ]);
+    start.labels.insert("EndBlock".to_string(), 8);
+    start.labels.insert("Option1".to_string(), 16);
+    start.labels.insert("Option2".to_string(), 19);
+    program(vec![(name, start)])
+}
+
+#[test]
+fn test_once() {
+    let mut vm = VirtualMachine::new(SharedProgram::new(once_program()).unwrap());
+    assert_eq!(vm.check_function_calls(), Ok(()));
+
+    // The variables that track once content are meant to be read before they're stored.
+    let string_table = ["line:1", "line:option1", "line:option2"].map(|id| line_info(id, "Text"));
+    assert_eq!(lint(&vm.program, &string_table, "Start", &vm.library, &vm.enums), []);
+
+    // Runs the node, picking the first available option, and returns what was seen.
+    let run = |vm: &mut VirtualMachine| {
+        let mut seen = Vec::new();
+        vm.set_node("Start");
+        loop {
+            match vm.continue_dialogue() {
+                SuspendReason::Line(line) => seen.push(line.id),
+                SuspendReason::Options(options) => {
+                    let option = options.iter().find(|option| option.is_available).unwrap();
+                    seen.push(option.line.id.clone());
+                    vm.set_selected_option(option.id).unwrap();
+                }
+                SuspendReason::DialogueComplete => break,
+                _ => {}
+            }
+        }
+        seen
+    };
+
+    // Once content is shown the first time, and skipped every time after that.
+    assert_eq!(run(&mut vm), ["line:1", "line:option1"]);
+    assert_eq!(run(&mut vm), ["line:option2"]);
+    assert_eq!(run(&mut vm), ["line:option2"]);
+    assert_eq!(vm.variable_storage[&VirtualMachine::once_variable_name("line:1")], YarnValue::Bool(true));
+
+    // What has been seen is part of the variables, so it's forgotten along with them.
+    vm.variable_storage.clear();
+    assert_eq!(run(&mut vm), ["line:1", "line:option1"]);
+}
+
+/// Start detours to Shop, which detours to Greeting and then shows an option. Greeting ends by
+/// reaching the end of the node, and Shop ends with `<<return>>`, or `<<stop>>` if the second
+/// option is selected.
+fn detour_program() -> Program {
+    let (shop_name, mut shop) = node("Shop", vec![
+        instruction(OpCode::PushString, &[string("Greeting")]),
+        instruction(OpCode::PeekAndDetourToNode, &[]),
+        instruction(OpCode::Pop, &[]),
+        instruction(OpCode::AddOption, &[string("line:leave"), string("Leave")]),
+        instruction(OpCode::AddOption, &[string("line:quit"), string("Quit")]),
+        instruction(OpCode::ShowOptions, &[]),
+        instruction(OpCode::Jump, &[]),
+        // Leave
+        instruction(OpCode::Return, &[]),
+        // Quit
+        instruction(OpCode::Stop, &[]),
+    ]);
+    shop.labels.insert("Leave".to_string(), 7);
+    shop.labels.insert("Quit".to_string(), 8);
+    program(vec![
+        node("Start", vec![
+            instruction(OpCode::RunLine, &[string("line:before")]),
+            instruction(OpCode::DetourToNode, &[string("Shop")]),
+            instruction(OpCode::RunLine, &[string("line:after")]),
+            instruction(OpCode::Stop, &[]),
+        ]),
+        (shop_name, shop),
+        node("Greeting", vec![
+            instruction(OpCode::RunLine, &[string("line:greeting")]),
+        ]),
+    ])
+}
+
+#[test]
+fn test_detours() {
+    let mut vm = VirtualMachine::new(SharedProgram::new(detour_program()).unwrap());
+    vm.set_node("Start");
+    assert_eq!(collect_events(&mut vm), [
+        "dialogue start",
+        "node start Start",
+        "line line:before",
+        "node start Shop",
+        "node start Greeting",
+        "line line:greeting",
+        "node complete Greeting",
+        "options 2",
+        "node complete Shop",
+        "line line:after",
+        "node complete Start",
+        "dialogue complete",
+    ]);
+    assert!(vm.state.return_stack.is_empty());
+
+    // Stopping inside a detour completes every node that was waiting for it.
+    vm.set_node("Start");
+    let options = loop {
+        if let SuspendReason::Options(options) = vm.continue_dialogue() {
+            break options;
+        }
+    };
+    assert_eq!(options.len(), 2);
+    let frames: Vec<&str> = vm.state.return_stack.iter().map(|frame| frame.node_name.as_str()).collect();
+    assert_eq!(frames, ["Start"]);
+
+    let snapshot = vm.snapshot();
+    vm.set_selected_option(1).unwrap();
+    assert_eq!(collect_events(&mut vm), [
+        "node complete Shop",
+        "node complete Start",
+        "dialogue complete",
+    ]);
+
+    // The return stack is saved in snapshots.
+    vm.restore(snapshot);
+    vm.set_selected_option(0).unwrap();
+    assert_eq!(collect_events(&mut vm), [
+        "node complete Shop",
+        "line line:after",
+        "node complete Start",
+        "dialogue complete",
+    ]);
+
+    let graph = DialogueGraph::new(&vm.program, &[]);
+    assert!(graph.edges.iter().any(|edge| edge.from == "Start" && edge.to == "Shop" && edge.kind == EdgeKind::Detour));
+    assert!(graph.edges.iter().any(|edge| edge.from == "Shop" && edge.to == "Greeting" && edge.kind == EdgeKind::Detour));
+    assert_eq!(vm.check_function_calls(), Ok(()));
+}
+
+/// Declares `enum Mood` with the cases `Happy` and `Sad`, and `$mood` with that type.
+fn mood_declarations() -> EnumDeclarations {
+    let mut enums = EnumDeclarations::default();
+    enums.enums.insert("Mood".to_string(), vec!["Happy".to_string(), "Sad".to_string()]);
+    enums.variables.insert("$mood".to_string(), "Mood".to_string());
+    enums
+}
+
+/// A smart variable node, which calculates the variable it's named after.
+fn smart_variable(name: &str, instructions: Vec<Instruction>) -> (String, Node) {
+    let (name, mut node) = node(name, instructions);
+    node.tags.push(SMART_VARIABLE_TAG.to_string());
+    (name, node)
+}
+
+#[test]
+fn test_enums_and_smart_variables() {
+    let program = program(vec![
+        node("Start", vec![
+            // <<set $mood to Mood.Happy>>
+            instruction(OpCode::PushString, &[string("Happy")]),
+            instruction(OpCode::StoreVariable, &[string("$mood")]),
+            instruction(OpCode::Pop, &[]),
+            // Mood: {$mood}, rich: {$rich}
+            instruction(OpCode::PushVariable, &[string("$mood")]),
+            instruction(OpCode::PushVariable, &[string("$rich")]),
+            instruction(OpCode::RunLine, &[string("line:1"), Value::FloatValue(2.0)]),
+            instruction(OpCode::Stop, &[]),
+        ]),
+        // <<declare $rich = $gold > 100>>
+        smart_variable("$rich", vec![
+            instruction(OpCode::PushVariable, &[string("$gold")]),
+            instruction(OpCode::PushFloat, &[Value::FloatValue(100.0)]),
+            instruction(OpCode::PushFloat, &[Value::FloatValue(2.0)]),
+            instruction(OpCode::CallFunc, &[string("GreaterThan")]),
+            instruction(OpCode::Return, &[]),
+        ]),
+    ]);
+
+    let mut vm = VirtualMachine::new(SharedProgram::new(program).unwrap());
+    vm.enums = mood_declarations();
+    vm.variable_storage.insert("$gold".to_string(), 150.0.into());
+    vm.set_node("Start");
+    let line = loop {
+        if let SuspendReason::Line(line) = vm.continue_dialogue() {
+            break line;
+        }
+    };
+    assert_eq!(line.substitutions, ["Happy", "True"]);
+
+    // Smart variables are calculated every time they're read.
+    vm.variable_storage.insert("$gold".to_string(), 50.0.into());
+    assert_eq!(vm.get_variable("$rich"), Ok(Some(false.into())));
+    assert!(!vm.variable_storage.contains_key("$rich"));
+    assert_eq!(vm.evaluate_expression("not $rich"), Ok(true.into()));
+
+    let sad = YarnValue::Enum { enum_name: "Mood".to_string(), case: "Sad".to_string() };
+    assert_eq!(vm.set_variable("$mood", sad.clone()), Ok(()));
+    assert_eq!(vm.get_variable("$mood"), Ok(Some(sad)));
+    assert_eq!(vm.variable_storage["$mood"], YarnValue::Str("Sad".to_string()));
+    assert_eq!(vm.evaluate_expression("$mood == Mood.Sad"), Ok(true.into()));
+    assert_eq!(
+        vm.evaluate_expression("Mood.Angry"),
+        Err(ExpressionError::UnknownEnumCase { enum_name: "Mood".to_string(), case: "Angry".to_string() }),
+    );
+    let angry = YarnValue::Enum { enum_name: "Mood".to_string(), case: "Angry".to_string() };
+    assert!(matches!(vm.set_variable("$mood", angry), Err(VariableError::InvalidEnumCase { .. })));
+    assert!(matches!(vm.set_variable("$mood", 1.0.into()), Err(VariableError::InvalidEnumCase { .. })));
+    assert_eq!(vm.set_variable("$rich", true.into()), Err(VariableError::SmartVariable("$rich".to_string())));
+
+    let string_table = [line_info("line:1", "Mood: {0}, rich: {1}")];
+    assert_eq!(lint(&vm.program, &string_table, "Start", &vm.library, &vm.enums), []);
+
+    // Assignments that can't be valid are found ahead of time.
+    let mut program = Program::clone(&vm.program);
+    let start = program.nodes.get_mut("Start").unwrap();
+    start.instructions[0] = instruction(OpCode::PushString, &[string("Angry")]);
+    start.instructions.insert(3, instruction(OpCode::StoreVariable, &[string("$rich")]));
+    assert_eq!(lint(&program, &string_table, "Start", &vm.library, &vm.enums), [
+        Lint::InvalidEnumAssignment {
+            node: "Start".to_string(),
+            variable: "$mood".to_string(),
+            enum_name: "Mood".to_string(),
+        },
+        Lint::SmartVariableAssignment {
+            node: "Start".to_string(),
+            variable: "$rich".to_string(),
+        },
+    ]);
+
+    // At runtime, they stop the dialogue instead.
+    let mut vm = VirtualMachine::new(SharedProgram::new(program).unwrap());
+    vm.enums = mood_declarations();
+    vm.set_node("Start");
+    assert_eq!(collect_events(&mut vm), [
+        "dialogue start",
+        "node start Start",
+        "node complete Start",
+        "dialogue complete",
+    ]);
+    assert!(!vm.variable_storage.contains_key("$mood"));
+}
+
+#[test]
+fn test_smart_variable_cycle() {
+    let mut vm = VirtualMachine::new(SharedProgram::new(program(vec![
+        node("Start", vec![
+            instruction(OpCode::PushVariable, &[string("$a")]),
+            instruction(OpCode::RunLine, &[string("line:1"), Value::FloatValue(1.0)]),
+            instruction(OpCode::Stop, &[]),
+        ]),
+        // <<declare $a = $b>>
+        smart_variable("$a", vec![
+            instruction(OpCode::PushVariable, &[string("$b")]),
+            instruction(OpCode::Return, &[]),
+        ]),
+        // <<declare $b = not $a>>
+        smart_variable("$b", vec![
+            instruction(OpCode::PushVariable, &[string("$a")]),
+            instruction(OpCode::PushFloat, &[Value::FloatValue(1.0)]),
+            instruction(OpCode::CallFunc, &[string("Not")]),
+            instruction(OpCode::Return, &[]),
+        ]),
+    ])).unwrap());
+
+    assert_eq!(vm.get_variable("$b"), Err(VariableError::SmartVariableCycle("$b".to_string())));
+    vm.set_node("Start");
+    assert_eq!(collect_events(&mut vm), [
+        "dialogue start",
+        "node start Start",
+        "node complete Start",
+        "dialogue complete",
+    ]);
+}
+
+#[test]
+fn test_smart_variable_function_error() {
+    let mut vm = VirtualMachine::new(SharedProgram::new(program(vec![
+        node("Start", vec![
+            instruction(OpCode::RunLine, &[string("line:1"), Value::FloatValue(0.0)]),
+            instruction(OpCode::RunLine, &[string("line:2"), Value::FloatValue(0.0)]),
+            instruction(OpCode::Stop, &[]),
+        ]),
+        // <<declare $lucky = luck() > 0.5>>, where luck() was never added to the library
+        smart_variable("$lucky", vec![
+            instruction(OpCode::PushFloat, &[Value::FloatValue(0.0)]),
+            instruction(OpCode::CallFunc, &[string("luck")]),
+            instruction(OpCode::PushFloat, &[Value::FloatValue(0.5)]),
+            instruction(OpCode::PushFloat, &[Value::FloatValue(2.0)]),
+            instruction(OpCode::CallFunc, &[string("GreaterThan")]),
+            instruction(OpCode::Return, &[]),
+        ]),
+    ])).unwrap());
+    vm.set_node("Start");
+    assert!(matches!(vm.continue_dialogue(), SuspendReason::DialogueStart));
+    assert!(matches!(vm.continue_dialogue(), SuspendReason::NodeStart(_)));
+    assert!(matches!(vm.continue_dialogue(), SuspendReason::Line(_)));
+
+    // The failure is returned, and the dialogue carries on as if the variable was never read.
+    let error = FunctionCallError::UnknownFunction {
+        node: "$lucky".to_string(),
+        function: "luck".to_string(),
+    };
+    assert_eq!(vm.get_variable("$lucky"), Err(VariableError::FunctionCall(error.clone())));
+    assert_eq!(
+        vm.evaluate_expression("$lucky or true"),
+        Err(ExpressionError::Variable(VariableError::FunctionCall(error))),
+    );
+    assert_eq!(collect_events(&mut vm), [
+        "line line:2",
+        "node complete Start",
+        "dialogue complete",
+    ]);
+}
+
+#[test]
+fn test_invalid_program() {
+    let mut bad_opcode = instruction(OpCode::Stop, &[]);
+    bad_opcode.opcode = 99;
+    let result = SharedProgram::new(program(vec![
+        node("Start", vec![
+            instruction(OpCode::RunLine, &[string("line:1")]),
+            bad_opcode,
+        ]),
+    ]));
+    assert_eq!(result.err(), Some(ProgramError::InvalidInstruction {
+        node: "Start".to_string(),
+        index: 1,
+        opcode: 99,
+    }));
+
+    let result = SharedProgram::new(program(vec![
+        node("Start", vec![
+            instruction(OpCode::PushBool, &[string("true")]),
+        ]),
+    ]));
+    assert!(matches!(result, Err(ProgramError::InvalidInstruction { index: 0, .. })));
+
+    let result = SharedProgram::new(program(vec![
+        node("Start", vec![
+            instruction(OpCode::JumpTo, &[string("End")]),
+        ]),
+    ]));
+    assert_eq!(result.err(), Some(ProgramError::UnknownLabel {
+        node: "Start".to_string(),
+        label: "End".to_string(),
+    }));
+}
+
+#[test]
+fn test_shared_program() {
+    fn assert_send<T: Send>() {}
+    assert_send::<VirtualMachine>();
+
+    let program = SharedProgram::new(program(vec![
+        node("Start", vec![
+            instruction(OpCode::PushVariable, &[string("$name")]),
+            instruction(OpCode::RunLine, &[string("line:1"), Value::FloatValue(1.0)]),
+            instruction(OpCode::Stop, &[]),
+        ]),
+    ])).unwrap();
+
+    // Every VM runs the same program on its own thread, with its own variables.
+    let threads: Vec<_> = (0..4)
+        .map(|i| {
+            let program = program.clone();
+            std::thread::spawn(move || {
+                let mut vm = VirtualMachine::new(program);
+                vm.variable_storage.insert("$name".to_string(), format!("NPC {}", i).into());
+                vm.set_node("Start");
+                loop {
+                    if let SuspendReason::Line(line) = vm.continue_dialogue() {
+                        return line.substitutions;
+                    }
+                }
+            })
+        })
+        .collect();
+    for (i, thread) in threads.into_iter().enumerate() {
+        assert_eq!(thread.join().unwrap(), [format!("NPC {}", i)]);
+    }
+
+    assert!(program.nodes.contains_key("Start"));
+}
+
+#[test]
+fn test_program_set() {
+    let named = |name: &str, nodes| Program { name: name.to_string(), ..program(nodes) };
+    let intro = named("Intro", vec![
+        node("Start", vec![
+            instruction(OpCode::PushFloat, &[Value::FloatValue(1.0)]),
+            instruction(OpCode::StoreVariable, &[string("$chapter")]),
+            instruction(OpCode::Pop, &[]),
+            instruction(OpCode::RunLine, &[string("line:intro"), Value::FloatValue(0.0)]),
+            instruction(OpCode::PushString, &[string("Chapter2")]),
+            instruction(OpCode::RunNode, &[]),
+        ]),
+    ]);
+    let chapter_2 = named("Chapter2", vec![
+        node("Chapter2", vec![
+            instruction(OpCode::RunLine, &[string("line:chapter2"), Value::FloatValue(0.0)]),
+            instruction(OpCode::Stop, &[]),
+        ]),
+    ]);
+
+    let mut set = ProgramSet::new();
+    set.add(intro.clone(), vec![line_info("line:intro", "Intro")]).unwrap();
+
+    let mut vm = VirtualMachine::new(SharedProgram::new(set.program()).unwrap());
+    vm.set_node("Start");
+    while !matches!(vm.continue_dialogue(), SuspendReason::Line(_)) {}
+
+    // Stream in the next chapter while the dialogue is running.
+    set.add(chapter_2.clone(), vec![line_info("line:chapter2", "Chapter 2")]).unwrap();
+    assert_eq!(set.names().collect::<Vec<_>>(), ["Intro", "Chapter2"]);
+    assert_eq!(
+        set.string_table().iter().map(|line_info| line_info.id.as_str()).collect::<Vec<_>>(),
+        ["line:intro", "line:chapter2"],
+    );
+    vm.set_program(SharedProgram::new(set.program()).unwrap());
+    assert_eq!(collect_events(&mut vm), [
+        "node complete Start",
+        "node start Chapter2",
+        "line line:chapter2",
+        "node complete Chapter2",
+        "dialogue complete",
+    ]);
+    assert_eq!(vm.variable_storage["$chapter"], YarnValue::Number(1.0));
+
+    assert_eq!(set.add(intro.clone(), Vec::new()), Err(MergeError::DuplicateProgram("Intro".to_string())));
+    assert_eq!(set.add(named("Other", chapter_2.nodes.clone().into_iter().collect()), Vec::new()), Err(MergeError::DuplicateNode {
+        node: "Chapter2".to_string(),
+        existing_program: "Chapter2".to_string(),
+        new_program: "Other".to_string(),
+    }));
+    assert_eq!(set.add(named("Other", Vec::new()), vec![line_info("line:intro", "Intro")]), Err(MergeError::DuplicateLineId {
+        line_id: "line:intro".to_string(),
+        existing_program: "Intro".to_string(),
+        new_program: "Other".to_string(),
+    }));
+
+    // Unloading the node that's running stops the dialogue, but keeps the variables.
+    vm.set_node("Chapter2");
+    vm.continue_dialogue();
+    assert!(set.remove("Chapter2").is_some());
+    assert!(!set.contains("Chapter2"));
+    vm.set_program(SharedProgram::new(set.program()).unwrap());
+    assert_eq!(collect_events(&mut vm), ["node start Chapter2", "node complete Chapter2", "dialogue complete"]);
+    assert_eq!(vm.variable_storage["$chapter"], YarnValue::Number(1.0));
+}
+
+#[test]
+fn test_reload() {
+    let run_line = |id: &str| instruction(OpCode::RunLine, &[string(id), Value::FloatValue(0.0)]);
+    let start = |lines: &[&str]| {
+        let mut instructions: Vec<_> = lines.iter().map(|id| run_line(id)).collect();
+        instructions.push(instruction(OpCode::Stop, &[]));
+        program(vec![node("Start", instructions)])
+    };
+    let next_line = |vm: &mut VirtualMachine| loop {
+        if let SuspendReason::Line(line) = vm.continue_dialogue() {
+            return line.id;
+        }
+    };
+
+    let mut vm = VirtualMachine::new(SharedProgram::new(start(&["line:a", "line:b"])).unwrap());
+    vm.variable_storage.insert("$gold".to_string(), 10.0.into());
+    assert_eq!(vm.reload(SharedProgram::new(start(&["line:a", "line:b"])).unwrap()), ReloadOutcome::NotRunning);
+
+    // A line added before the current one doesn't change where the dialogue is.
+    vm.set_node("Start");
+    assert_eq!(next_line(&mut vm), "line:a");
+    assert_eq!(vm.reload(SharedProgram::new(start(&["line:new", "line:a", "line:b"])).unwrap()), ReloadOutcome::Continued);
+    assert_eq!(next_line(&mut vm), "line:b");
+
+    // When the current line is removed, the node starts again.
+    vm.set_node("Start");
+    assert_eq!(next_line(&mut vm), "line:new");
+    assert_eq!(vm.reload(SharedProgram::new(start(&["line:a", "line:b"])).unwrap()), ReloadOutcome::RestartedNode("Start".to_string()));
+    assert_eq!(collect_events(&mut vm)[..3], ["node complete Start", "node start Start", "line line:a"]);
+
+    // Nodes waiting on a detour that can't be found again complete along with the restarted node.
+    let mut detour_vm = VirtualMachine::new(SharedProgram::new(detour_program()).unwrap());
+    detour_vm.set_node("Start");
+    while !matches!(detour_vm.continue_dialogue(), SuspendReason::Options(_)) {}
+    let mut without_start = detour_program();
+    without_start.nodes.remove("Start");
+    assert_eq!(detour_vm.reload(SharedProgram::new(without_start).unwrap()), ReloadOutcome::RestartedNode("Shop".to_string()));
+    assert!(detour_vm.state.return_stack.is_empty());
+    assert_eq!(collect_events(&mut detour_vm)[..4], [
+        "node complete Shop",
+        "node complete Start",
+        "node start Shop",
+        "node start Greeting",
+    ]);
+
+    vm.set_node("Start");
+    assert_eq!(next_line(&mut vm), "line:a");
+
+    // When the node is removed, the dialogue stops.
+    let other = program(vec![node("Other", vec![instruction(OpCode::Stop, &[])])]);
+    assert_eq!(vm.reload(SharedProgram::new(other).unwrap()), ReloadOutcome::Stopped);
+    assert_eq!(collect_events(&mut vm), ["node complete Start", "dialogue complete"]);
+    assert_eq!(vm.variable_storage["$gold"], YarnValue::Number(10.0));
+}